@@ -3,11 +3,50 @@ use super::semantic::*;
 use super::token::*;
 use super::asm_ins::OpcodeIns;
 use super::directive::Directive;
+use super::source_map::{SourceMap, WordKind};
+use super::debug_info::{Assertion, DebugInfo};
+use super::expr::Expr;
+use super::macros::{Expansion, LineOrigin, MacroExpander};
+use super::include::Includer;
+use super::conditional::{self, Conditional};
+use super::object::{ObjectModule, Relocation, RelocationKind};
+use super::pseudo;
+use super::relax::{self, Relaxation};
+use super::asm_error::{AsmError, ErrorType};
+use crate::vm::extension::{Extension, ExtensionOperand};
+use crate::vm::arch::Architecture;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+const CODE_NOT_RELOCATABLE: &'static str = "LK000";
+const CODE_GLOBAL_NOT_DEFINED: &'static str = "LK005";
 
 #[allow(dead_code)]
 pub struct Asm {
+    pub source_map: SourceMap,
+    // Breakpoints and assertions, which are not part of the binary
+    pub debug_info: DebugInfo,
+    // Every error from the last `run`, in the order they were found
+    pub errors: Vec<AsmError>,
+    // Every instruction the last `run` rewrote because its label was too far away
+    pub relaxations: Vec<Relaxation>,
     lexer: Lexer,
     semantic_checker: SemanticChecker,
+    includer: Includer,
+    macro_expander: MacroExpander,
+    conditional: Conditional,
+    // Where every line of the file with its macros expanded came from
+    expansion: Expansion,
+    extension: Option<Extension>,
+    architecture: Architecture,
+    // Whether `run_object` is assembling, and the words the linker has to change
+    relocatable: bool,
+    relocations: Vec<Relocation>,
+    // The register long branches jump through, if relaxation is on
+    relaxation_register: Option<u16>,
+    // The line of the instruction or directive being assembled
+    line_num: usize,
     token_index: usize,
     memory_location: usize,
 }
@@ -16,42 +55,186 @@ pub struct Asm {
 impl Asm {
     pub fn new() -> Asm {
         Asm {
+            source_map: SourceMap::new(),
+            debug_info: DebugInfo::new(),
+            errors: vec![],
+            relaxations: vec![],
             lexer: Lexer::new(),
             semantic_checker: SemanticChecker::new(),
+            includer: Includer::new(),
+            macro_expander: MacroExpander::new(),
+            conditional: Conditional::new(),
+            expansion: Expansion::default(),
+            extension: None,
+            architecture: Architecture::Lc3,
+            relocatable: false,
+            relocations: vec![],
+            relaxation_register: None,
+            line_num: 0,
             token_index: 0,
             memory_location: 0,
         }
     }
 
+    pub fn with_architecture(architecture: Architecture) -> Asm {
+        let mut asm = Asm::new();
+        asm.architecture = architecture;
+        asm.lexer.set_architecture(architecture);
+        asm.semantic_checker.set_architecture(architecture);
+        return asm;
+    }
+
+    pub fn get_architecture(&self) -> Architecture {
+        return self.architecture;
+    }
+
     pub fn run(&mut self, input_file: String) -> Vec<u16> {
+        return self.run_source("", input_file);
+    }
+
+    /* Assembles the file at `path`. Its `.INCLUDE`s are also looked up in its directory. */
+    pub fn run_file(&mut self, path: &str) -> Result<Vec<u16>, String> {
+        let input_file = fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {}", path, err))?;
+        return Ok(self.run_source(path, input_file));
+    }
+
+    /*
+    Assembles one module of a larger program, for the `Linker`. `name` names
+    the module in errors. Returns `None` if there were errors.
+    */
+    pub fn run_object(&mut self, name: &str, input_file: String) -> Option<ObjectModule> {
+        if self.architecture != Architecture::Lc3 {
+            let msg = format!("object modules are not available on the {}.", self.architecture.as_str());
+            self.errors = vec![AsmError::new(String::from(CODE_NOT_RELOCATABLE), "", 0, ErrorType::LogicalError, &msg)];
+            return None;
+        }
+
+        self.relocatable = true;
+        self.relocations = vec![];
+        self.semantic_checker.set_relocatable(true);
+        let binary_file = self.run_source(name, input_file);
+        self.relocatable = false;
+        self.semantic_checker.set_relocatable(false);
+
+        if self.errors.len() > 0 {
+            return None;
+        }
+
+        let mut globals = BTreeMap::new();
+        for label in self.semantic_checker.globals.clone() {
+            match self.get_symbol_address(&label) {
+                Some(address) => {
+                    globals.insert(label, address);
+                },
+                None => {
+                    let msg = format!("`{}` is `.GLOBAL`, but it is not defined in this module.", label);
+                    let mut error = AsmError::new(String::from(CODE_GLOBAL_NOT_DEFINED), "", 0, ErrorType::LabelError, &msg);
+                    error.set_file(name);
+                    self.errors.push(error);
+                },
+            }
+        }
+        if self.errors.len() > 0 {
+            return None;
+        }
+
+        return Some(ObjectModule {
+            name: name.to_string(),
+            origin: binary_file[0],
+            words: binary_file[1..].to_vec(),
+            globals,
+            relocations: std::mem::take(&mut self.relocations),
+        });
+    }
+
+    fn run_source(&mut self, name: &str, input_file: String) -> Vec<u16> {
+        // Nothing is printed here, so the caller decides what to do with `self.errors`
+        self.errors = vec![];
+        self.relaxations = vec![];
+
+        // 0. Paste in included files while handling constants and `.IF` blocks, then
+        // expand macros and handle the constants and `.IF` blocks in their bodies.
+        // Every later step works on the expanded file.
+        let syntax_checker = &self.lexer.syntax_checker;
+        let extension = &self.extension;
+        let is_instruction = |word: &str| {
+            syntax_checker.is_instruction_name(word) || extension.as_ref().is_some_and(|extension| extension.name == word)
+        };
+        let included = self.includer.run(name, &input_file, &mut self.conditional, &is_instruction);
+
+        if self.includer.errors.len() > 0 {
+            self.errors = std::mem::take(&mut self.includer.errors);
+            return vec![];
+        }
+
+        self.expansion = self.macro_expander.run(&included.text, &is_instruction);
+        self.expansion.locate_files(&included);
+
+        if self.macro_expander.errors.len() > 0 {
+            self.errors = std::mem::take(&mut self.macro_expander.errors);
+            for error in self.errors.iter_mut() {
+                let (file, line_num) = included.locate(error.get_line_num());
+                error.set_origin(&LineOrigin { file, line_num, macro_line: None });
+                error.set_file(&self.includer.files[file]);
+            }
+            return vec![];
+        }
+
+        let file = self.conditional.run_expanded(&self.expansion.text, &is_instruction);
+        self.semantic_checker.constants = self.conditional.constants.iter()
+            .map(|(name, constant)| (name.clone(), (constant.value, constant.line_num)))
+            .collect();
+
+        let binary_file = if self.conditional.errors.len() > 0 {
+            self.errors = std::mem::take(&mut self.conditional.errors);
+            vec![]
+        } else {
+            self.run_expanded(file)
+        };
+
+        for error in self.errors.iter_mut() {
+            if let Some(origin) = self.expansion.get_origin(error.get_line_num()) {
+                error.set_origin(origin);
+                error.set_file(&self.includer.files[origin.file]);
+            }
+        }
+
+        return binary_file;
+    }
+
+    fn run_expanded(&mut self, input_file: String) -> Vec<u16> {
         // 1. Verify that file is syntactically valid
-        if !self.lexer.syntax_checker.is_syntactically_valid_file(&input_file) {
+        self.errors = self.lexer.syntax_checker.get_syntax_errors(&input_file);
+        if self.errors.len() > 0 {
             return vec![];
         }
         
         // 2. Create token stream with Lexer
-        let tokens = self.lexer.run(input_file.clone());
+        let mut tokens = self.lexer.run(input_file.clone());
+        for token in tokens.iter_mut() {
+            token.file = self.expansion.original_file(token.line_num);
+        }
         
         if self.lexer.errors.len() > 0 {
-            // let io = &Box::new(StdIO {});
-            for error in self.lexer.errors.iter() {
-                println!("{}", error.generate_msg());
-            }
+            self.errors = std::mem::take(&mut self.lexer.errors);
             return vec![];
         }
         
-        // this is for debug purposes
-        for (i, token) in tokens.iter().enumerate() {
-            println!("{}\t: {:?}", i, token);
+        // 3. Verify that file is semantically valid. Relaxing an instruction moves
+        // every label after it, so this is redone until no more have to be relaxed.
+        self.semantic_checker.relaxed.clear();
+        loop {
+            self.semantic_checker.run(&tokens, input_file.clone());
+            if self.semantic_checker.to_relax.is_empty() || self.semantic_checker.errors.len() > 0 {
+                break;
+            }
+            let to_relax = std::mem::take(&mut self.semantic_checker.to_relax);
+            self.semantic_checker.relaxed.extend(to_relax);
         }
         
-        // 3. Verify that file is semantically valid
-        self.semantic_checker.run(&tokens, input_file);
-        
         if self.semantic_checker.errors.len() > 0 {
-            for error in self.semantic_checker.errors.iter() {
-                println!("{}", error.generate_msg());
-            }
+            self.errors = std::mem::take(&mut self.semantic_checker.errors);
             return vec![];
         }
         
@@ -61,6 +244,107 @@ impl Asm {
         return self.assemble(tokens);
     }
 
+    /*
+    Defines the constant `name`, like `-D NAME=value`. It wins over an `.EQU`
+    of the same name in the file. Like `.EQU`, the value has to fit in 16 bits.
+    */
+    pub fn define(&mut self, name: &str, value: i32) -> Result<(), String> {
+        if !conditional::is_constant_name(name) {
+            return Err(format!("`{}` is not a valid constant name", name));
+        }
+        let value = conditional::check_range(value)?;
+        self.conditional.defines.insert(name.to_string(), value);
+        return Ok(());
+    }
+
+    /* Lets `.INCLUDE` find files in `path`, after the directory of the including file. */
+    pub fn add_include_path(&mut self, path: &str) {
+        self.includer.include_paths.push(PathBuf::from(path));
+    }
+
+    /*
+    Lets `.INCLUDE "name"` find `contents` without reading a file, like an
+    open tab in the web editor. These are looked up before any directory.
+    */
+    pub fn add_source(&mut self, name: &str, contents: String) {
+        self.includer.add_source(name, contents);
+    }
+
+    /* The name of file number `file`, as used by `Token::file` and the source map. */
+    pub fn get_file_name(&self, file: usize) -> Option<&str> {
+        return self.includer.get_file_name(file);
+    }
+
+    /* Lets programs use the pseudo-instructions in `pseudo`, like MOV and PUSH. */
+    pub fn enable_pseudo_ops(&mut self) -> Result<(), String> {
+        if self.architecture != Architecture::Lc3 {
+            return Err(format!("pseudo-instructions are not available on the {}", self.architecture.as_str()));
+        }
+        self.lexer.set_pseudo_ops(true);
+        return Ok(());
+    }
+
+    /*
+    Rewrites BR, LD and LEA instructions whose label is out of range instead
+    of reporting an error, see `relax`. Long branches overwrite `register`.
+    */
+    pub fn enable_relaxation(&mut self, register: u16) -> Result<(), String> {
+        if self.architecture != Architecture::Lc3 {
+            return Err(format!("relaxation is not available on the {}", self.architecture.as_str()));
+        }
+        if register > 7 {
+            return Err(format!("R{} is not a register", register));
+        }
+        self.relaxation_register = Some(register);
+        self.semantic_checker.set_relaxation(true);
+        return Ok(());
+    }
+
+    /* Lets programs use `extension` as an instruction on opcode 1101. */
+    pub fn set_extension(&mut self, extension: Extension) -> Result<(), String> {
+        if self.architecture != Architecture::Lc3 {
+            return Err(format!("opcode 1101 is not free on the {}", self.architecture.as_str()));
+        }
+        self.lexer.set_extension_name(&extension.name);
+        self.semantic_checker.set_extension(extension.clone());
+        self.extension = Some(extension);
+        return Ok(());
+    }
+
+    pub fn get_symbol_address(&self, label: &str) -> Option<u16> {
+        return self.semantic_checker.symbol_table
+            .get(label)
+            .map(|(address, _)| *address as u16);
+    }
+
+    pub fn get_symbols(&self) -> HashMap<String, u16> {
+        return self.semantic_checker.symbol_table
+            .iter()
+            .map(|(label, (address, _))| (label.clone(), *address as u16))
+            .collect();
+    }
+
+    /*
+    Maps every labelled address back to its label. If several labels share
+    an address, the alphabetically first one is used.
+    */
+    pub fn get_address_labels(&self) -> HashMap<u16, String> {
+        let mut labels: HashMap<u16, String> = HashMap::new();
+
+        for (label, (address, _)) in self.semantic_checker.symbol_table.iter() {
+            // A constant is a number, not the name of an address, and an anonymous label has no name
+            if self.semantic_checker.constants.contains_key(label) || label.starts_with(':') {
+                continue;
+            }
+            let entry = labels.entry(*address as u16).or_insert(label.clone());
+            if label < entry {
+                *entry = label.clone();
+            }
+        }
+
+        return labels;
+    }
+
     pub fn assemble(&mut self, tokens: Vec<Token>) -> Vec<u16> {
         // Every token is already assumed completely semantically valid. Therefore, there
         // are no errors that should occur in this step. If we receive an instruction, it is
@@ -77,16 +361,52 @@ impl Asm {
                 continue;
             }
 
+            let line_num = tokens[self.token_index].line_num;
+            let address = self.memory_location as u16;
+            self.line_num = line_num;
+
             match &tokens[self.token_index].inner_token {
+                TokenType::Instruction(instruction) if self.semantic_checker.relaxed.contains(&self.token_index) => {
+                    let start = self.token_index;
+                    self.token_index += 1;
+                    let words = self.handle_relaxed_instruction(instruction, &tokens);
+                    for (i, value) in words.iter().enumerate() {
+                        binary_file.push(*value);
+                        let kind = if i == relax::get_pointer_index(instruction) { WordKind::Data } else { WordKind::Instruction };
+                        self.insert_source(address.wrapping_add(i as u16), line_num, kind);
+                    }
+
+                    let operands: Vec<&str> = tokens[start + 1..self.token_index].iter().map(|token| token.original_match.as_str()).collect();
+                    self.relaxations.push(Relaxation {
+                        address,
+                        line_num: self.expansion.original_line_num(line_num),
+                        text: format!("{} {}", tokens[start].original_match, operands.join(", ")),
+                        words,
+                    });
+                },
+                TokenType::Instruction(instruction) if instruction.is_pseudo() => {
+                    self.token_index += 1;
+                    let words = self.handle_pseudo_instruction(instruction, &tokens);
+                    for (i, value) in words.into_iter().enumerate() {
+                        binary_file.push(value);
+                        let word_address = address.wrapping_add(i as u16 * self.architecture.word_size());
+                        // The value loaded by LDIMM sits between its instructions
+                        let kind = if *instruction == OpcodeIns::Ldimm && i == 2 { WordKind::Data } else { WordKind::Instruction };
+                        self.insert_source(word_address, line_num, kind);
+                    }
+                },
                 TokenType::Instruction(instruction) => {
                     self.increment();
                     binary_file.push(self.handle_instruction(instruction, &tokens));
+                    self.insert_source(address, line_num, WordKind::Instruction);
                 },
                 TokenType::Directive(directive) => {
                     self.token_index += 1;
                     let memory_vec = self.handle_directive(directive, &tokens);
-                    for value in memory_vec {
+                    for (i, value) in memory_vec.into_iter().enumerate() {
                         binary_file.push(value);
+                        let word_address = address.wrapping_add(i as u16 * self.architecture.word_size());
+                        self.insert_source(word_address, line_num, WordKind::Data);
                     }
                 },
                 _ => {
@@ -98,11 +418,26 @@ impl Asm {
         return binary_file;
     }
 
+    fn insert_source(&mut self, address: u16, line_num: usize, kind: WordKind) {
+        let file = self.expansion.original_file(line_num);
+        self.source_map.insert(address, file, self.expansion.original_line_num(line_num), kind);
+    }
+
     pub fn increment(&mut self) {
-        self.memory_location += 1;
+        self.advance(1);
         self.token_index += 1;
     }
 
+    /* Moves past `words` words of memory, which are two bytes each on the LC-3b. */
+    fn advance(&mut self, words: usize) {
+        self.memory_location += words * self.architecture.word_size() as usize;
+    }
+
+    /* The PC-relative offset of `label_loc`, counted in words. */
+    fn pc_offset(&self, label_loc: i32) -> i32 {
+        return (label_loc - self.memory_location as i32) / self.architecture.word_size() as i32;
+    }
+
     pub fn set_origin(&mut self, tokens: &Vec<Token>) {
         if let TokenType::Label(_) = tokens[self.token_index].inner_token {
             self.token_index += 1;
@@ -110,48 +445,72 @@ impl Asm {
 
         self.token_index += 1; // skip .orig
         
-        if let TokenType::Number(origin) = tokens[self.token_index].inner_token {
-            self.memory_location = origin as usize;
-            self.token_index += 1;
-        } else {
-            unreachable!();
-        }
+        self.memory_location = self.get_number(&tokens[self.token_index].inner_token) as usize;
+        self.token_index += 1;
     }    
     pub fn handle_directive(&mut self, directive: &Directive, tokens: &Vec<Token>) -> Vec<u16> {    
         let mut output: Vec<u16> = vec![];
 
         match directive {
             Directive::END => return output,
-            Directive::FILL => {
-                if let TokenType::Number(value) = tokens[self.token_index].inner_token {
-                    output.push(value as u16);
-                    self.memory_location += 1;
+            Directive::BREAK => {
+                self.debug_info.breakpoints.push(self.memory_location as u16);
+                return output;
+            },
+            // Only the semantic checker needs these, see `object`
+            Directive::GLOBAL | Directive::EXTERN => {},
+            Directive::ASSERT => {
+                if let TokenType::String(expression) = &tokens[self.token_index].inner_token {
+                    // The semantic checker already made sure the expression parses and its labels exist
+                    let symbols = self.get_symbols();
+                    let (lhs, comparison, rhs) = Assertion::parse_expression(expression).unwrap();
+
+                    self.debug_info.assertions.push(Assertion {
+                        address: self.memory_location as u16,
+                        line_num: self.expansion.original_line_num(tokens[self.token_index].line_num),
+                        text: expression.clone(),
+                        lhs: lhs.resolve(&symbols).unwrap(),
+                        comparison,
+                        rhs: rhs.resolve(&symbols).unwrap(),
+                    });
                 } else {
                     unreachable!();
                 }
             },
+            Directive::FILL => {
+                let value = &tokens[self.token_index].inner_token;
+                // The absolute address, unlike the PC-relative offsets of instructions
+                output.push(self.get_absolute(value, self.memory_location as u16) as u16);
+                self.advance(1);
+            },
             Directive::BLKW => {
-                if let TokenType::Number(count) = tokens[self.token_index].inner_token {
-                    for _ in 0..count {
-                        output.push(0);
-                        self.memory_location += 1;
-                    }
-                } else {
-                    unreachable!();
+                for _ in 0..self.get_number(&tokens[self.token_index].inner_token) {
+                    output.push(0);
+                    self.advance(1);
                 }
             },
             Directive::STRINGZ => {
                 if let TokenType::String(string) = &tokens[self.token_index].inner_token {
                     for c in string.chars() {
                         output.push(c as u16);
-                        self.memory_location += 1;
+                        self.advance(1);
                     }
                     output.push('\0' as u16);
-                    self.memory_location += 1;
+                    self.advance(1);
                 } else {
                     unreachable!();
                 }
-            }
+            },
+            Directive::STRINGP => {
+                if let TokenType::String(string) = &tokens[self.token_index].inner_token {
+                    for word in Directive::pack_string(string) {
+                        output.push(word);
+                        self.advance(1);
+                    }
+                } else {
+                    unreachable!();
+                }
+            },
             Directive::ORIG => unreachable!(),
         }
         
@@ -177,9 +536,15 @@ impl Asm {
             OpcodeIns::Ld | OpcodeIns::Ldi | OpcodeIns::Lea | OpcodeIns::St | OpcodeIns::Sti => {
                 output = self.handle_reg_offset9(opcode, tokens);
             },
-            OpcodeIns::Ldr | OpcodeIns::Str => {
+            OpcodeIns::Ldr | OpcodeIns::Str | OpcodeIns::Ldb | OpcodeIns::Stb | OpcodeIns::Ldw | OpcodeIns::Stw => {
                 output = self.handle_reg_reg_offset6(opcode, tokens);
             },
+            OpcodeIns::Xor => {
+                output = self.handle_reg_reg_ctrl_reg_or_imm5(opcode, tokens);
+            },
+            OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa => {
+                output = self.handle_shift(instruction, opcode, tokens);
+            },
             OpcodeIns::Jsr => {
                 output = self.handle_jsr(opcode, tokens);
             },
@@ -199,9 +564,14 @@ impl Asm {
                 let ins = opcode + subroutine;
                 output = ins;
             },
+            OpcodeIns::TrapVector => {
+                output = self.handle_trap_vector(opcode, tokens);
+            },
+            OpcodeIns::Reserved => {
+                output = self.handle_extension(tokens);
+            },
             _ => {
-                println!("unimplemented ins: {:?}", instruction);
-                unimplemented!()
+                unimplemented!("unimplemented ins: {:?}", instruction)
             }
         }
         
@@ -210,6 +580,47 @@ impl Asm {
         return output;
     }
 
+    /* Expands a pseudo-instruction into the words of its real instructions, see `pseudo`. */
+    pub fn handle_pseudo_instruction(&mut self, instruction: &OpcodeIns, tokens: &Vec<Token>) -> Vec<u16> {
+        let mut operands: Vec<u16> = vec![];
+
+        for _ in 0..instruction.get_expected_operands().len() {
+            let token = &tokens[self.token_index].inner_token;
+            match token {
+                TokenType::Register(reg) => operands.push(*reg),
+                // Like `.FILL`, the absolute address. It is the third word of LDIMM.
+                _ => operands.push(self.get_absolute(token, self.memory_location as u16 + 2) as u16),
+            }
+            self.token_index += 1;
+        }
+
+        let output = pseudo::expand(instruction, &operands);
+        self.advance(output.len());
+        return output;
+    }
+
+    /* The long form of an instruction whose label is out of range, see `relax`. */
+    pub fn handle_relaxed_instruction(&mut self, instruction: &OpcodeIns, tokens: &Vec<Token>) -> Vec<u16> {
+        let register = match instruction {
+            OpcodeIns::Br(_, _, _) => self.relaxation_register.expect("Expected that only `enable_relaxation` would relax instructions"),
+            _ => match tokens[self.token_index].inner_token {
+                TokenType::Register(register) => {
+                    self.token_index += 1;
+                    register
+                },
+                _ => unreachable!(),
+            },
+        };
+        // Like `.FILL`, so an object module can still be moved
+        let pointer = (self.memory_location + relax::get_pointer_index(instruction)) as u16;
+        let target = self.get_absolute(&tokens[self.token_index].inner_token, pointer) as u16;
+        self.token_index += 1;
+
+        let output = relax::expand(instruction, register, target);
+        self.advance(output.len());
+        return output;
+    }
+
     pub fn handle_reg_reg_ctrl_reg_or_imm5(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
         let reg1 = &tokens[self.token_index].inner_token;
         self.token_index += 1;
@@ -236,9 +647,9 @@ impl Asm {
             TokenType::Register(sr2) => {
                 return output_value + sr2;
             },
-            TokenType::Number(imm5) => {
+            TokenType::Number(_) | TokenType::Expression(_) => {
                 output_value += 1 << 5; // control bit, tells the VM that this is an immediate value
-                return self.add_imm(output_value, *imm5 as u16, 5);
+                return self.add_imm(output_value, self.get_number(reg3_or_imm5) as u16, 5);
             },
             _ => {
                 unreachable!();
@@ -246,6 +657,56 @@ impl Asm {
         }
     }
 
+    pub fn handle_extension(&mut self, tokens: &Vec<Token>) -> u16 {
+        let extension = self.extension.clone()
+            .expect("Expected that only an Asm with an extension would lex OpcodeIns::Reserved");
+        let mut values: Vec<u16> = vec![];
+
+        for operand in extension.operands.iter() {
+            let token = &tokens[self.token_index].inner_token;
+            match (operand, token) {
+                (ExtensionOperand::Reg, TokenType::Register(reg)) => values.push(*reg),
+                (ExtensionOperand::Imm(_), _) => values.push(self.get_number(token) as u16),
+                (ExtensionOperand::Label(width), _) => values.push(self.get_pc_offset(token, *width) as u16),
+                _ => unreachable!(),
+            }
+            self.token_index += 1;
+        }
+
+        return extension.encode(&values);
+    }
+
+    pub fn handle_shift(&mut self, instruction: &OpcodeIns, opcode: u16, tokens: &Vec<Token>) -> u16 {
+        let reg1 = &tokens[self.token_index].inner_token;
+        self.token_index += 1;
+        let reg2 = &tokens[self.token_index].inner_token;
+        self.token_index += 1;
+        let amount = &tokens[self.token_index].inner_token;
+        self.token_index += 1;
+
+        // Bits 5 and 4 pick the shift: 00 is LSHF, 01 is RSHFL and 11 is RSHFA
+        let mut output_value = opcode + match instruction {
+            OpcodeIns::Lshf => 0b00 << 4,
+            OpcodeIns::Rshfl => 0b01 << 4,
+            OpcodeIns::Rshfa => 0b11 << 4,
+            _ => unreachable!(),
+        };
+
+        if let TokenType::Register(dr) = reg1 {
+            output_value += dr << 9;
+        } else {
+            unreachable!();
+        }
+
+        if let TokenType::Register(sr) = reg2 {
+            output_value += sr << 6;
+        } else {
+            unreachable!();
+        }
+
+        return output_value + (self.get_number(amount) as u16 & 0xF);
+    }
+
     pub fn handle_br(&mut self, n: bool, z: bool, p: bool, opcode: u16, tokens: &Vec<Token>) -> u16 {
         let label = &tokens[self.token_index].inner_token;
         self.token_index += 1;
@@ -262,14 +723,8 @@ impl Asm {
             output_value += 1 << 9;
         }
 
-        if let TokenType::Label(l) = label {
-            let (pcoffset9, _) = self.semantic_checker.symbol_table.get(l)
-                .expect(&format!("Expected that the label `{}` would be defined and verified in the semantic checker", l));
-            let immediate = *pcoffset9 - self.memory_location as i32;
-            return self.add_imm(output_value, immediate as u16, 9);
-        } else {
-            unreachable!();
-        }
+        let immediate = self.get_pc_offset(label, 9);
+        return self.add_imm(output_value, immediate as u16, 9);
     }
 
     pub fn handle_jmp(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
@@ -302,14 +757,8 @@ impl Asm {
             unreachable!();
         }
 
-        if let TokenType::Label(label) = offset {
-            let (label_loc, _) = self.semantic_checker.symbol_table.get(label)
-                .expect(&format!("expected that the label `{label}` existed the symbol table existed"));
-            let pcoffset9 = *label_loc - self.memory_location as i32;
-            return self.add_imm(output_value, pcoffset9 as u16, imm_len);
-        } else {
-            unreachable!()
-        }
+        let pcoffset9 = self.get_pc_offset(offset, imm_len as u8);
+        return self.add_imm(output_value, pcoffset9 as u16, imm_len);
     }
 
     pub fn handle_reg_reg_offset6(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
@@ -335,11 +784,7 @@ impl Asm {
             unreachable!();
         }
 
-        if let TokenType::Number(offset6) = number {
-            return self.add_imm(output_value, *offset6 as u16, 6);
-        } else {
-            unreachable!();
-        }
+        return self.add_imm(output_value, self.get_number(number) as u16, 6);
     }
     
     pub fn handle_jsr(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
@@ -350,14 +795,8 @@ impl Asm {
         let label = &tokens[self.token_index].inner_token;
         self.token_index += 1;
 
-        if let TokenType::Label(l) = label {
-            let (pcoffset11, _) = self.semantic_checker.symbol_table.get(l)
-                .expect(&format!("Expected that the label `{}` would be defined and verified in the semantic checker", l));
-            let immediate = *pcoffset11 - self.memory_location as i32; 
-                return self.add_imm(output_value, immediate as u16, 11);
-        } else {
-            unreachable!();
-        }
+        let immediate = self.get_pc_offset(label, 11);
+        return self.add_imm(output_value, immediate as u16, 11);
     }
     
     pub fn handle_jsrr(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
@@ -412,6 +851,106 @@ impl Asm {
         return output;
     }
     
+    pub fn handle_trap_vector(&mut self, opcode: u16, tokens: &Vec<Token>) -> u16 {
+        let vector = &tokens[self.token_index].inner_token;
+        self.token_index += 1;
+
+        return opcode + (self.get_number(vector) as u16 & 0xFF);
+    }
+
+    /* The value of a number or expression operand. */
+    fn get_number(&self, token: &TokenType) -> i32 {
+        match token {
+            TokenType::Number(number) => return *number as i32,
+            TokenType::Expression(text) => return self.evaluate(text),
+            _ => unreachable!(),
+        }
+    }
+
+    /* The address a label or expression operand refers to. */
+    fn get_address(&self, token: &TokenType) -> i32 {
+        match token {
+            TokenType::Label(label) => {
+                let (address, _) = self.semantic_checker.symbol_table.get(label)
+                    .unwrap_or_else(|| panic!("Expected that the label `{}` would be defined and verified in the semantic checker", label));
+                return *address;
+            },
+            TokenType::Expression(text) => return self.evaluate(text),
+            _ => unreachable!(),
+        }
+    }
+
+    /*
+    The PC-relative offset to a label or expression operand of an instruction
+    that was just passed. An `.EXTERN` label is left 0 for the linker.
+    */
+    fn get_pc_offset(&mut self, token: &TokenType, width: u8) -> i32 {
+        if let TokenType::Label(label) = token
+            && self.semantic_checker.externs.contains_key(label) {
+            let address = (self.memory_location - self.architecture.word_size() as usize) as u16;
+            self.push_relocation(address, RelocationKind::PcOffset(width), Some(label.clone()));
+            return 0;
+        }
+        return self.pc_offset(self.get_address(token));
+    }
+
+    /*
+    A number or the absolute address of a label, for the word at `address`.
+    In an object module, an address has to move with the module.
+    */
+    fn get_absolute(&mut self, token: &TokenType, address: u16) -> i32 {
+        match token {
+            TokenType::Label(label) if self.semantic_checker.externs.contains_key(label) => {
+                self.push_relocation(address, RelocationKind::Absolute, Some(label.clone()));
+                return 0;
+            },
+            TokenType::Label(_) => {
+                if self.relocatable {
+                    self.push_relocation(address, RelocationKind::Absolute, None);
+                }
+                return self.get_address(token);
+            },
+            TokenType::Expression(text) => {
+                let value = self.evaluate(text);
+                if self.relocatable {
+                    self.relocate_expression(text, value, address);
+                }
+                return value;
+            },
+            _ => return self.get_number(token),
+        }
+    }
+
+    /*
+    An expression is an address if moving every label by one moves it by one,
+    like `TABLE+2`, and a plain number if it does not move, like `END-START`.
+    */
+    fn relocate_expression(&mut self, text: &str, value: i32, address: u16) {
+        let moved = |label: &str| self.semantic_checker.symbol_table.get(label).map(|(address, _)| *address + 1);
+        let moved_value = Expr::parse(text).and_then(|expr| expr.evaluate(&moved)).unwrap_or(value);
+
+        match moved_value - value {
+            0 => {},
+            1 => self.push_relocation(address, RelocationKind::Absolute, None),
+            _ => {
+                let msg = format!("`{}` is neither a number nor an address, so it cannot be moved by the linker.", text);
+                self.errors.push(AsmError::new(String::from(CODE_NOT_RELOCATABLE), text, self.line_num as i32, ErrorType::OperandError, &msg));
+            },
+        }
+    }
+
+    fn push_relocation(&mut self, address: u16, kind: RelocationKind, symbol: Option<String>) {
+        let line_num = self.expansion.original_line_num(self.line_num);
+        self.relocations.push(Relocation { address, kind, symbol, line_num });
+    }
+
+    fn evaluate(&self, text: &str) -> i32 {
+        let symbols = |label: &str| self.semantic_checker.symbol_table.get(label).map(|(address, _)| *address);
+        return Expr::parse(text)
+            .and_then(|expr| expr.evaluate(&symbols))
+            .expect("Expected that the semantic checker would only let valid expressions through");
+    }
+
     pub fn add_imm(&self, instruction: u16, immediate_value: u16, length: u16) -> u16 {
         if immediate_value as i16 >= 0 {
             return instruction + immediate_value;
@@ -428,6 +967,7 @@ impl Asm {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::asm::debug_info::AssertOperand;
 
     fn mk_token(t: TokenType) -> Token {
         Token {
@@ -437,6 +977,7 @@ mod tests {
             file_relative_from: 0,
             file_relative_to:0,
             line_num: 0,
+            file: 0,
             original_match: "".to_string(),
         }
     }
@@ -811,22 +1352,81 @@ mod tests {
         let mut asm = Asm::new();
         
         let stream = get_file(vec![
-            TokenType::Instruction(OpcodeIns::Trap(20)), // getc
-            TokenType::Instruction(OpcodeIns::Trap(21)), // out
-            TokenType::Instruction(OpcodeIns::Trap(22)), // puts
-            TokenType::Instruction(OpcodeIns::Trap(23)), // in
-            TokenType::Instruction(OpcodeIns::Trap(25)), // halt
-            TokenType::Instruction(OpcodeIns::Trap(32)), // maybe some other instruction someday?
+            TokenType::Instruction(OpcodeIns::Trap(0x20)), // getc
+            TokenType::Instruction(OpcodeIns::Trap(0x21)), // out
+            TokenType::Instruction(OpcodeIns::Trap(0x22)), // puts
+            TokenType::Instruction(OpcodeIns::Trap(0x23)), // in
+            TokenType::Instruction(OpcodeIns::Trap(0x24)), // putsp
+            TokenType::Instruction(OpcodeIns::Trap(0x25)), // halt
         ]);
         
         let bin = asm.assemble(stream);
         
-        assert_eq!(bin[1], 0b1111_0000_0001_0100);
-        assert_eq!(bin[2], 0b1111_0000_0001_0101);
-        assert_eq!(bin[3], 0b1111_0000_0001_0110);
-        assert_eq!(bin[4], 0b1111_0000_0001_0111);
-        assert_eq!(bin[5], 0b1111_0000_0001_1001);
-        assert_eq!(bin[6], 0b1111_0000_0010_0000);
+        assert_eq!(bin[1], 0b1111_0000_0010_0000);
+        assert_eq!(bin[2], 0b1111_0000_0010_0001);
+        assert_eq!(bin[3], 0b1111_0000_0010_0010);
+        assert_eq!(bin[4], 0b1111_0000_0010_0011);
+        assert_eq!(bin[5], 0b1111_0000_0010_0100);
+        assert_eq!(bin[6], 0b1111_0000_0010_0101);
+    }
+
+    #[test]
+    fn test_trap_vector() {
+        let mut asm = Asm::new();
+
+        let bin = asm.run(String::from(".ORIG x3000
+        TRAP x20
+        trap x25
+        TRAP xFF
+        .END"));
+
+        assert_eq!(bin[1..], [0xF020, 0xF025, 0xF0FF]);
+    }
+
+    #[test]
+    fn test_asm_directive_stringp() {
+        let mut asm = Asm::new();
+
+        let bin = asm.run(String::from(r#".ORIG x3000
+        LEA R0, odd
+        PUTSP
+        HALT
+odd     .STRINGP "abc"
+even    .STRINGP "ab"
+after   .FILL #7
+        .END"#));
+
+        assert_eq!(bin[4..], [0x6261, 0x0063, 0x6261, 0x0000, 7]);
+        assert_eq!(bin[3], 0xF025);
+        assert_eq!(bin[2], 0xF024);
+        assert_eq!(asm.get_symbol_address("even"), Some(0x3005));
+        assert_eq!(asm.get_symbol_address("after"), Some(0x3007));
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut asm = Asm::new();
+
+        let bin = asm.run(String::from(r#".ORIG x3000
+        ADD R1, R1, #1
+num     .FILL #5
+
+msg     .STRINGZ "hi"
+        HALT
+        .END"#));
+
+        assert_eq!(bin.len(), 7);
+        assert_eq!(asm.source_map.len(), 6);
+        assert_eq!(asm.source_map.get_line_num(0x3000), Some(2));
+        assert_eq!(asm.source_map.get_line_num(0x3001), Some(3));
+        assert_eq!(asm.source_map.get_line_num(0x3002), Some(5));
+        assert_eq!(asm.source_map.get_line_num(0x3004), Some(5));
+        assert_eq!(asm.source_map.get_line_num(0x3005), Some(6));
+
+        assert!(asm.source_map.is_instruction(0x3000));
+        assert!(!asm.source_map.is_instruction(0x3001));
+        assert!(!asm.source_map.is_instruction(0x3003));
+        assert!(asm.source_map.is_instruction(0x3005));
     }
 
     #[test]
@@ -852,5 +1452,412 @@ mod tests {
         assert_eq!(bin[1], 0b1011_001_111111111);
         assert_eq!(bin[2], 0b1011_001_000000001);
     }
-}
 
+    #[test]
+    fn test_asm_directive_break_and_assert() {
+        let mut asm = Asm::new();
+
+        let bin = asm.run(String::from(r#".ORIG x3000
+        ADD R0, R0, #5
+        .ASSERT R0 == #5    ; checked before the BREAK's instruction
+        .break
+        ST R0, count
+check   .Assert [count] >= x1
+        HALT
+count   .FILL #0
+        .END"#));
+
+        assert_eq!(bin.len(), 5);
+        assert_eq!(asm.get_symbol_address("count"), Some(0x3003));
+        assert_eq!(asm.get_symbol_address("check"), Some(0x3002));
+        assert_eq!(asm.debug_info.breakpoints, vec![0x3001]);
+        assert_eq!(asm.debug_info.assertions.len(), 2);
+
+        let assertion = &asm.debug_info.assertions[1];
+        assert_eq!(assertion.address, 0x3002);
+        assert_eq!(assertion.line_num, 6);
+        assert_eq!(assertion.text, "[count] >= x1");
+        assert_eq!(assertion.lhs, AssertOperand::Memory(Box::new(AssertOperand::Number(0x3003))));
+    }
+
+    #[test]
+    fn test_asm_directive_assert_errors() {
+        let mut asm = Asm::new();
+
+        asm.run(String::from(".ORIG x3000\n.ASSERT R0 = #5\nHALT\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM019");
+
+        asm.run(String::from(".ORIG x3000\n.ASSERT [nowhere] == #5\nHALT\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM014");
+
+        // Without an expression, the next line's instruction is taken as the missing operand
+        asm.run(String::from(".ORIG x3000\n.ASSERT\nHALT\n.END"));
+        assert_eq!(asm.errors[0].code, "SM001");
+    }
+
+    #[test]
+    fn test_expressions() {
+        let mut asm = Asm::new();
+        let bin = asm.run(String::from(".ORIG x3000+x10
+start   LEA R0, table+2
+        ADD R1, R1, #10/2
+        AND R2, R2, 'A'-'A'+3
+        BRnzp end-1
+        .FILL 'A'+1
+        .FILL end-start
+table   .BLKW #2*2
+end     HALT
+        .END"));
+
+        assert!(asm.errors.is_empty());
+        assert_eq!(bin[0], 0x3010);
+        assert_eq!(bin[1], 0b1110_000_000000111);
+        assert_eq!(bin[2], 0b0001_001_001_1_00101);
+        assert_eq!(bin[3], 0b0101_010_010_1_00011);
+        assert_eq!(bin[4], 0b0000_111_000000101);
+        assert_eq!(bin[5], 'B' as u16);
+        assert_eq!(bin[6], 10);
+        assert_eq!(asm.get_symbol_address("end"), Some(0x301A));
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let mut asm = Asm::new();
+
+        asm.run(String::from(".ORIG x3000\nADD R0, R0, #10*4\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM015");
+
+        asm.run(String::from(".ORIG x3000\nBR done+300\ndone HALT\n.END"));
+        assert_eq!(asm.errors[0].code, "SM015");
+
+        // .BLKW decides where the labels after it go, so it cannot use them
+        asm.run(String::from(".ORIG x3000\n.BLKW later-x3000\nlater HALT\n.END"));
+        assert_eq!(asm.errors[0].code, "SM023");
+
+        asm.run(String::from(".ORIG x3000\nADD R0, R0, (1+2\n.END"));
+        assert_eq!(asm.errors[0].code, "SM023");
+
+        asm.run(String::from(".ORIG x3000\nJMP R0+1\n.END"));
+        assert_eq!(asm.errors[0].code, "SM022");
+
+        // Only reported once, as an undefined label
+        asm.run(String::from(".ORIG x3000\nLD R0, nowhere+1\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM014");
+
+        asm.run(String::from(".ORIG x3000\n.BLKW 0-2\n.END"));
+        assert_eq!(asm.errors[0].code, "SM015");
+
+        // The same goes for a number, which would otherwise move the labels after it backwards
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000\n.BLKW #-1\nEND .FILL #1\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM015");
+        assert_eq!(asm.get_symbol_address("END"), Some(0x3000));
+
+        // On the LC-3b, an odd byte offset is between two words
+        let mut asm = Asm::with_architecture(Architecture::Lc3b);
+        asm.run(String::from(".ORIG x3000\nBR t+1\nt HALT\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM028");
+        let mut asm = Asm::with_architecture(Architecture::Lc3b);
+        assert!(asm.run(String::from(".ORIG x3000\nBR t+2\nt HALT\nHALT\n.END")).len() > 0);
+        assert!(asm.errors.is_empty());
+    }
+
+    #[test]
+    fn test_asm_directive_fill_label() {
+        let mut asm = Asm::new();
+        let bin = asm.run(String::from(r#".ORIG x3000
+        LDI R0, ptr
+        HALT
+ptr     .FILL msg
+table   .FILL first
+        .FILL second
+first   HALT
+second  HALT
+msg     .STRINGZ "hi"
+        .END"#));
+
+        assert!(asm.errors.is_empty());
+        assert_eq!(bin[3], 0x3007);
+        assert_eq!(bin[4], 0x3005);
+        assert_eq!(bin[5], 0x3006);
+
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000\nptr .FILL nowhere\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM014");
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut asm = Asm::new();
+        let bin = asm.run(String::from(".ORIG x3000
+.MACRO PUSH reg
+        ADD R6, R6, #-1
+        STR reg, R6, #0
+.ENDM
+.MACRO POP reg
+        LDR reg, R6, #0
+        ADD R6, R6, #1
+.ENDM
+start   PUSH R1
+        POP R2
+        BR start
+        .END"));
+
+        assert!(asm.errors.is_empty());
+        assert_eq!(bin[1], 0b0001_110_110_1_11111);
+        assert_eq!(bin[2], 0b0111_001_110_000000);
+        assert_eq!(bin[3], 0b0110_010_110_000000);
+        assert_eq!(bin[5], 0b0000_000_111111011);
+        assert_eq!(asm.get_symbol_address("start"), Some(0x3000));
+        // Both words of `POP R2` map back to the line that invoked it
+        assert_eq!(asm.source_map.get_line_num(0x3002), Some(11));
+        assert_eq!(asm.source_map.get_line_num(0x3003), Some(11));
+
+        // A label local to the expansion never clashes with one in the source
+        let mut asm = Asm::new();
+        let bin = asm.run(String::from(".ORIG x3000
+.MACRO SPIN reg
+loop    ADD reg, reg, #-1
+        BRp loop
+.ENDM
+        SPIN R1
+loop__1 HALT
+loop    BR loop__1
+        .END"));
+
+        assert!(asm.errors.is_empty());
+        assert_eq!(bin[2], 0b0000_001_111111110);
+        assert_eq!(bin[4], 0b0000_000_111111110);
+        assert_eq!(asm.get_symbol_address("loop__1"), Some(0x3002));
+    }
+
+    #[test]
+    fn test_macro_errors_point_at_invocation_and_definition() {
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000
+.MACRO ADDTO reg, n
+        ADD reg, reg, n
+.ENDM
+        ADDTO R1, #100
+        .END"));
+
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM015");
+        assert_eq!(asm.errors[0].get_line_num(), 5);
+        assert!(asm.errors[0].generate_msg().contains("On line 5 (in the macro `ADDTO`, line 3)"));
+    }
+
+    #[test]
+    fn test_include() {
+        let mut asm = Asm::new();
+        asm.add_source("io.asm", String::from("; Prints R0 twice
+.MACRO TWICE op
+        op
+        op
+.ENDM
+PUTS_TWICE TWICE PUTS
+        RET"));
+
+        let binary_file = asm.run(String::from(".ORIG x3000
+        LEA R0, msg
+        JSR PUTS_TWICE
+        HALT
+.INCLUDE \"io.asm\"
+msg     .STRINGZ \"hi\"
+        .END"));
+
+        assert!(asm.errors.is_empty());
+        assert_eq!(binary_file[4..7], [0xF022, 0xF022, 0xC1C0]);
+        assert_eq!(asm.get_symbol_address("msg"), Some(0x3006));
+
+        // The subroutine maps to its lines in io.asm
+        assert_eq!(asm.get_file_name(1), Some("io.asm"));
+        assert_eq!(asm.source_map.get(0x3003).map(|line| (line.file, line.line_num)), Some((1, 6)));
+        assert_eq!(asm.source_map.get(0x3006).map(|line| (line.file, line.line_num)), Some((0, 6)));
+    }
+
+    #[test]
+    fn test_include_errors_name_the_file() {
+        let mut asm = Asm::new();
+        asm.add_source("lib.asm", String::from("\nLIB     ADD R1, R1, #100\n        RET"));
+        asm.run(String::from(".ORIG x3000\n.INCLUDE \"lib.asm\"\n        ADD R1, R1, #100\n.END"));
+
+        assert_eq!(asm.errors.len(), 2);
+        assert_eq!(asm.errors[0].get_file(), Some("lib.asm"));
+        assert!(asm.errors[0].generate_msg().contains("In `lib.asm`, on line 2"));
+        assert_eq!(asm.errors[1].get_file(), None);
+        assert!(asm.errors[1].generate_msg().contains("On line 3"));
+    }
+
+    #[test]
+    fn test_object_module() {
+        let mut asm = Asm::new();
+        let module = asm.run_object("table.asm", String::from(".ORIG x3000
+        .GLOBAL start
+        .EXTERN print
+start   LD R0, second
+        JSR print
+        HALT
+second  .FILL table+1
+size    .FILL end-table
+table   .FILL print
+end     .FILL #0
+        .END")).unwrap();
+
+        assert_eq!(module.origin, 0x3000);
+        assert_eq!(module.globals.get("start"), Some(&0x3000));
+        assert_eq!(module.words[3..6], [0x3006, 1, 0]);
+        assert_eq!(module.relocations, vec![
+            Relocation { address: 0x3001, kind: RelocationKind::PcOffset(11), symbol: Some("print".to_string()), line_num: 5 },
+            Relocation { address: 0x3003, kind: RelocationKind::Absolute, symbol: None, line_num: 7 },
+            Relocation { address: 0x3005, kind: RelocationKind::Absolute, symbol: Some("print".to_string()), line_num: 9 },
+        ]);
+    }
+
+    #[test]
+    fn test_object_module_errors() {
+        // A whole program cannot use `.EXTERN`
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000\n.EXTERN print\nJSR print\n.END"));
+        assert_eq!(asm.errors[0].code, "SM024");
+
+        let mut asm = Asm::new();
+        assert!(asm.run_object("a.asm", String::from(".ORIG x3000\n.EXTERN print\nprint RET\n.END")).is_none());
+        assert_eq!(asm.errors[0].code, "SM025");
+
+        let mut asm = Asm::new();
+        asm.run_object("a.asm", String::from(".ORIG x3000\n.EXTERN print\n.FILL print+1\n.END"));
+        assert_eq!(asm.errors[0].code, "SM023");
+
+        let mut asm = Asm::new();
+        asm.run_object("a.asm", String::from(".ORIG x3000\nhere .FILL here*2\n.END"));
+        assert_eq!(asm.errors[0].code, "LK000");
+        assert_eq!(asm.errors[0].get_line_num(), 2);
+
+        let mut asm = Asm::new();
+        asm.run_object("a.asm", String::from(".ORIG x3000\n.GLOBAL missing\n.END"));
+        assert_eq!(asm.errors[0].code, "SM014");
+
+        let mut asm = Asm::new();
+        assert!(asm.run_object("a.asm", String::from(".ORIG x3000\n.EXTERN foo\n.GLOBAL foo\n.END")).is_none());
+        assert_eq!(asm.errors[0].code, "SM027");
+    }
+
+    #[test]
+    fn test_constants_and_conditions() {
+        let file = String::from(".ORIG x3000
+.EQU SOLUTION, #0
+.EQU SIZE, #12
+.IF SOLUTION
+        ADD R0, R0, SIZE
+.ELSE
+        ADD R0, R0, #-1
+.ENDIF
+values  .BLKW SIZE/4
+        ADD R1, R1, TOO_BIG
+        .END");
+
+        let mut asm = Asm::new();
+        asm.run(file.clone());
+        // TOO_BIG is not a constant, so it is a label where a number was expected
+        assert_eq!(asm.errors[0].code, "SM003");
+        assert_eq!(asm.errors[0].get_line_num(), 10);
+
+        let file = file.replace("TOO_BIG", "SIZE-SIZE");
+        let mut asm = Asm::new();
+        asm.define("SOLUTION", 1).unwrap();
+        assert!(Asm::new().define("BIG", 70000).is_err());
+        assert!(Asm::new().define("SMALL", -40000).is_err());
+        assert!(Asm::new().define("x10", 5).is_err());
+        let binary_file = asm.run(file.clone());
+        assert!(asm.errors.is_empty());
+        assert_eq!(binary_file, vec![0x3000, 0b0001_000_000_1_01100, 0, 0, 0, 0b0001_001_001_1_00000]);
+
+        // Constants are in the symbol table, but are not addresses
+        assert_eq!(asm.get_symbol_address("SIZE"), Some(12));
+        assert_eq!(asm.get_symbol_address("values"), Some(0x3001));
+        assert_eq!(asm.get_address_labels().get(&12), None);
+
+        let mut asm = Asm::new();
+        assert_eq!(asm.run(file)[1], 0b0001_000_000_1_11111);
+
+        // A label cannot have the name of a constant
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000\n.EQU SIZE, #5\nSIZE .FILL SIZE\n.END"));
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM005");
+        assert!(asm.errors[0].generate_msg().contains("the constant `SIZE`, which was defined on line 2"));
+    }
+
+    #[test]
+    fn test_conditions_guard_includes_and_macros() {
+        let file = String::from(".ORIG x3000
+.EQU SOLUTION, #0
+.MACRO INC reg
+        ADD reg, reg, #1
+.ENDM
+.IF SOLUTION
+.INCLUDE \"solution.asm\"
+        INC R0, R1
+.ELSE
+        INC R2
+.ENDIF
+        .END");
+
+        // Neither the missing file nor the bad invocation is looked at
+        let mut asm = Asm::new();
+        let binary_file = asm.run(file.clone());
+        assert!(asm.errors.is_empty());
+        assert_eq!(binary_file, vec![0x3000, 0b0001_010_010_1_00001]);
+
+        let mut asm = Asm::new();
+        asm.define("SOLUTION", 1).unwrap();
+        asm.run(file);
+        assert_eq!(asm.errors[0].code, "IN000");
+    }
+
+    #[test]
+    fn test_local_and_anonymous_labels() {
+        let file = String::from(".ORIG x3000
+MAIN    AND R1, R1, #0
+.loop   ADD R1, R1, #1
+        BRn .loop
+        BRnzp :+
+SUB     AND R2, R2, #0
+@loop   ADD R2, R2, #-1
+:       BRp @loop
+        BRz :-
+        HALT
+        .END");
+
+        let mut asm = Asm::new();
+        let binary_file = asm.run(file);
+        assert!(asm.errors.is_empty());
+        assert_eq!(binary_file, vec![
+            0x3000, 0x5260, 0x1261, 0b0000_100_111111110, 0b0000_111_000000010,
+            0x54A0, 0x14BF, 0b0000_001_111111110, 0b0000_010_111111110, 0xF025,
+        ]);
+
+        assert_eq!(asm.get_symbol_address("MAIN.loop"), Some(0x3001));
+        assert_eq!(asm.get_symbol_address("SUB.loop"), Some(0x3005));
+        assert_eq!(asm.get_symbol_address(":1"), Some(0x3006));
+        assert_eq!(asm.get_address_labels().get(&0x3006), None);
+
+        let mut asm = Asm::new();
+        let binary_file = asm.run(String::from(".ORIG x3000
+MAIN    LEA R0, .table+1
+        HALT
+.table  .FILL #1
+        .FILL #2
+        .END"));
+        assert!(asm.errors.is_empty());
+        assert_eq!(binary_file[1], 0xE002);
+    }
+}
@@ -1,4 +1,5 @@
 use super::token::*;
+use super::macros::LineOrigin;
 use crate::output::SystemIO;
 
 pub enum ErrorType {
@@ -28,6 +29,10 @@ pub struct AsmError {
     from_to: Option<(usize, usize)>,
     err_type: ErrorType,
     msg: String,
+    // The macro and line of its definition, for errors in an expanded macro
+    macro_line: Option<(String, usize)>,
+    // The name of the file the error is in, if the program is made of several files
+    file: Option<String>,
 }
 
 impl AsmError {
@@ -39,6 +44,8 @@ impl AsmError {
             from_to: None,
             err_type: err_type,
             msg: String::from(msg),
+            macro_line: None,
+            file: None,
         }
     }
 
@@ -50,6 +57,8 @@ impl AsmError {
             from_to: Some((token.from, token.to)),
             err_type: err_type,
             msg: String::from(msg),
+            macro_line: None,
+            file: None,
         }
     }
 
@@ -58,6 +67,26 @@ impl AsmError {
         self.from_to = Some((from, to));
     }
 
+    pub fn get_line_num(&self) -> usize {
+        return self.line_num;
+    }
+
+    /* Points the error at the original file, instead of the file with its macros expanded. */
+    pub fn set_origin(&mut self, origin: &LineOrigin) {
+        self.line_num = origin.line_num;
+        self.macro_line = origin.macro_line.clone();
+    }
+
+    /* Names the file the error is in. A file without a name is left unnamed. */
+    pub fn set_file(&mut self, name: &str) {
+        self.file = if name.is_empty() { None } else { Some(name.to_string()) };
+    }
+
+    #[allow(dead_code)]
+    pub fn get_file(&self) -> Option<&str> {
+        return self.file.as_deref();
+    }
+
     #[allow(dead_code)]
     pub fn print(&self, io: &mut Box<dyn SystemIO>) {
         let _ = self.generate_msg()
@@ -74,7 +103,14 @@ impl AsmError {
         let specific_problem = &self.msg;
         let line_content = &self.line_content;
 
-        let mut gen_msg = format!("[{code}] {err_type}: On line {line_num}, {specific_problem}\n\t{line_content}");
+        let mut location = match &self.macro_line {
+            Some((name, macro_line_num)) => format!("On line {line_num} (in the macro `{name}`, line {macro_line_num})"),
+            None => format!("On line {line_num}"),
+        };
+        if let Some(file) = &self.file {
+            location = format!("In `{file}`, o{}", &location[1..]);
+        }
+        let mut gen_msg = format!("[{code}] {err_type}: {location}, {specific_problem}\n\t{line_content}");
 
         if let Some((from, to)) = self.from_to {
             gen_msg += "\n\t";
@@ -2,6 +2,7 @@ use wasm_bindgen::prelude::*;
 use serde::{Deserialize, Serialize};
 use tsify::Tsify;
 use std::collections::VecDeque;
+use crate::vm::arch::Architecture;
 
 pub enum OperandType {
     /*
@@ -13,6 +14,8 @@ pub enum OperandType {
     Label,
     Imm,
     RegOrImm,
+    // `.FILL`, where a label means its address
+    ImmOrLabel,
     String,
 }
 
@@ -37,7 +40,29 @@ pub enum OpcodeIns {
     Sti,
     Str,
     Trap(u16),
+    // `TRAP xNN`, where the vector is given as an operand
+    TrapVector,
+    // The course-defined instruction on opcode 1101, see `vm::extension`
     Reserved,
+    // LC-3b only
+    Ldb,
+    Stb,
+    Ldw,
+    Stw,
+    Xor,
+    Lshf,
+    Rshfl,
+    Rshfa,
+    // Pseudo-instructions, see `pseudo`
+    Mov,
+    Clr,
+    Sub,
+    Neg,
+    Push,
+    Pop,
+    Ldimm,
+    Inc,
+    Dec,
     INVALID,
 }
 
@@ -48,6 +73,7 @@ impl OperandType {
             OperandType::Label => "a label".to_string(),
             OperandType::Imm => "an immediate value".to_string(),
             OperandType::RegOrImm => "a register or immediate value".to_string(),
+            OperandType::ImmOrLabel => "an immediate value or label".to_string(),
             OperandType::String => "a string".to_string(),
         }
     }
@@ -78,15 +104,82 @@ impl OpcodeIns {
             "ST" => return OpcodeIns::St,
             "STI" => return OpcodeIns::Sti,
             "STR" => return OpcodeIns::Str,
-            "GETC" => return OpcodeIns::Trap(20),
-            "OUT" => return OpcodeIns::Trap(21),
-            "PUTS" => return OpcodeIns::Trap(22),
-            "IN" => return OpcodeIns::Trap(23),
-            "HALT" => return OpcodeIns::Trap(25),
+            "GETC" => return OpcodeIns::Trap(0x20),
+            "OUT" => return OpcodeIns::Trap(0x21),
+            "PUTS" => return OpcodeIns::Trap(0x22),
+            "IN" => return OpcodeIns::Trap(0x23),
+            "PUTSP" => return OpcodeIns::Trap(0x24),
+            "HALT" => return OpcodeIns::Trap(0x25),
+            "TRAP" => return OpcodeIns::TrapVector,
+            "LDB" => return OpcodeIns::Ldb,
+            "STB" => return OpcodeIns::Stb,
+            "LDW" => return OpcodeIns::Ldw,
+            "STW" => return OpcodeIns::Stw,
+            "XOR" => return OpcodeIns::Xor,
+            "LSHF" => return OpcodeIns::Lshf,
+            "RSHFL" => return OpcodeIns::Rshfl,
+            "RSHFA" => return OpcodeIns::Rshfa,
+            "MOV" => return OpcodeIns::Mov,
+            "CLR" => return OpcodeIns::Clr,
+            "SUB" => return OpcodeIns::Sub,
+            "NEG" => return OpcodeIns::Neg,
+            "PUSH" => return OpcodeIns::Push,
+            "POP" => return OpcodeIns::Pop,
+            "LDIMM" => return OpcodeIns::Ldimm,
+            "INC" => return OpcodeIns::Inc,
+            "DEC" => return OpcodeIns::Dec,
             _ => return OpcodeIns::INVALID,
         }
     }
 
+    /* Like `from`, but only gives instructions that `architecture` has. */
+    pub fn from_architecture(name: &str, architecture: Architecture) -> OpcodeIns {
+        let instruction = OpcodeIns::from(name);
+
+        if instruction.is_available_on(architecture) {
+            return instruction;
+        }
+        return OpcodeIns::INVALID;
+    }
+
+    /*
+    The LC-3b replaces LD, LDI, LDR, ST, STI and STR with byte and word
+    loads and stores, NOT with XOR, and opcode 1101 with the shifts.
+    Pseudo-instructions expand to LC-3 instructions, so they are LC-3 only.
+    */
+    pub fn is_available_on(&self, architecture: Architecture) -> bool {
+        let lc3_only = self.is_pseudo() || matches!(self,
+            OpcodeIns::Ld | OpcodeIns::Ldi | OpcodeIns::Ldr |
+            OpcodeIns::St | OpcodeIns::Sti | OpcodeIns::Str |
+            OpcodeIns::Reserved
+        );
+        let lc3b_only = matches!(self,
+            OpcodeIns::Ldb | OpcodeIns::Stb | OpcodeIns::Ldw | OpcodeIns::Stw |
+            OpcodeIns::Xor | OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa
+        );
+
+        match architecture {
+            Architecture::Lc3 => return *self != OpcodeIns::INVALID && !lc3b_only,
+            Architecture::Lc3b => return *self != OpcodeIns::INVALID && !lc3_only,
+        }
+    }
+
+    pub fn is_pseudo(&self) -> bool {
+        return matches!(self,
+            OpcodeIns::Mov | OpcodeIns::Clr | OpcodeIns::Sub | OpcodeIns::Neg | OpcodeIns::Push |
+            OpcodeIns::Pop | OpcodeIns::Ldimm | OpcodeIns::Inc | OpcodeIns::Dec
+        );
+    }
+
+    /* How many words the instruction assembles to. Only pseudo-instructions take more than one. */
+    pub fn get_word_count(&self) -> i32 {
+        match self {
+            OpcodeIns::Neg | OpcodeIns::Push | OpcodeIns::Pop => return 2,
+            OpcodeIns::Sub | OpcodeIns::Ldimm => return 3,
+            _ => return 1,
+        }
+    }
+
     pub fn get_expected_operands(&self) -> VecDeque<OperandType> {
         match self {
             OpcodeIns::Add => vec![OperandType::Reg, OperandType::Reg, OperandType::RegOrImm].into_iter().collect(),
@@ -105,24 +198,20 @@ impl OpcodeIns {
             OpcodeIns::St => vec![OperandType::Reg, OperandType::Label].into_iter().collect(),
             OpcodeIns::Sti => vec![OperandType::Reg, OperandType::Label].into_iter().collect(),
             OpcodeIns::Str => vec![OperandType::Reg, OperandType::Reg, OperandType::Imm].into_iter().collect(),
-            OpcodeIns::Trap(subroutine) => self.get_expected_operand_for_trap(*subroutine),
+            OpcodeIns::Trap(_) => vec![].into_iter().collect(),
+            OpcodeIns::TrapVector => vec![OperandType::Imm].into_iter().collect(),
+            OpcodeIns::Ldb | OpcodeIns::Stb | OpcodeIns::Ldw | OpcodeIns::Stw => vec![OperandType::Reg, OperandType::Reg, OperandType::Imm].into_iter().collect(),
+            OpcodeIns::Xor => vec![OperandType::Reg, OperandType::Reg, OperandType::RegOrImm].into_iter().collect(),
+            OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa => vec![OperandType::Reg, OperandType::Reg, OperandType::Imm].into_iter().collect(),
+            OpcodeIns::Mov => vec![OperandType::Reg, OperandType::Reg].into_iter().collect(),
+            OpcodeIns::Sub => vec![OperandType::Reg, OperandType::Reg, OperandType::Reg].into_iter().collect(),
+            OpcodeIns::Clr | OpcodeIns::Neg | OpcodeIns::Push | OpcodeIns::Pop | OpcodeIns::Inc | OpcodeIns::Dec => vec![OperandType::Reg].into_iter().collect(),
+            // Like `.FILL`, a label means its address
+            OpcodeIns::Ldimm => vec![OperandType::Reg, OperandType::ImmOrLabel].into_iter().collect(),
             _ => vec![].into_iter().collect(),
         }
     }
 
-    fn get_expected_operand_for_trap(&self, subroutine: u16) -> VecDeque<OperandType> {
-        match subroutine {
-            20 => vec![].into_iter().collect(),
-            21 => vec![].into_iter().collect(),
-            22 => vec![].into_iter().collect(),
-            23 => vec![].into_iter().collect(),
-            25 => vec![].into_iter().collect(),
-            _ => {
-                panic!("asm_ins::OpcodeIns::get_expected_operand_for_trap(): Received an impossible trap subroutine number.");
-            },
-        }
-    }
-
     fn get_br(nzp: &str) -> OpcodeIns {
         // nzp only contained everything AFTER br, that being nzp
 
@@ -163,15 +252,24 @@ impl OpcodeIns {
     
     pub fn get_immediate_value_width(&self) -> Option<i32> {
         match self {
-            OpcodeIns::Add | OpcodeIns::And => Some(5),
+            OpcodeIns::Add | OpcodeIns::And | OpcodeIns::Xor => Some(5),
             OpcodeIns::Ldr | OpcodeIns::Str => Some(6),
+            OpcodeIns::Ldb | OpcodeIns::Stb | OpcodeIns::Ldw | OpcodeIns::Stw => Some(6),
+            OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa => Some(4),
+            OpcodeIns::Ldimm => Some(16),
             OpcodeIns::Br(_,_,_) | OpcodeIns::Ld | OpcodeIns::Ldi => Some(9),
             OpcodeIns::Lea | OpcodeIns::St | OpcodeIns::Sti => Some(9),
             OpcodeIns::Jsr => Some(11),
+            OpcodeIns::TrapVector => Some(8),
             _ => None,
         }
     }
 
+    /* Trap vectors and shift amounts are the only immediate values that are not 2's complement. */
+    pub fn has_unsigned_immediate(&self) -> bool {
+        return matches!(self, OpcodeIns::TrapVector | OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa);
+    }
+
     pub fn get_opcode_value(&self) -> u16 {
         match self {
             OpcodeIns::Br(_,_,_) => 0,
@@ -188,9 +286,18 @@ impl OpcodeIns {
             OpcodeIns::Sti => 11,
             OpcodeIns::Jmp | OpcodeIns::Ret => 12,
             OpcodeIns::Lea => 14,
-            OpcodeIns::Trap(_) => 15,
-            // OpcodeIns::Reserved => 13,
-            OpcodeIns::Reserved | OpcodeIns::INVALID => unreachable!(),
+            OpcodeIns::Trap(_) | OpcodeIns::TrapVector => 15,
+            OpcodeIns::Reserved => 13,
+            OpcodeIns::Ldb => 2,
+            OpcodeIns::Stb => 3,
+            OpcodeIns::Ldw => 6,
+            OpcodeIns::Stw => 7,
+            OpcodeIns::Xor => 9,
+            OpcodeIns::Lshf | OpcodeIns::Rshfl | OpcodeIns::Rshfa => 13,
+            // Pseudo-instructions have no opcode of their own, see `pseudo::expand`
+            OpcodeIns::Mov | OpcodeIns::Clr | OpcodeIns::Sub | OpcodeIns::Neg | OpcodeIns::Push |
+            OpcodeIns::Pop | OpcodeIns::Ldimm | OpcodeIns::Inc | OpcodeIns::Dec => unreachable!(),
+            OpcodeIns::INVALID => unreachable!(),
         }
     }
 }
@@ -219,11 +326,12 @@ mod tests {
         assert!(OpcodeIns::from("ST") == OpcodeIns::St);
         assert!(OpcodeIns::from("STI") == OpcodeIns::Sti);
         assert!(OpcodeIns::from("STR") == OpcodeIns::Str);
-        assert!(OpcodeIns::from("GETC") == OpcodeIns::Trap(20));
-        assert!(OpcodeIns::from("OUT") == OpcodeIns::Trap(21));
-        assert!(OpcodeIns::from("PUTS") == OpcodeIns::Trap(22));
-        assert!(OpcodeIns::from("IN") == OpcodeIns::Trap(23));
-        assert!(OpcodeIns::from("HALT") == OpcodeIns::Trap(25));
+        assert!(OpcodeIns::from("GETC") == OpcodeIns::Trap(0x20));
+        assert!(OpcodeIns::from("OUT") == OpcodeIns::Trap(0x21));
+        assert!(OpcodeIns::from("PUTS") == OpcodeIns::Trap(0x22));
+        assert!(OpcodeIns::from("IN") == OpcodeIns::Trap(0x23));
+        assert!(OpcodeIns::from("PUTSP") == OpcodeIns::Trap(0x24));
+        assert!(OpcodeIns::from("HALT") == OpcodeIns::Trap(0x25));
 
 
         assert!(OpcodeIns::from("HALTT") == OpcodeIns::INVALID);
@@ -256,8 +364,8 @@ mod tests {
         assert!(OpcodeIns::from("NOT") == OpcodeIns::Not);
         assert!(OpcodeIns::from("LD") == OpcodeIns::Ld);
         assert!(OpcodeIns::from("lD") == OpcodeIns::Ld);
-        assert!(OpcodeIns::from("hAlT") == OpcodeIns::Trap(25));
-        assert!(OpcodeIns::from("halt") == OpcodeIns::Trap(25));
+        assert!(OpcodeIns::from("hAlT") == OpcodeIns::Trap(0x25));
+        assert!(OpcodeIns::from("halt") == OpcodeIns::Trap(0x25));
     }
 
     #[test]
@@ -282,4 +390,15 @@ mod tests {
         assert!(OpcodeIns::get_br("ADD") == OpcodeIns::INVALID);
         assert!(OpcodeIns::get_br("okay") == OpcodeIns::INVALID);
     }
+
+    #[test]
+    fn test_from_architecture() {
+        assert!(OpcodeIns::from_architecture("LDR", Architecture::Lc3) == OpcodeIns::Ldr);
+        assert!(OpcodeIns::from_architecture("LDR", Architecture::Lc3b) == OpcodeIns::INVALID);
+        assert!(OpcodeIns::from_architecture("LDW", Architecture::Lc3) == OpcodeIns::INVALID);
+        assert!(OpcodeIns::from_architecture("LDW", Architecture::Lc3b) == OpcodeIns::Ldw);
+        assert!(OpcodeIns::from_architecture("rshfa", Architecture::Lc3b) == OpcodeIns::Rshfa);
+        assert!(OpcodeIns::from_architecture("ADD", Architecture::Lc3b) == OpcodeIns::Add);
+        assert!(OpcodeIns::from_architecture("NOT", Architecture::Lc3b) == OpcodeIns::Not);
+    }
 }
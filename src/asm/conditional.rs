@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use super::asm_error::{AsmError, ErrorType};
+use super::expr::Expr;
+use super::macros::{is_identifier, split_words, substitute};
+
+const CODE_IF_NOT_ENDED: &'static str = "CD000";
+const CODE_UNMATCHED_CONDITIONAL: &'static str = "CD001";
+const CODE_INVALID_CONSTANT: &'static str = "CD002";
+const CODE_INVALID_CONDITION: &'static str = "CD003";
+
+/*
+Symbolic constants and conditional assembly, handled line by line while
+files are included, so `.IF` can leave out an `.INCLUDE` or a macro
+invocation:
+
+    .EQU   SIZE, #10          ; a constant, which cannot change
+    .SET   COUNT, SIZE*2      ; a constant, which a later .SET can change
+    .IF    SOLUTION           ; assembled if the value is not 0
+            ADD R1, R1, SIZE
+    .ELSE
+            ADD R1, R1, #0    ; TODO: your code here
+    .ENDIF
+
+A constant can be used wherever a number is accepted, and in expressions.
+Every use is replaced by the value the constant has on that line, so a
+constant has to be defined above its uses. Values may only use numbers
+and other constants, since labels are not known yet. A name cannot look
+like a register or a number, like `R1` or `x10`, and no label can have it.
+
+`-D NAME=value` (`Asm::define`) defines a constant from outside the file.
+It wins over an `.EQU` or `.SET` of the same name, so a file can give a
+default.
+
+The body of a `.MACRO` is left alone until the macro is expanded. Then
+`run_expanded` handles the directives and constants in the expanded lines,
+so a body can use `.SET` and `.IF` every time it is expanded.
+
+Lines that are left out, and the directives themselves, become empty
+lines, so every other line keeps its number.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constant {
+    pub value: i32,
+    // The line of the `.EQU` or last `.SET`, or 0 for a definition from outside the file
+    pub line_num: usize,
+    reassignable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    line_num: usize,
+    // Whether the lines around the block are assembled
+    outer_active: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+#[allow(dead_code)]
+pub struct Conditional {
+    // Definitions from outside the file
+    pub defines: HashMap<String, i32>,
+    // Every constant at the end of the last `run`
+    pub constants: HashMap<String, Constant>,
+    pub errors: Vec<AsmError>,
+    blocks: Vec<Block>,
+    // Whether the lines are inside a `.MACRO` body, which is left alone
+    in_macro: bool,
+}
+
+impl Block {
+    fn is_active(&self) -> bool {
+        return self.outer_active && (self.condition != self.in_else);
+    }
+}
+
+#[allow(dead_code)]
+impl Conditional {
+    pub fn new() -> Conditional {
+        Conditional {
+            defines: HashMap::new(),
+            constants: HashMap::new(),
+            errors: vec![],
+            blocks: vec![],
+            in_macro: false,
+        }
+    }
+
+    /*
+    Evaluates the conditions and replaces the constants in `file`.
+    `is_instruction` keeps instruction names from becoming constants.
+    */
+    pub fn run(&mut self, file: &str, is_instruction: &dyn Fn(&str) -> bool) -> String {
+        self.start();
+        return self.run_expanded(file, is_instruction);
+    }
+
+    /*
+    Like `run`, but keeps the constants from before, for a file whose macros
+    were just expanded.
+    */
+    pub fn run_expanded(&mut self, file: &str, is_instruction: &dyn Fn(&str) -> bool) -> String {
+        self.errors = vec![];
+        self.blocks = vec![];
+        self.in_macro = false;
+
+        let lines: Vec<String> = file.split('\n')
+            .enumerate()
+            .map(|(i, line)| self.run_line(line, i + 1, is_instruction))
+            .collect();
+        self.end_blocks(0, file);
+
+        return lines.join("\n");
+    }
+
+    /* Forgets every constant except the definitions from outside the file. */
+    pub fn start(&mut self) {
+        self.errors = vec![];
+        self.blocks = vec![];
+        self.in_macro = false;
+        self.constants = self.defines.iter()
+            .map(|(name, value)| (name.clone(), Constant { value: *value, line_num: 0, reassignable: false }))
+            .collect();
+    }
+
+    /*
+    Handles one line, and returns what it becomes: the line with its constants
+    replaced, or an empty line if it is left out or is a directive.
+    */
+    pub fn run_line(&mut self, line: &str, line_num: usize, is_instruction: &dyn Fn(&str) -> bool) -> String {
+        let words = split_words(line);
+        let directive = words.first().map(|word| word.to_ascii_uppercase());
+        let active = self.is_active();
+
+        if self.in_macro {
+            self.in_macro = directive.as_deref() != Some(".ENDM");
+            return line.to_string();
+        }
+
+        match directive.as_deref() {
+            Some(".IF") => {
+                // The condition of a block that is left out is not evaluated
+                let condition = active && self.evaluate_condition(&words[1..], line, line_num);
+                self.blocks.push(Block { line_num, outer_active: active, condition, in_else: false });
+            },
+            Some(".ELSE") => match self.blocks.last_mut() {
+                Some(block) if !block.in_else => block.in_else = true,
+                _ => self.push_error(CODE_UNMATCHED_CONDITIONAL, line, line_num, "`.ELSE` is not inside an `.IF` block, or the block already has an `.ELSE`."),
+            },
+            Some(".ENDIF") => match self.blocks.pop() {
+                Some(_) => {},
+                None => self.push_error(CODE_UNMATCHED_CONDITIONAL, line, line_num, "`.ENDIF` ends an `.IF` block, but no block was started with `.IF`."),
+            },
+            Some(directive @ (".EQU" | ".SET")) if active => {
+                self.define(directive == ".SET", &words[1..], line, line_num, is_instruction);
+            },
+            Some(".MACRO") if active => {
+                self.in_macro = true;
+                return line.to_string();
+            },
+            _ if active => return self.replace_constants(line),
+            _ => {},
+        }
+        return String::new();
+    }
+
+    /* How many `.IF` blocks are open. */
+    pub fn get_depth(&self) -> usize {
+        return self.blocks.len();
+    }
+
+    /*
+    Reports every block opened after there were `depth` open blocks, since
+    a block has to end in the file it starts in. `file` is that file.
+    */
+    pub fn end_blocks(&mut self, depth: usize, file: &str) {
+        self.in_macro = false;
+        while self.blocks.len() > depth {
+            let block = self.blocks.pop().unwrap();
+            let line = file.split('\n').nth(block.line_num - 1).unwrap_or("");
+            self.push_error(CODE_IF_NOT_ENDED, line, block.line_num, "this `.IF` block is never ended. To resolve this error, add `.ENDIF` after its last line.");
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        return self.blocks.last().is_none_or(|block| block.is_active());
+    }
+
+    fn evaluate_condition(&mut self, words: &[String], line: &str, line_num: usize) -> bool {
+        let [condition] = words else {
+            self.push_error(CODE_INVALID_CONDITION, line, line_num, "`.IF` takes one value without spaces, like `.IF SOLUTION` or `.IF SIZE-10`.");
+            return false;
+        };
+
+        match self.evaluate(condition) {
+            Ok(value) => return value != 0,
+            Err(msg) => {
+                self.push_error(CODE_INVALID_CONDITION, line, line_num, &format!("{}. HINT: `.IF` can only use numbers and constants defined above it.", msg));
+                return false;
+            },
+        }
+    }
+
+    fn define(&mut self, reassignable: bool, words: &[String], line: &str, line_num: usize, is_instruction: &dyn Fn(&str) -> bool) {
+        let [name, value] = words else {
+            self.push_error(CODE_INVALID_CONSTANT, line, line_num, "a constant needs a name and a value, like `.EQU SIZE, #10`.");
+            return;
+        };
+
+        if !is_constant_name(name) || is_instruction(&name.to_ascii_uppercase()) {
+            self.push_error(CODE_INVALID_CONSTANT, line, line_num, &format!("`{}` is not a valid constant name.", name));
+            return;
+        }
+        if let Some(other) = self.constants.get(name) {
+            // A definition from outside the file replaces the default in the file
+            if other.line_num == 0 {
+                return;
+            }
+            if !(other.reassignable && reassignable) {
+                let msg = format!("the constant `{}` was already defined on line {}. HINT: Use `.SET` for a constant that changes.", name, other.line_num);
+                self.push_error(CODE_INVALID_CONSTANT, line, line_num, &msg);
+                return;
+            }
+        }
+
+        match self.evaluate(value) {
+            Ok(value) => match check_range(value) {
+                Ok(value) => {
+                    self.constants.insert(name.clone(), Constant { value, line_num, reassignable });
+                },
+                Err(msg) => self.push_error(CODE_INVALID_CONSTANT, line, line_num, &format!("{}.", msg)),
+            },
+            Err(msg) => {
+                self.push_error(CODE_INVALID_CONSTANT, line, line_num, &format!("{}. HINT: A constant can only use numbers and constants defined above it.", msg));
+            },
+        }
+    }
+
+    fn evaluate(&self, text: &str) -> Result<i32, String> {
+        let constants = |name: &str| self.constants.get(name).map(|constant| constant.value);
+        return Expr::parse(text)?.evaluate(&constants);
+    }
+
+    fn replace_constants(&self, line: &str) -> String {
+        if self.constants.is_empty() {
+            return line.to_string();
+        }
+
+        let replacements: HashMap<String, String> = self.constants.iter()
+            .map(|(name, constant)| (name.clone(), format_number(constant.value)))
+            .collect();
+        // A number is never the first word of a line, so a constant there is a label with the
+        // same name. It is left alone for the semantic checker to report.
+        let start = line.len() - line.trim_start().len();
+        let split = line[start..].find(char::is_whitespace).map_or(line.len(), |end| start + end);
+        return format!("{}{}", &line[..split], substitute(&line[split..], &replacements));
+    }
+
+    fn push_error(&mut self, code: &str, line: &str, line_num: usize, msg: &str) {
+        self.errors.push(AsmError::new(
+            String::from(code),
+            line,
+            line_num as i32,
+            ErrorType::SyntaxError,
+            msg,
+        ));
+    }
+}
+
+/* Parses a `NAME=value` definition, like the `-D` option takes. */
+#[allow(dead_code)]
+pub fn parse_definition(text: &str) -> Result<(String, i32), String> {
+    let (name, value) = text.split_once('=').unwrap_or((text, "1"));
+
+    if !is_constant_name(name) {
+        return Err(format!("`{}` is not a valid constant name", name));
+    }
+    let value = Expr::parse(value)?.evaluate(&|_| None)?;
+    return Ok((name.to_string(), check_range(value)?));
+}
+
+/* A constant has to fit in one word, as a signed or an unsigned number. */
+pub fn check_range(value: i32) -> Result<i32, String> {
+    if !(-32768..=65535).contains(&value) {
+        return Err(format!("{} does not fit in 16 bits", value));
+    }
+    return Ok(value);
+}
+
+/* A number the lexer reads back as the same 16-bit word. */
+fn format_number(value: i32) -> String {
+    if value > i16::MAX as i32 {
+        return format!("x{:X}", value);
+    }
+    return format!("#{}", value);
+}
+
+/* A constant name cannot be read as a register or a number, like `R1` or `x10`. */
+pub fn is_constant_name(word: &str) -> bool {
+    let is_hex = word.strip_prefix(['x', 'X']).is_some_and(|hex| !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()));
+    return is_identifier(word) && !is_register(word) && !is_hex;
+}
+
+fn is_register(word: &str) -> bool {
+    let upper = word.to_ascii_uppercase();
+    return upper == "PC" || (upper.len() == 2 && upper.starts_with('R') && upper.as_bytes()[1].is_ascii_digit());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_instruction(word: &str) -> bool {
+        return ["ADD", "HALT"].contains(&word);
+    }
+
+    #[test]
+    fn test_constants_and_conditions() {
+        let mut conditional = Conditional::new();
+        conditional.defines.insert("SOLUTION".to_string(), 1);
+
+        let output = conditional.run(".EQU SIZE, #10
+.EQU SOLUTION, 0
+.SET N, SIZE*2
+ADD R1, R1, N ; N
+.IF SOLUTION
+.SET N, N+1
+.IF SIZE-10
+HALT
+.ELSE
+.FILL N
+.ENDIF
+.ELSE
+.FILL SIZE
+.ENDIF
+.SET N, xFFFF
+.FILL N", &is_instruction);
+
+        assert!(conditional.errors.is_empty());
+        assert_eq!(output.split('\n').collect::<Vec<&str>>(), vec![
+            "", "", "", "ADD R1, R1, #20 ; N", "", "", "", "", "", ".FILL #21", "", "", "", "", "", ".FILL xFFFF",
+        ]);
+        assert_eq!(conditional.constants.get("SIZE").map(|constant| constant.line_num), Some(1));
+    }
+
+    #[test]
+    fn test_macro_bodies() {
+        let mut conditional = Conditional::new();
+        let output = conditional.run(".EQU N, #1\n.MACRO M n\n.IF n\nADD R1, R1, N\n.ENDIF\n.ENDM\n.FILL N", &is_instruction);
+
+        // The body waits for `run_expanded`, with the constants from before
+        assert_eq!(output, "\n.MACRO M n\n.IF n\nADD R1, R1, N\n.ENDIF\n.ENDM\n.FILL #1");
+        assert_eq!(conditional.run_expanded("\n.IF 0\nHALT\n.ENDIF\nADD R1, R1, N", &is_instruction), "\n\n\n\nADD R1, R1, #1");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut conditional = Conditional::new();
+        conditional.run(".EQU A, #1
+.EQU A, #2
+.EQU ADD, #1
+.EQU x10, #5
+.EQU R1, #5
+.EQU B, label
+.IF
+.ENDIF
+.ELSE
+.ENDIF
+.IF A", &is_instruction);
+
+        let codes: Vec<&str> = conditional.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["CD002", "CD002", "CD002", "CD002", "CD002", "CD003", "CD001", "CD001", "CD000"]);
+    }
+
+    #[test]
+    fn test_parse_definition() {
+        assert_eq!(parse_definition("SOLUTION=1"), Ok(("SOLUTION".to_string(), 1)));
+        assert_eq!(parse_definition("SIZE=x10"), Ok(("SIZE".to_string(), 16)));
+        assert_eq!(parse_definition("DEBUG"), Ok(("DEBUG".to_string(), 1)));
+        assert!(parse_definition("1X=2").is_err());
+        assert!(parse_definition("xBEEF=2").is_err());
+        assert!(parse_definition("X=label").is_err());
+        assert!(parse_definition("BIG=70000").is_err());
+        assert!(parse_definition("SMALL=0-40000").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+/*
+Metadata from the `.BREAK` and `.ASSERT` directives. Neither directive
+emits a word, so a program assembles to exactly the same binary with or
+without them; they only end up here, beside the binary.
+
+    .BREAK              ; stop before the next instruction runs
+    .ASSERT R0 == #5    ; checked before the next instruction runs
+    .ASSERT [count] != x0
+
+An assertion compares two operands: a register (R0-R7 or PC), a number,
+a label (meaning its address), or `[...]` for the word in memory at a
+number or label. `<`, `<=`, `>` and `>=` compare as signed 16-bit numbers.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub breakpoints: Vec<u16>,
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssertOperand {
+    Register(usize),
+    Pc,
+    Number(u16),
+    Label(String),
+    Memory(Box<AssertOperand>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    // The address of the instruction the assertion is checked before
+    pub address: u16,
+    pub line_num: usize,
+    pub text: String,
+    pub lhs: AssertOperand,
+    pub comparison: Comparison,
+    pub rhs: AssertOperand,
+}
+
+#[allow(dead_code)]
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.breakpoints.is_empty() && self.assertions.is_empty();
+    }
+}
+
+#[allow(dead_code)]
+impl AssertOperand {
+    pub fn parse(text: &str) -> Result<AssertOperand, String> {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+
+        if let Some(inner) = text.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            return match AssertOperand::parse(inner)? {
+                operand @ (AssertOperand::Number(_) | AssertOperand::Label(_)) => Ok(AssertOperand::Memory(Box::new(operand))),
+                _ => Err(format!("`{}` must be a label or an address", inner.trim())),
+            };
+        }
+        if upper == "PC" {
+            return Ok(AssertOperand::Pc);
+        }
+        if upper.len() == 2 && upper.starts_with('R')
+            && let Some(index) = upper[1..].parse::<usize>().ok().filter(|index| *index < 8) {
+            return Ok(AssertOperand::Register(index));
+        }
+        if let Some(number) = parse_number(text) {
+            return Ok(AssertOperand::Number(number));
+        }
+        if !text.is_empty()
+            && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+            && !text.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(AssertOperand::Label(text.to_string()));
+        }
+
+        return Err(format!("`{}` is not a register, number, label or memory location", text));
+    }
+
+    /* Every label this operand refers to. */
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            AssertOperand::Label(label) => return vec![label],
+            AssertOperand::Memory(inner) => return inner.labels(),
+            _ => return vec![],
+        }
+    }
+
+    /* Replaces labels with their addresses. */
+    pub fn resolve(&self, symbols: &HashMap<String, u16>) -> Result<AssertOperand, String> {
+        match self {
+            AssertOperand::Label(label) => {
+                return symbols.get(label)
+                    .map(|address| AssertOperand::Number(*address))
+                    .ok_or(format!("the label `{}` is not defined", label));
+            },
+            AssertOperand::Memory(inner) => return Ok(AssertOperand::Memory(Box::new(inner.resolve(symbols)?))),
+            other => return Ok(other.clone()),
+        }
+    }
+}
+
+impl Comparison {
+    pub fn holds(&self, lhs: u16, rhs: u16) -> bool {
+        let (signed_lhs, signed_rhs) = (lhs as i16, rhs as i16);
+
+        match self {
+            Comparison::Eq => return lhs == rhs,
+            Comparison::Ne => return lhs != rhs,
+            Comparison::Lt => return signed_lhs < signed_rhs,
+            Comparison::Le => return signed_lhs <= signed_rhs,
+            Comparison::Gt => return signed_lhs > signed_rhs,
+            Comparison::Ge => return signed_lhs >= signed_rhs,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Assertion {
+    /* Parses the expression of an `.ASSERT`, like `R0 == #5`. */
+    pub fn parse_expression(text: &str) -> Result<(AssertOperand, Comparison, AssertOperand), String> {
+        // Two character operators first, so `<=` is not read as `<`
+        let operators = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ];
+
+        for (symbol, comparison) in operators {
+            if let Some((lhs, rhs)) = text.split_once(symbol) {
+                return Ok((AssertOperand::parse(lhs)?, comparison, AssertOperand::parse(rhs)?));
+            }
+        }
+
+        return Err(format!("`{}` does not compare anything. HINT: use ==, !=, <, <=, > or >=", text.trim()));
+    }
+}
+
+/* Parses `x3000` or `#-5`, like the assembler does. */
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('x').or(text.strip_prefix('X')) {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Some(decimal) = text.strip_prefix('#') {
+        return decimal.parse::<i16>().ok().map(|n| n as u16);
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(
+            Assertion::parse_expression("R0 == #5"),
+            Ok((AssertOperand::Register(0), Comparison::Eq, AssertOperand::Number(5)))
+        );
+        assert_eq!(
+            Assertion::parse_expression(" [count]<=x41"),
+            Ok((AssertOperand::Memory(Box::new(AssertOperand::Label("count".to_string()))), Comparison::Le, AssertOperand::Number(0x41)))
+        );
+        assert_eq!(
+            Assertion::parse_expression("pc != #-1"),
+            Ok((AssertOperand::Pc, Comparison::Ne, AssertOperand::Number(0xFFFF)))
+        );
+
+        assert!(Assertion::parse_expression("R0").is_err());
+        assert!(Assertion::parse_expression("R1 == #99999").is_err());
+        assert!(Assertion::parse_expression("[R1] == #1").is_err());
+        assert!(Assertion::parse_expression("R1 == 5").is_err());
+    }
+
+    #[test]
+    fn test_resolve_and_compare() {
+        let symbols = HashMap::from([("count".to_string(), 0x3005)]);
+        let operand = AssertOperand::parse("[count]").unwrap();
+
+        assert_eq!(operand.labels(), vec!["count"]);
+        assert_eq!(operand.resolve(&symbols), Ok(AssertOperand::Memory(Box::new(AssertOperand::Number(0x3005)))));
+        assert!(AssertOperand::parse("missing").unwrap().resolve(&symbols).is_err());
+
+        assert!(Comparison::Lt.holds(0xFFFF, 0));
+        assert!(Comparison::Ge.holds(5, 5));
+        assert!(!Comparison::Eq.holds(5, 6));
+    }
+}
@@ -15,6 +15,14 @@ pub enum Directive {
     FILL,
     BLKW,
     STRINGZ,
+    // A string packed two characters per word, for PUTSP
+    STRINGP,
+    // Debugging metadata, see `debug_info`. Neither emits a word.
+    BREAK,
+    ASSERT,
+    // Labels shared between object modules, see `object`. Neither emits a word.
+    GLOBAL,
+    EXTERN,
     END,
 }
 
@@ -25,6 +33,11 @@ impl Directive {
             ".FILL" => return Directive::FILL,
             ".BLKW" => return Directive::BLKW,
             ".STRINGZ" => return Directive::STRINGZ,
+            ".STRINGP" => return Directive::STRINGP,
+            ".BREAK" => return Directive::BREAK,
+            ".ASSERT" => return Directive::ASSERT,
+            ".GLOBAL" => return Directive::GLOBAL,
+            ".EXTERN" => return Directive::EXTERN,
             ".END" => return Directive::END,
             _ => unreachable!(),
         }
@@ -32,9 +45,33 @@ impl Directive {
 
     pub fn get_expected_operands(&self) -> VecDeque<OperandType> {
         match self {
-            Directive::ORIG | Directive::FILL | Directive::BLKW => vec![OperandType::Imm].into_iter().collect(),
-            Directive::STRINGZ => vec![OperandType::String].into_iter().collect(),
+            Directive::ORIG | Directive::BLKW => vec![OperandType::Imm].into_iter().collect(),
+            // A label is its address, for pointers and jump tables
+            Directive::FILL => vec![OperandType::ImmOrLabel].into_iter().collect(),
+            // The lexer turns the whole expression of an `.ASSERT` into a single string
+            Directive::STRINGZ | Directive::STRINGP | Directive::ASSERT => vec![OperandType::String].into_iter().collect(),
+            Directive::GLOBAL | Directive::EXTERN => vec![OperandType::Label].into_iter().collect(),
             _ => vec![].into_iter().collect(),
         }
     }
+
+    /*
+    Packs `string` the way PUTSP expects it: the first character of each pair
+    goes in bits [7:0] and the second in bits [15:8]. The string always ends
+    with a zero byte, which is a full x0000 word when the length is even.
+    */
+    pub fn pack_string(string: &str) -> Vec<u16> {
+        let bytes: Vec<u16> = string.chars().map(|c| c as u16 & 0xFF).collect();
+        let mut output: Vec<u16> = vec![];
+
+        for pair in bytes.chunks(2) {
+            let high = *pair.get(1).unwrap_or(&0);
+            output.push((high << 8) | pair[0]);
+        }
+        if bytes.len().is_multiple_of(2) {
+            output.push(0);
+        }
+
+        return output;
+    }
 }
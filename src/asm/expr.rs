@@ -0,0 +1,238 @@
+/*
+An arithmetic expression, used wherever an operand takes a number or a
+label:
+
+    LD   R0, TABLE+2
+    ADD  R1, R1, #10*4
+    .FILL 'A'+1
+    .BLKW (END-START)/2
+
+Expressions have no whitespace, since the lexer splits words on it. They
+are made of labels, `#` decimal numbers, `x` hexadecimal numbers, bare
+decimal numbers, character literals like 'A', the operators `+ - * /`
+and parentheses. Hexadecimal numbers are unsigned here, so `xFFFF` is
+65535 and not -1. Division rounds towards zero.
+
+A label means its address, so it can only be evaluated once the symbol
+table is known.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Label(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Operator, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[allow(dead_code)]
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut parser = Parser { chars: &chars, position: 0 };
+        let expr = parser.parse_sum()?;
+
+        if parser.position < chars.len() {
+            return Err(format!("unexpected `{}` in the expression `{}`", chars[parser.position], text));
+        }
+        return Ok(expr);
+    }
+
+    /* Every label this expression refers to. */
+    pub fn labels(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => return vec![],
+            Expr::Label(label) => return vec![label],
+            Expr::Neg(inner) => return inner.labels(),
+            Expr::Binary(lhs, _, rhs) => return lhs.labels().into_iter().chain(rhs.labels()).collect(),
+        }
+    }
+
+    /* Evaluates the expression, looking up the address of each label with `symbols`. */
+    pub fn evaluate(&self, symbols: &dyn Fn(&str) -> Option<i32>) -> Result<i32, String> {
+        match self {
+            Expr::Number(number) => return Ok(*number),
+            Expr::Label(label) => return symbols(label).ok_or(format!("the label `{}` is not defined", label)),
+            Expr::Neg(inner) => return Ok(inner.evaluate(symbols)?.wrapping_neg()),
+            Expr::Binary(lhs, operator, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(symbols)?, rhs.evaluate(symbols)?);
+
+                match operator {
+                    Operator::Add => return Ok(lhs.wrapping_add(rhs)),
+                    Operator::Sub => return Ok(lhs.wrapping_sub(rhs)),
+                    Operator::Mul => return Ok(lhs.wrapping_mul(rhs)),
+                    Operator::Div if rhs == 0 => return Err("the expression divides by zero".to_string()),
+                    Operator::Div => return Ok(lhs.wrapping_div(rhs)),
+                }
+            },
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.position).copied();
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_product()?;
+
+        while let Some(c @ ('+' | '-')) = self.peek() {
+            self.position += 1;
+            let operator = if c == '+' { Operator::Add } else { Operator::Sub };
+            expr = Expr::Binary(Box::new(expr), operator, Box::new(self.parse_product()?));
+        }
+        return Ok(expr);
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+
+        while let Some(c @ ('*' | '/')) = self.peek() {
+            self.position += 1;
+            let operator = if c == '*' { Operator::Mul } else { Operator::Div };
+            expr = Expr::Binary(Box::new(expr), operator, Box::new(self.parse_unary()?));
+        }
+        return Ok(expr);
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                return Ok(Expr::Neg(Box::new(self.parse_unary()?)));
+            },
+            Some('+') => {
+                self.position += 1;
+                return self.parse_unary();
+            },
+            _ => return self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let expr = self.parse_sum()?;
+                if self.peek() != Some(')') {
+                    return Err("a `(` in the expression is never closed".to_string());
+                }
+                self.position += 1;
+                return Ok(expr);
+            },
+            Some('\'') => {
+                let literal: String = self.chars.iter().skip(self.position).take(3).collect();
+                let mut literal_chars = literal.chars();
+                if let (Some(_), Some(c), Some('\'')) = (literal_chars.next(), literal_chars.next(), literal_chars.next()) {
+                    self.position += 3;
+                    return Ok(Expr::Number(c as i32));
+                }
+                return Err(format!("`{}` is not a character literal like 'A'", literal));
+            },
+            Some('#') => {
+                self.position += 1;
+                let negative = self.peek() == Some('-');
+                if negative {
+                    self.position += 1;
+                }
+                let digits = self.take_word();
+                let number = digits.parse::<i32>().map_err(|_| format!("`#{}` is not a decimal number", digits))?;
+                return Ok(Expr::Number(if negative { -number } else { number }));
+            },
+            Some(c) if c.is_ascii_alphanumeric() || c == '_' => {
+                let word = self.take_word();
+
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    return word.parse::<i32>().map(Expr::Number).map_err(|_| format!("`{}` is not a decimal number", word));
+                }
+                if let Some(hex) = word.strip_prefix('x').or(word.strip_prefix('X'))
+                    && !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return i32::from_str_radix(hex, 16).map(Expr::Number).map_err(|_| format!("`{}` is too large", word));
+                }
+                // A local label with the label it belongs to, like `MAIN.loop`
+                if self.peek() == Some('.') {
+                    self.position += 1;
+                    return Ok(Expr::Label(format!("{}.{}", word, self.take_word())));
+                }
+                // A label a macro made local to one expansion, like `loop$1`
+                if self.peek() == Some('$') {
+                    self.position += 1;
+                    return Ok(Expr::Label(format!("{}${}", word, self.take_word())));
+                }
+                return Ok(Expr::Label(word));
+            },
+            Some(c @ ('.' | '@')) => {
+                self.position += 1;
+                let name = self.take_word();
+                if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+                    return Err(format!("`{}{}` is not a local label like `.loop`", c, name));
+                }
+                return Ok(Expr::Label(format!("{}{}", c, name)));
+            },
+            Some(c) => return Err(format!("expected a number or label, but found `{}`", c)),
+            None => return Err("the expression ends too early".to_string()),
+        }
+    }
+
+    fn take_word(&mut self) -> String {
+        let mut word = String::new();
+
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            word.push(c);
+            self.position += 1;
+        }
+        return word;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text: &str) -> Result<i32, String> {
+        let symbols = |label: &str| match label {
+            "START" => Some(0x3000),
+            "END" => Some(0x3010),
+            _ => None,
+        };
+        return Expr::parse(text)?.evaluate(&symbols);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("START+2"), Ok(0x3002));
+        assert_eq!(evaluate("END-START"), Ok(16));
+        assert_eq!(evaluate("#10*4"), Ok(40));
+        assert_eq!(evaluate("'A'+1"), Ok(66));
+        assert_eq!(evaluate("(END-START)/2"), Ok(8));
+        assert_eq!(evaluate("2+3*4"), Ok(14));
+        assert_eq!(evaluate("-(2+3)*#-1"), Ok(5));
+        assert_eq!(evaluate("xFFFF+1"), Ok(0x10000));
+
+        assert_eq!(Expr::parse("MAIN.loop+.x-@y").unwrap().labels(), vec!["MAIN.loop", ".x", "@y"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(evaluate("START+").is_err());
+        assert!(evaluate("(START+2").is_err());
+        assert!(evaluate("START)").is_err());
+        assert!(evaluate("MISSING+1").is_err());
+        assert!(evaluate("#1/0").is_err());
+        assert!(evaluate("'AB'").is_err());
+        assert_eq!(Expr::parse("(END-START)/LEN").unwrap().labels(), vec!["END", "START", "LEN"]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::asm_error::{AsmError, ErrorType};
+use super::conditional::Conditional;
+
+const CODE_INCLUDE_NOT_FOUND: &'static str = "IN000";
+const CODE_INCLUDE_CYCLE: &'static str = "IN001";
+const CODE_INVALID_INCLUDE: &'static str = "IN002";
+
+/*
+`.INCLUDE` pastes another file in place of the directive, before macros
+are expanded and before the rest of the assembler sees the file:
+
+    .ORIG x3000
+            JSR PRINT_NUM
+            HALT
+    .INCLUDE "lib/io.asm"   ; subroutines only, no .ORIG or .END
+    .END
+
+A file name is looked up in this order:
+
+    1. the sources given to `Asm::add_source`, by their exact name
+    2. the directory of the file with the `.INCLUDE`
+    3. every directory given to `Asm::add_include_path`, in order
+
+Constants and `.IF` blocks are handled as each line is read, so an
+`.INCLUDE` in a block that is left out is never looked up. A block has to
+end in the file it starts in.
+
+A file that is included twice is only pasted the first time, so two
+libraries can share a third one. A file that includes itself, directly
+or through other files, is an error.
+
+Every file gets a number, its index in `files`. The main file is 0. Every
+line of the combined text remembers its file and its line in that file.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Included {
+    pub text: String,
+    // `lines[i]` is the file and line that line `i + 1` of `text` came from
+    pub lines: Vec<(usize, usize)>,
+}
+
+#[allow(dead_code)]
+pub struct Includer {
+    pub include_paths: Vec<PathBuf>,
+    // The name of every file, by file number
+    pub files: Vec<String>,
+    pub errors: Vec<AsmError>,
+    sources: HashMap<String, String>,
+    // What makes two names the same file, by file number
+    keys: Vec<String>,
+}
+
+#[allow(dead_code)]
+impl Included {
+    /* The file and line that `line_num` of the combined text came from. */
+    pub fn locate(&self, line_num: usize) -> (usize, usize) {
+        if line_num == 0 {
+            return (0, 0);
+        }
+        return self.lines.get(line_num - 1).copied().unwrap_or((0, line_num));
+    }
+}
+
+#[allow(dead_code)]
+impl Includer {
+    pub fn new() -> Includer {
+        Includer {
+            include_paths: vec![],
+            files: vec![],
+            errors: vec![],
+            sources: HashMap::new(),
+            keys: vec![],
+        }
+    }
+
+    pub fn add_source(&mut self, name: &str, contents: String) {
+        self.sources.insert(name.to_string(), contents);
+    }
+
+    /*
+    Pastes every file `file` includes into it, and runs `conditional` on every
+    line. `name` is the path of `file`, or empty if it has none.
+    */
+    pub fn run(&mut self, name: &str, file: &str, conditional: &mut Conditional, is_instruction: &dyn Fn(&str) -> bool) -> Included {
+        self.files = vec![name.to_string()];
+        self.keys = vec![get_key(Path::new(name))];
+        self.errors = vec![];
+
+        let mut included = Included::default();
+        conditional.start();
+        self.include(0, file, &mut vec![0], &mut included, conditional, is_instruction);
+        // Every line was given a newline, but the last one did not have one
+        included.text.pop();
+
+        return included;
+    }
+
+    pub fn get_file_name(&self, file: usize) -> Option<&str> {
+        return self.files.get(file).map(|name| name.as_str());
+    }
+
+    fn include(&mut self, file_num: usize, file: &str, stack: &mut Vec<usize>, included: &mut Included,
+        conditional: &mut Conditional, is_instruction: &dyn Fn(&str) -> bool) {
+        let dir = Path::new(&self.files[file_num]).parent().map(|dir| dir.to_path_buf());
+        let depth = conditional.get_depth();
+
+        for (i, line) in file.split('\n').enumerate() {
+            let line_num = i + 1;
+            let output = conditional.run_line(line, line_num, is_instruction);
+            self.take_errors(conditional, file_num);
+
+            let code = output.split(';').next().unwrap_or("").trim_start();
+            let is_include = code.get(..8).is_some_and(|word| word.eq_ignore_ascii_case(".INCLUDE"))
+                && code[8..].chars().next().is_none_or(|c| c.is_whitespace() || c == '"');
+
+            if !is_include {
+                included.text.push_str(&output);
+                included.text.push('\n');
+                included.lines.push((file_num, line_num));
+                continue;
+            }
+
+            let Some(name) = parse_file_name(&line.trim_start()[8..]) else {
+                self.push_error(CODE_INVALID_INCLUDE, file_num, line, line_num, "`.INCLUDE` takes one file name in quotes, like `.INCLUDE \"lib/io.asm\"`.");
+                continue;
+            };
+            let Some((name, key, contents)) = self.find(&name, dir.as_deref()) else {
+                let msg = format!("the file `{}` could not be found. HINT: Is it in the same directory, or in an include path?", name);
+                self.push_error(CODE_INCLUDE_NOT_FOUND, file_num, line, line_num, &msg);
+                continue;
+            };
+
+            match self.keys.iter().position(|other| *other == key) {
+                Some(other) if stack.contains(&other) => {
+                    let msg = format!("`{}` is already being included, so including it again would never end.", self.files[other]);
+                    self.push_error(CODE_INCLUDE_CYCLE, file_num, line, line_num, &msg);
+                },
+                // Already pasted once
+                Some(_) => {},
+                None => {
+                    let other = self.files.len();
+                    self.files.push(name);
+                    self.keys.push(key);
+
+                    stack.push(other);
+                    self.include(other, &contents, stack, included, conditional, is_instruction);
+                    stack.pop();
+                },
+            }
+        }
+
+        conditional.end_blocks(depth, file);
+        self.take_errors(conditional, file_num);
+    }
+
+    /* Moves the errors of `conditional` into `errors`, as errors in the file `file_num`. */
+    fn take_errors(&mut self, conditional: &mut Conditional, file_num: usize) {
+        for mut error in conditional.errors.drain(..) {
+            error.set_file(&self.files[file_num]);
+            self.errors.push(error);
+        }
+    }
+
+    /* The name, identity and contents of the file `name` refers to. */
+    fn find(&self, name: &str, dir: Option<&Path>) -> Option<(String, String, String)> {
+        if let Some(contents) = self.sources.get(name) {
+            return Some((name.to_string(), name.to_string(), contents.clone()));
+        }
+
+        let mut candidates = vec![dir.unwrap_or(Path::new("")).join(name)];
+        candidates.extend(self.include_paths.iter().map(|include_path| include_path.join(name)));
+
+        for path in candidates {
+            if let Ok(contents) = fs::read_to_string(&path) {
+                return Some((path.display().to_string(), get_key(&path), contents));
+            }
+        }
+        return None;
+    }
+
+    fn push_error(&mut self, code: &str, file_num: usize, line: &str, line_num: usize, msg: &str) {
+        let mut error = AsmError::new(String::from(code), line, line_num as i32, ErrorType::SyntaxError, msg);
+        error.set_file(&self.files[file_num]);
+        self.errors.push(error);
+    }
+}
+
+/* The file name in `"file.asm"`, with nothing but a comment after it. */
+fn parse_file_name(rest: &str) -> Option<String> {
+    let rest = rest.trim_start().strip_prefix('"')?;
+    let (name, after) = rest.split_once('"')?;
+    let after = after.trim_start();
+
+    if name.is_empty() || !(after.is_empty() || after.starts_with(';')) {
+        return None;
+    }
+    return Some(name.to_string());
+}
+
+/* Two paths are the same file if they resolve to the same place. */
+fn get_key(path: &Path) -> String {
+    return fs::canonicalize(path).unwrap_or(path.to_path_buf()).display().to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(includer: &mut Includer, file: &str) -> Included {
+        return includer.run("", file, &mut Conditional::new(), &|word| word == "ADD");
+    }
+
+    #[test]
+    fn test_include() {
+        let mut includer = Includer::new();
+        includer.add_source("io.asm", "PUTS_TWICE PUTS\n.INCLUDE \"util.asm\"\nPUTS".to_string());
+        includer.add_source("util.asm", "; util\nRET".to_string());
+
+        let included = run(&mut includer, "main\n.include \"io.asm\" ; I/O\n.INCLUDE \"util.asm\"\nHALT");
+
+        assert!(includer.errors.is_empty());
+        assert_eq!(included.text, "main\nPUTS_TWICE PUTS\n; util\nRET\nPUTS\nHALT");
+        assert_eq!(includer.files, vec!["", "io.asm", "util.asm"]);
+        assert_eq!(included.locate(2), (1, 1));
+        assert_eq!(included.locate(4), (2, 2));
+        assert_eq!(included.locate(5), (1, 3));
+        assert_eq!(included.locate(6), (0, 4));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut includer = Includer::new();
+        includer.add_source("a.asm", ".INCLUDE \"b.asm\"".to_string());
+        includer.add_source("b.asm", "\n.INCLUDE \"a.asm\"".to_string());
+
+        run(&mut includer, ".INCLUDE \"a.asm\"\n.INCLUDE \"missing.asm\"\n.INCLUDE io.asm");
+
+        let codes: Vec<&str> = includer.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["IN001", "IN000", "IN002"]);
+        assert!(includer.errors[0].generate_msg().contains("In `b.asm`, on line 2"));
+    }
+    #[test]
+    fn test_conditional() {
+        let mut includer = Includer::new();
+        includer.add_source("io.asm", ".EQU SIZE, #2\n.IF 1\nPUTS".to_string());
+
+        let included = run(&mut includer, ".IF 0\n.INCLUDE \"missing.asm\"\n.ELSE\n.INCLUDE \"io.asm\"\n.ENDIF\n.BLKW SIZE");
+
+        let codes: Vec<&str> = includer.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["CD000"]);
+        assert!(includer.errors[0].generate_msg().contains("In `io.asm`, on line 2"));
+        assert_eq!(included.text, "\n\n\n\n\nPUTS\n\n.BLKW #2");
+    }
+}
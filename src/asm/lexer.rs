@@ -3,6 +3,7 @@ use super::directive::*;
 use super::syntax::SyntaxChecker;
 use super::asm_error::*;
 use super::token::*;
+use crate::vm::arch::Architecture;
 
 const CODE_TOKEN_NO_CATEGORY: &'static str = "SX001";
 const CODE_STRING_NOT_ENDED: &'static str = "SX002";
@@ -12,6 +13,14 @@ pub struct Lexer {
     pub token_stream: Vec<Token>,
     pub errors: Vec<AsmError>,
     pub syntax_checker: SyntaxChecker,
+    // The mnemonic of the opcode 1101 extension, if there is one
+    extension_name: Option<String>,
+    architecture: Architecture,
+    pseudo_ops: bool,
+    // The last label defined that is not local, which local labels belong to
+    label_scope: String,
+    // How many anonymous labels have been defined so far
+    anonymous_count: usize,
     curr_file: String,
     file_as_chars: Vec<char>,
     curr_line_num: i32,
@@ -26,6 +35,11 @@ impl Lexer {
             token_stream: vec![],
             errors: vec![],
             syntax_checker: SyntaxChecker::new(),
+            extension_name: None,
+            architecture: Architecture::Lc3,
+            pseudo_ops: false,
+            label_scope: String::new(),
+            anonymous_count: 0,
             curr_file: String::new(),
             file_as_chars: vec![],
             curr_line_num: 1,
@@ -35,6 +49,22 @@ impl Lexer {
         }
     }
 
+    /* Makes `name` an instruction, lexed as `OpcodeIns::Reserved`. */
+    pub fn set_extension_name(&mut self, name: &str) {
+        self.extension_name = Some(name.to_ascii_uppercase());
+    }
+
+    pub fn set_architecture(&mut self, architecture: Architecture) {
+        self.architecture = architecture;
+        self.syntax_checker = SyntaxChecker::with_architecture(architecture);
+        self.syntax_checker.set_pseudo_ops(self.pseudo_ops);
+    }
+
+    pub fn set_pseudo_ops(&mut self, enabled: bool) {
+        self.pseudo_ops = enabled;
+        self.syntax_checker.set_pseudo_ops(enabled);
+    }
+
     pub fn run(&mut self, mut input_file: String) -> Vec<Token> {
         input_file.push(' ');
         self.file_length = input_file.len();
@@ -75,6 +105,10 @@ impl Lexer {
             if (c.is_whitespace() || c == ';' || c == ',') && word_buffer.len() > 0 {
                 self.parse_word(word_buffer.iter().collect());
                 word_buffer.clear();
+                if c != '\n' && c != ';' && self.last_token_is_assert() {
+                    self.parse_assert_expression();
+                    continue;
+                }
                 if c == '\n' {
                     self.next_line();
                 }
@@ -137,7 +171,10 @@ impl Lexer {
 
     fn reset(&mut self) {
         self.token_stream = vec![];
-        self.syntax_checker = SyntaxChecker::new();
+        self.syntax_checker = SyntaxChecker::with_architecture(self.architecture);
+        self.syntax_checker.set_pseudo_ops(self.pseudo_ops);
+        self.label_scope = String::new();
+        self.anonymous_count = 0;
         self.curr_file = String::new();
         self.file_as_chars = vec![];
         self.curr_line_num = 1;
@@ -153,13 +190,23 @@ impl Lexer {
         if self.syntax_checker.is_ignore(&upper) {
             return;
         }
+        else if self.extension_name.as_ref() == Some(&upper) {
+            self.token_stream.push(Token::new(
+                self.file_position,
+                self.line_position,
+                self.curr_line_num,
+                &word,
+                TokenType::Instruction(OpcodeIns::Reserved)
+            ));
+            return;
+        }
         else if self.syntax_checker.is_instruction_name(&upper) {
             self.token_stream.push(Token::new(
                 self.file_position,
                 self.line_position,
                 self.curr_line_num,
                 &word,
-                TokenType::Instruction(OpcodeIns::from(&upper))
+                TokenType::Instruction(OpcodeIns::from_architecture(&upper, self.architecture))
             ));
             return;
         }
@@ -193,13 +240,27 @@ impl Lexer {
             ));
             return;
         }
-        else if self.syntax_checker.is_valid_label(&word) {
+        else if self.syntax_checker.is_valid_label(&word)
+            || self.syntax_checker.is_valid_local_label(&word)
+            || self.syntax_checker.is_valid_anonymous_label(&word) {
+            let label = self.resolve_label(&word);
+            self.token_stream.push(Token::new(
+                self.file_position,
+                self.line_position,
+                self.curr_line_num,
+                &word,
+                TokenType::Label(label)
+            ));
+            return;
+        }
+        else if self.syntax_checker.is_valid_expression(&word) {
+            let expression = self.resolve_expression(&word);
             self.token_stream.push(Token::new(
                 self.file_position,
                 self.line_position,
                 self.curr_line_num,
                 &word,
-                TokenType::Label(word.to_string())
+                TokenType::Expression(expression)
             ));
             return;
         }
@@ -222,6 +283,94 @@ impl Lexer {
         }
     }
 
+    /*
+    The name of a label in the symbol table. A local label like `.loop` (or
+    `@loop`) belongs to the label defined before it, so `MAIN` and `SUB` can
+    both have a `.loop`, which become `MAIN.loop` and `SUB.loop`.
+
+    Anonymous labels are numbered: the third `:` becomes `:3`. `:+` is the
+    next one after this line and `:-` the last one, and every extra `+` or
+    `-` goes one further.
+    */
+    fn resolve_label(&mut self, word: &str) -> String {
+        // A label is defined by being the first word of its line
+        let is_definition = self.token_stream.last().is_none_or(|token| token.line_num != self.curr_line_num as usize);
+
+        if word == ":" {
+            self.anonymous_count += 1;
+            return format!(":{}", self.anonymous_count);
+        }
+        if let Some(steps) = word.strip_prefix(':') {
+            let count = self.anonymous_count as i32;
+            let number = if steps.starts_with('+') { count + steps.len() as i32 } else { count + 1 - steps.len() as i32 };
+            return format!(":{}", number);
+        }
+        if let Some(name) = word.strip_prefix(['.', '@']) {
+            return format!("{}.{}", self.label_scope, name);
+        }
+
+        if is_definition {
+            self.label_scope = word.to_string();
+        }
+        return word.to_string();
+    }
+
+    /* Resolves every local label in an expression, like the `.table` in `.table+1`. */
+    fn resolve_expression(&mut self, word: &str) -> String {
+        let chars: Vec<char> = word.chars().collect();
+        let mut output = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let starts_label = (chars[i] == '.' || chars[i] == '@')
+                && (i == 0 || !(chars[i - 1].is_ascii_alphanumeric() || chars[i - 1] == '_' || chars[i - 1] == '\''))
+                && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic() || *c == '_');
+            if !starts_label {
+                output.push(chars[i]);
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let label: String = chars[start..i].iter().collect();
+            output += &self.resolve_label(&label);
+        }
+        return output;
+    }
+
+    fn last_token_is_assert(&self) -> bool {
+        return matches!(
+            self.token_stream.last(),
+            Some(Token { inner_token: TokenType::Directive(Directive::ASSERT), .. })
+        );
+    }
+
+    /*
+    The expression of an `.ASSERT` is everything up to the end of the line or
+    a comment. It becomes one string token that the semantic checker parses.
+    */
+    pub fn parse_assert_expression(&mut self) {
+        let mut expression = String::new();
+
+        while self.file_position < self.file_as_chars.len()
+            && self.file_as_chars[self.file_position] != '\n'
+            && self.file_as_chars[self.file_position] != ';' {
+            expression.push(self.next_char());
+        }
+
+        self.token_stream.push(Token::new(
+            self.file_position + 1,
+            self.line_position + 1,
+            self.curr_line_num,
+            &expression,
+            TokenType::String(expression.trim().to_string()),
+        ));
+    }
+
     pub fn parse_register(&self, word: &str) -> u16 {
         let base = 10;
 
@@ -430,29 +579,33 @@ mod tests {
         );
         assert_eq!(
             lexer.run(String::from(" GETC "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(20))
+            TokenType::Instruction(OpcodeIns::Trap(0x20))
         );
         assert_eq!(
             lexer.run(String::from(" OUT "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(21))
+            TokenType::Instruction(OpcodeIns::Trap(0x21))
         );
         assert_eq!(
             lexer.run(String::from(" PUTS "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(22))
+            TokenType::Instruction(OpcodeIns::Trap(0x22))
         );
         assert_eq!(
             lexer.run(String::from(" IN "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(23))
+            TokenType::Instruction(OpcodeIns::Trap(0x23))
+        );
+        assert_eq!(
+            lexer.run(String::from(" PUTSP "))[0].inner_token,
+            TokenType::Instruction(OpcodeIns::Trap(0x24))
         );
         assert_eq!(
             lexer.run(String::from(" HALT "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(25))
+            TokenType::Instruction(OpcodeIns::Trap(0x25))
         );
 
 
         assert_ne!(
             lexer.run(String::from(" HALTS "))[0].inner_token,
-            TokenType::Instruction(OpcodeIns::Trap(25))
+            TokenType::Instruction(OpcodeIns::Trap(0x25))
         );
         assert_ne!(
             lexer.run(String::from(" ADDI "))[0].inner_token,
@@ -552,6 +705,10 @@ mod tests {
             lexer.run(String::from(" .STRINGZ "))[0].inner_token,
             TokenType::Directive(Directive::STRINGZ)
         );
+        assert_eq!(
+            lexer.run(String::from(" .STRINGP "))[0].inner_token,
+            TokenType::Directive(Directive::STRINGP)
+        );
         assert_eq!(
             lexer.run(String::from(" .END "))[0].inner_token,
             TokenType::Directive(Directive::END)
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use super::asm_error::{AsmError, ErrorType};
+use super::object::{ObjectModule, RelocationKind};
+
+const CODE_GLOBAL_REDEFINED: &'static str = "LK001";
+const CODE_UNDEFINED_SYMBOL: &'static str = "LK002";
+const CODE_OFFSET_OUT_OF_RANGE: &'static str = "LK003";
+const CODE_IMAGE_TOO_LARGE: &'static str = "LK004";
+// LK005 is `Asm::run_object`'s
+const CODE_INVALID_MODULE: &'static str = "LK006";
+
+/*
+Puts object modules together into one loadable image, in the same format
+`Asm::run` returns: the origin, then every word.
+
+Modules are placed one after the other, in the order they are given,
+starting at `base` (or the `.ORIG` of the first module). Then every
+`.GLOBAL` label gets its final address, and the relocations of every
+module are applied. Errors name the module and the line they came from.
+*/
+#[allow(dead_code)]
+pub struct Linker {
+    pub errors: Vec<AsmError>,
+    // The final address of every `.GLOBAL` label, from the last `link`
+    pub symbols: HashMap<String, u16>,
+    // Where every module was placed, from the last `link`
+    pub placements: Vec<u16>,
+}
+
+#[allow(dead_code)]
+impl Linker {
+    pub fn new() -> Linker {
+        Linker {
+            errors: vec![],
+            symbols: HashMap::new(),
+            placements: vec![],
+        }
+    }
+
+    pub fn link(&mut self, modules: &[ObjectModule], base: Option<u16>) -> Vec<u16> {
+        self.errors = vec![];
+        self.symbols = HashMap::new();
+        self.placements = vec![];
+
+        let Some(first) = modules.first() else {
+            return vec![];
+        };
+
+        // 0. Object files can come from anywhere, so check what the rest relies on
+        for module in modules {
+            self.validate(module);
+        }
+        if !self.errors.is_empty() {
+            return vec![];
+        }
+
+        // 1. Place every module
+        let mut next = base.unwrap_or(first.origin) as usize;
+        for module in modules {
+            self.placements.push(next as u16);
+            next += module.words.len();
+        }
+        if next > 0x10000 {
+            let msg = format!("the linked program ends at x{:X}, past the end of memory.", next);
+            self.push_error(CODE_IMAGE_TOO_LARGE, &modules[modules.len() - 1].name, 0, &msg);
+            return vec![];
+        }
+
+        // 2. Find the final address of every global
+        let mut owners: HashMap<String, &str> = HashMap::new();
+        for (module, placement) in modules.iter().zip(self.placements.clone()) {
+            for (label, address) in module.globals.iter() {
+                if let Some(owner) = owners.get(label) {
+                    let msg = format!("the global label `{}` is also defined in `{}`.", label, owner);
+                    self.push_error(CODE_GLOBAL_REDEFINED, &module.name, 0, &msg);
+                    continue;
+                }
+                owners.insert(label.clone(), &module.name);
+                self.symbols.insert(label.clone(), address.wrapping_sub(module.origin).wrapping_add(placement));
+            }
+        }
+
+        // 3. Apply the relocations
+        let mut image: Vec<u16> = vec![self.placements[0]];
+        for (module, placement) in modules.iter().zip(self.placements.clone()) {
+            let mut words = module.words.clone();
+            let delta = placement.wrapping_sub(module.origin);
+
+            for relocation in module.relocations.iter() {
+                let index = relocation.address.wrapping_sub(module.origin) as usize;
+                let target = match &relocation.symbol {
+                    None => None,
+                    Some(symbol) => match self.symbols.get(symbol) {
+                        Some(address) => Some(*address),
+                        None => {
+                            let msg = format!("the label `{}` is `.EXTERN`, but no module makes it `.GLOBAL`.", symbol);
+                            self.push_error(CODE_UNDEFINED_SYMBOL, &module.name, relocation.line_num, &msg);
+                            continue;
+                        },
+                    },
+                };
+
+                match (relocation.kind, target) {
+                    (RelocationKind::Absolute, None) => words[index] = words[index].wrapping_add(delta),
+                    (RelocationKind::Absolute, Some(address)) => words[index] = words[index].wrapping_add(address),
+                    (RelocationKind::PcOffset(_), None) => {},
+                    (RelocationKind::PcOffset(width), Some(address)) => {
+                        let pc = relocation.address.wrapping_add(delta).wrapping_add(1);
+                        let offset = address as i32 - pc as i32;
+                        let limit = 1 << (width - 1);
+
+                        if offset < -limit || offset >= limit {
+                            let msg = format!(
+                                "`{}` is {} words away after linking, but this instruction can only reach {} to {}. HINT: Link the modules in a different order, or load the address with `.FILL`.",
+                                relocation.symbol.as_deref().unwrap_or(""), offset, -limit, limit - 1,
+                            );
+                            self.push_error(CODE_OFFSET_OUT_OF_RANGE, &module.name, relocation.line_num, &msg);
+                            continue;
+                        }
+                        words[index] |= offset as u16 & ((1 << width) - 1);
+                    },
+                }
+            }
+
+            image.extend(words);
+        }
+
+        if !self.errors.is_empty() {
+            return vec![];
+        }
+        return image;
+    }
+
+    /* Every relocation has to be inside the module, with an offset width an instruction can have. */
+    fn validate(&mut self, module: &ObjectModule) {
+        let end = module.origin as usize + module.words.len();
+        if end > 0x10000 {
+            let msg = format!("the module is assembled at x{:04X}, but its {} words run past the end of memory.", module.origin, module.words.len());
+            self.push_error(CODE_INVALID_MODULE, &module.name, 0, &msg);
+            return;
+        }
+
+        for relocation in module.relocations.iter() {
+            if !(module.origin as usize..end).contains(&(relocation.address as usize)) {
+                let msg = format!("a relocation is at x{:04X}, which is outside the module (x{:04X} to x{:04X}).", relocation.address, module.origin, end - 1);
+                self.push_error(CODE_INVALID_MODULE, &module.name, relocation.line_num, &msg);
+            }
+            if let RelocationKind::PcOffset(width) = relocation.kind
+                && !(1..=16).contains(&width) {
+                let msg = format!("a relocation has a {}-bit PC offset, but offsets are 1 to 16 bits.", width);
+                self.push_error(CODE_INVALID_MODULE, &module.name, relocation.line_num, &msg);
+            }
+        }
+    }
+
+    fn push_error(&mut self, code: &str, module: &str, line_num: usize, msg: &str) {
+        let mut error = AsmError::new(String::from(code), "", line_num as i32, ErrorType::LabelError, msg);
+        error.set_file(module);
+        self.errors.push(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::{RunOutcome, VM};
+
+    fn assemble(name: &str, file: &str) -> ObjectModule {
+        let mut asm = Asm::new();
+        let module = asm.run_object(name, file.to_string());
+        assert!(asm.errors.is_empty(), "{:?}", asm.errors.iter().map(|error| error.generate_msg()).collect::<Vec<_>>());
+        return module.unwrap();
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = assemble("main.asm", ".ORIG x3000
+        .EXTERN PRINT
+        .EXTERN count
+        LEA R0, msg
+        JSR PRINT
+        LD R1, count_ptr
+        LDR R1, R1, #0
+        HALT
+count_ptr .FILL count
+msg     .STRINGZ \"hi\"
+        .END");
+        let print = assemble("print.asm", ".ORIG x3000
+        .GLOBAL PRINT
+        .GLOBAL count
+PRINT   ST R7, saved
+        PUTS
+        LD R7, saved
+        LD R2, self
+        RET
+saved   .FILL #0
+self    .FILL PRINT
+count   .FILL #7
+        .END");
+
+        assert_eq!(main.externs(), vec!["PRINT", "count"]);
+        assert_eq!(ObjectModule::from_json(&print.to_json()), Ok(print.clone()));
+
+        let mut linker = Linker::new();
+        let image = linker.link(&[main, print], None);
+        assert!(linker.errors.is_empty());
+
+        // print.asm comes right after the 9 words of main.asm
+        assert_eq!(linker.placements, vec![0x3000, 0x3009]);
+        assert_eq!(linker.symbols.get("PRINT"), Some(&0x3009));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.run(image), RunOutcome::Halted);
+        assert_eq!(vm.get_registers().r[1], 7);
+        assert_eq!(vm.get_registers().r[2], 0x3009);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let uses = assemble("uses.asm", ".ORIG x3000\n.EXTERN far\n.EXTERN missing\nLD R0, far\nJSR missing\n.END");
+        let defines = assemble("defines.asm", ".ORIG x3000\n.GLOBAL far\n.BLKW #300\nfar .FILL #1\n.END");
+
+        let mut linker = Linker::new();
+        assert!(linker.link(&[uses.clone(), defines.clone(), defines], None).is_empty());
+
+        let codes: Vec<&str> = linker.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["LK001", "LK003", "LK002"]);
+        assert!(linker.errors[1].generate_msg().contains("In `uses.asm`, on line 4"));
+
+        // A broken object file is an error, not a panic
+        let mut broken = uses.clone();
+        broken.relocations[0].address = 0x4000;
+        broken.relocations[1].kind = RelocationKind::PcOffset(0);
+        assert!(linker.link(&[broken], None).is_empty());
+        let codes: Vec<&str> = linker.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["LK006", "LK006"]);
+
+        let far = ObjectModule::from_json(r#"{"name":"far.asm","origin":65535,"words":[1,2],"globals":{},"relocations":[]}"#).unwrap();
+        assert!(linker.link(&[far], None).is_empty());
+        assert_eq!(linker.errors[0].code, "LK006");
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use super::asm_error::{AsmError, ErrorType};
+use super::include::Included;
+
+const CODE_MACRO_NOT_ENDED: &'static str = "MC000";
+const CODE_ENDM_WITHOUT_MACRO: &'static str = "MC001";
+const CODE_INVALID_MACRO_DEFINITION: &'static str = "MC002";
+const CODE_WRONG_ARGUMENT_COUNT: &'static str = "MC003";
+const CODE_MACRO_TOO_DEEP: &'static str = "MC004";
+const CODE_EXPANSION_TOO_LARGE: &'static str = "MC005";
+const CODE_RESERVED_CHARACTER: &'static str = "MC006";
+// Every directive, since a local label like `.loop` looks like one
+const DIRECTIVES: [&str; 18] = [
+    ".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".STRINGP", ".BREAK", ".ASSERT", ".GLOBAL", ".EXTERN", ".END",
+    ".MACRO", ".ENDM", ".IF", ".ELSE", ".ENDIF", ".EQU", ".SET", ".INCLUDE",
+];
+
+// A macro that invokes itself would otherwise never stop expanding
+const MAX_DEPTH: usize = 32;
+// Macros that each invoke the next one twice grow exponentially without invoking
+// themselves. More lines than fit in memory can never assemble anyway.
+const MAX_EXPANDED_LINES: usize = 1 << 16;
+
+/*
+`.MACRO` definitions, expanded before the rest of the assembler sees the
+file:
+
+    .MACRO PUSH reg
+        ADD R6, R6, #-1
+        STR reg, R6, #0
+    .ENDM
+
+            PUSH R1         ; becomes the two lines above, with R1 for reg
+
+Parameters are replaced wherever they appear as a whole word, except in
+comments, strings and character literals. Labels defined inside a body
+are local to each expansion, so a macro with a loop can be used more than
+once: `loop` becomes `loop$1`, `loop$2` and so on. Source code cannot use
+`$`, so these never clash with other labels. A body can invoke other macros, and a macro can be invoked before
+it is defined. A label in front of an invocation labels the first line of
+the expansion.
+
+Every line of the expanded file remembers where it came from, so errors
+point at the invocation and at the line of the definition.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    // The line of the `.MACRO` directive
+    pub line_num: usize,
+    body: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineOrigin {
+    // The file the line is in, see `include`
+    pub file: usize,
+    // The line in the original file. For expanded lines, this is the invocation.
+    pub line_num: usize,
+    // The macro and the line of its body this line was expanded from
+    pub macro_line: Option<(String, usize)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Expansion {
+    pub text: String,
+    // `lines[i]` is where line `i + 1` of `text` came from
+    pub lines: Vec<LineOrigin>,
+}
+
+#[allow(dead_code)]
+pub struct MacroExpander {
+    pub macros: HashMap<String, Macro>,
+    pub errors: Vec<AsmError>,
+    expansion_count: usize,
+    // Lines that came out of macro bodies so far
+    expanded_lines: usize,
+    // The macros being expanded right now, outermost first
+    expanding: Vec<String>,
+    // Set by the first MC004 or MC005, which stops the whole expansion
+    aborted: bool,
+}
+
+#[allow(dead_code)]
+impl Expansion {
+    pub fn get_origin(&self, line_num: usize) -> Option<&LineOrigin> {
+        if line_num == 0 {
+            return None;
+        }
+        return self.lines.get(line_num - 1);
+    }
+
+    /* The line in the original file that `line_num` of the expanded file came from. */
+    pub fn original_line_num(&self, line_num: usize) -> usize {
+        return self.get_origin(line_num).map(|origin| origin.line_num).unwrap_or(line_num);
+    }
+
+    /* The file that `line_num` of the expanded file came from. */
+    pub fn original_file(&self, line_num: usize) -> usize {
+        return self.get_origin(line_num).map(|origin| origin.file).unwrap_or(0);
+    }
+
+    /*
+    Points every line at the file it was included from, when the expanded
+    file was itself made by pasting `included` files together.
+    */
+    pub fn locate_files(&mut self, included: &Included) {
+        for origin in self.lines.iter_mut() {
+            (origin.file, origin.line_num) = included.locate(origin.line_num);
+            if let Some((_, macro_line_num)) = origin.macro_line.as_mut() {
+                *macro_line_num = included.locate(*macro_line_num).1;
+            }
+        }
+    }
+
+    pub fn has_macros(&self) -> bool {
+        return self.lines.iter().any(|origin| origin.macro_line.is_some());
+    }
+}
+
+#[allow(dead_code)]
+impl MacroExpander {
+    pub fn new() -> MacroExpander {
+        MacroExpander {
+            macros: HashMap::new(),
+            errors: vec![],
+            expansion_count: 0,
+            expanded_lines: 0,
+            expanding: vec![],
+            aborted: false,
+        }
+    }
+
+    /*
+    Expands every macro in `file`. `is_instruction` tells instruction names
+    apart from labels, so that labels in a body can be made local.
+    */
+    pub fn run(&mut self, file: &str, is_instruction: &dyn Fn(&str) -> bool) -> Expansion {
+        self.macros = HashMap::new();
+        self.errors = vec![];
+        self.expansion_count = 0;
+        self.expanded_lines = 0;
+        self.expanding = vec![];
+        self.aborted = false;
+
+        let lines = self.collect_definitions(file, is_instruction);
+        let mut expansion = Expansion::default();
+
+        for (i, line) in lines.iter().enumerate() {
+            if self.aborted {
+                break;
+            }
+            let origin = LineOrigin { file: 0, line_num: i + 1, macro_line: None };
+            self.expand_line(line, &origin, 0, is_instruction, &mut expansion);
+        }
+        // Every line was given a newline, but the last one did not have one
+        expansion.text.pop();
+
+        return expansion;
+    }
+
+    /*
+    Takes the definitions out of the file. Their lines are left empty, so the
+    other lines keep their numbers.
+    */
+    fn collect_definitions(&mut self, file: &str, is_instruction: &dyn Fn(&str) -> bool) -> Vec<String> {
+        let mut lines: Vec<String> = vec![];
+        let mut current: Option<Macro> = None;
+
+        for (i, line) in file.split('\n').enumerate() {
+            let line_num = i + 1;
+            let words = split_words(line);
+            let directive = words.first().map(|word| word.to_ascii_uppercase());
+
+            if has_code_character(line, '$') {
+                self.push_error(CODE_RESERVED_CHARACTER, line, line_num, "`$` is only for the labels made local to a macro expansion, like `loop$1`, so it cannot be used in code.");
+            }
+
+            match (directive.as_deref(), &mut current) {
+                (Some(".MACRO"), Some(_)) => {
+                    self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, "a macro cannot be defined inside another macro. HINT: Did you forget an `.ENDM`?");
+                },
+                (Some(".MACRO"), None) => {
+                    current = self.parse_definition(&words[1..], line, line_num, is_instruction);
+                    if current.is_none() {
+                        // Skip the body, so it is not reported again as loose lines
+                        current = Some(Macro { name: String::new(), params: vec![], line_num, body: vec![] });
+                    }
+                },
+                (Some(".ENDM"), Some(_)) => {
+                    let definition = current.take().unwrap();
+                    if !definition.name.is_empty() {
+                        self.macros.insert(definition.name.clone(), definition);
+                    }
+                },
+                (Some(".ENDM"), None) => {
+                    self.push_error(CODE_ENDM_WITHOUT_MACRO, line, line_num, "`.ENDM` ends a macro, but no macro was started with `.MACRO`.");
+                },
+                (_, Some(definition)) => {
+                    definition.body.push((line_num, line.to_string()));
+                },
+                (_, None) => {
+                    lines.push(line.to_string());
+                    continue;
+                },
+            }
+            lines.push(String::new());
+        }
+
+        if let Some(definition) = current {
+            let line = file.split('\n').nth(definition.line_num - 1).unwrap_or("");
+            self.push_error(CODE_MACRO_NOT_ENDED, line, definition.line_num, "this macro is never ended. To resolve this error, add `.ENDM` after the last line of its body.");
+        }
+
+        return lines;
+    }
+
+    fn parse_definition(&mut self, words: &[String], line: &str, line_num: usize, is_instruction: &dyn Fn(&str) -> bool) -> Option<Macro> {
+        let Some(name) = words.first() else {
+            self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, "`.MACRO` needs a name, like `.MACRO PUSH reg`.");
+            return None;
+        };
+        let name = name.to_ascii_uppercase();
+        let params: Vec<String> = words[1..].to_vec();
+
+        if !is_identifier(&name) {
+            self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, &format!("`{}` is not a valid macro name.", name));
+            return None;
+        }
+        if is_instruction(&name) || name.starts_with('.') {
+            self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, &format!("`{}` is already an instruction, so it cannot be a macro.", name));
+            return None;
+        }
+        if let Some(other) = self.macros.get(&name) {
+            let msg = format!("the macro `{}` was already defined on line {}.", name, other.line_num);
+            self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, &msg);
+            return None;
+        }
+        for (i, param) in params.iter().enumerate() {
+            if !is_identifier(param) || params[..i].contains(param) {
+                self.push_error(CODE_INVALID_MACRO_DEFINITION, line, line_num, &format!("`{}` is not a valid parameter name, or is used twice.", param));
+                return None;
+            }
+        }
+
+        return Some(Macro { name, params, line_num, body: vec![] });
+    }
+
+    fn expand_line(&mut self, line: &str, origin: &LineOrigin, depth: usize, is_instruction: &dyn Fn(&str) -> bool, expansion: &mut Expansion) {
+        let words = split_words(line);
+        let upper: Vec<String> = words.iter().take(2).map(|word| word.to_ascii_uppercase()).collect();
+
+        // The invocation is either the first word, or the second after a label
+        let (label, name) = match upper.as_slice() {
+            [first, ..] if self.macros.contains_key(first) => (None, first.clone()),
+            [_, second] if self.macros.contains_key(second) => (Some(words[0].clone()), second.clone()),
+            _ if depth > 0 && self.expanded_lines >= MAX_EXPANDED_LINES => {
+                if !self.aborted {
+                    let msg = format!("macros expanded into more than {} lines, which could never fit in memory.", MAX_EXPANDED_LINES);
+                    self.push_error(CODE_EXPANSION_TOO_LARGE, line, origin.line_num, &msg);
+                    self.aborted = true;
+                }
+                return;
+            },
+            _ => {
+                if depth > 0 {
+                    self.expanded_lines += 1;
+                }
+                expansion.text.push_str(line);
+                expansion.text.push('\n');
+                expansion.lines.push(origin.clone());
+                return;
+            },
+        };
+
+        if self.aborted {
+            return;
+        }
+        if self.expanding.contains(&name) {
+            let msg = format!("the macro `{}` invokes itself (through {}), so it would never stop expanding.", name, self.expanding.join(" -> "));
+            self.push_error(CODE_MACRO_TOO_DEEP, line, origin.line_num, &msg);
+            self.aborted = true;
+            return;
+        }
+        if depth >= MAX_DEPTH {
+            let msg = format!("macros were expanded more than {} levels deep. HINT: Does `{}` invoke itself?", MAX_DEPTH, name);
+            self.push_error(CODE_MACRO_TOO_DEEP, line, origin.line_num, &msg);
+            self.aborted = true;
+            return;
+        }
+
+        let definition = self.macros.get(&name).unwrap().clone();
+        let args = get_arguments(line, label.as_deref(), &name);
+
+        if args.len() != definition.params.len() {
+            let msg = format!(
+                "the macro `{}` (defined on line {}) takes {} argument(s), but was given {}.",
+                name, definition.line_num, definition.params.len(), args.len(),
+            );
+            self.push_error(CODE_WRONG_ARGUMENT_COUNT, line, origin.line_num, &msg);
+            return;
+        }
+
+        self.expansion_count += 1;
+        let mut replacements: HashMap<String, String> = definition.params.iter().cloned().zip(args).collect();
+        for (_, body_line) in definition.body.iter() {
+            // A local or anonymous label already belongs to the label before it
+            if let Some(local) = get_defined_label(body_line, is_instruction, &self.macros)
+                && is_identifier(&local) && !replacements.contains_key(&local) {
+                let unique = format!("{}${}", local, self.expansion_count);
+                replacements.insert(local, unique);
+            }
+        }
+
+        self.expanding.push(name.clone());
+        let mut label = label;
+        for (body_line_num, body_line) in definition.body.iter() {
+            let mut expanded = substitute(body_line, &replacements);
+
+            if let Some(label) = label.take() {
+                if get_defined_label(&expanded, is_instruction, &self.macros).is_some() || expanded.trim().is_empty() {
+                    self.expand_line(&label, origin, depth + 1, is_instruction, expansion);
+                } else {
+                    expanded = format!("{} {}", label, expanded.trim_start());
+                }
+            }
+
+            let body_origin = LineOrigin {
+                file: origin.file,
+                line_num: origin.line_num,
+                macro_line: Some((definition.name.clone(), *body_line_num)),
+            };
+            self.expand_line(&expanded, &body_origin, depth + 1, is_instruction, expansion);
+        }
+
+        // An empty body still has to keep the label
+        if let Some(label) = label {
+            self.expand_line(&label, origin, depth + 1, is_instruction, expansion);
+        }
+        self.expanding.pop();
+    }
+
+    fn push_error(&mut self, code: &str, line: &str, line_num: usize, msg: &str) {
+        self.errors.push(AsmError::new(
+            String::from(code),
+            line,
+            line_num as i32,
+            ErrorType::SyntaxError,
+            msg,
+        ));
+    }
+}
+
+/* The words of a line before any comment, split on whitespace and commas. */
+pub fn split_words(line: &str) -> Vec<String> {
+    let code = line.split(';').next().unwrap_or("");
+    return code
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_string())
+        .collect();
+}
+
+pub fn is_identifier(word: &str) -> bool {
+    return word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+}
+
+/* The comma separated arguments after the label (if any) and the macro name. */
+fn get_arguments(line: &str, label: Option<&str>, name: &str) -> Vec<String> {
+    let mut rest = line.split(';').next().unwrap_or("").trim_start();
+    if let Some(label) = label {
+        rest = rest[label.len()..].trim_start();
+    }
+    let rest = rest[name.len()..].trim();
+
+    if rest.is_empty() {
+        return vec![];
+    }
+    return rest.split(',').map(|arg| arg.trim().to_string()).collect();
+}
+
+/*
+The label a line defines, if its first word is not an instruction, directive or macro.
+This includes local labels like `.loop` and anonymous labels like `:`.
+*/
+fn get_defined_label(line: &str, is_instruction: &dyn Fn(&str) -> bool, macros: &HashMap<String, Macro>) -> Option<String> {
+    let first = split_words(line).into_iter().next()?;
+    let upper = first.to_ascii_uppercase();
+    let name = first.strip_prefix(['.', '@']).unwrap_or(&first);
+
+    if first == ":" {
+        return Some(first);
+    }
+    if !is_identifier(name) || DIRECTIVES.contains(&upper.as_str()) || is_instruction(&upper) || macros.contains_key(&upper) {
+        return None;
+    }
+    return Some(first);
+}
+
+/* Whether `c` appears outside the comment, strings and character literals of a line. */
+fn has_code_character(line: &str, c: char) -> bool {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            ';' => return false,
+            '"' => i = chars[i + 1..].iter().position(|c| *c == '"').map(|j| i + j + 2).unwrap_or(chars.len()),
+            '\'' if chars.get(i + 2) == Some(&'\'') => i += 3,
+            other if other == c => return true,
+            _ => i += 1,
+        }
+    }
+    return false;
+}
+
+/* Replaces whole words, leaving comments, strings and character literals alone. */
+pub fn substitute(line: &str, replacements: &HashMap<String, String>) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == ';' {
+            output.extend(&chars[i..]);
+            break;
+        }
+        if c == '"' {
+            let end = chars[i + 1..].iter().position(|c| *c == '"').map(|j| i + j + 2).unwrap_or(chars.len());
+            output.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+        if c == '\'' && chars.get(i + 2) == Some(&'\'') {
+            output.extend(&chars[i..i + 3]);
+            i += 3;
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            // A label already made local to an expansion, like `loop$1`, is one word
+            if chars.get(i) == Some(&'$') {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let word: String = chars[start..i].iter().collect();
+            // The name of a directive or a local label, like `.loop`, is never replaced
+            if start > 0 && (chars[start - 1] == '.' || chars[start - 1] == '@') {
+                output.push_str(&word);
+                continue;
+            }
+            output.push_str(replacements.get(&word).unwrap_or(&word));
+            continue;
+        }
+
+        output.push(c);
+        i += 1;
+    }
+
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_instruction(word: &str) -> bool {
+        return ["ADD", "STR", "LDR", "BRN", "HALT", "AND"].contains(&word);
+    }
+
+    fn expand(file: &str) -> (Expansion, Vec<AsmError>) {
+        let mut expander = MacroExpander::new();
+        let expansion = expander.run(file, &is_instruction);
+        return (expansion, expander.errors);
+    }
+
+    #[test]
+    fn test_substitution() {
+        let (expansion, errors) = expand(".MACRO PUSH reg
+    ADD R6, R6, #-1
+    STR reg, R6, #0 ; reg goes on the stack
+.ENDM
+start PUSH R1
+      push R2");
+
+        assert!(errors.is_empty());
+        let lines: Vec<&str> = expansion.text.lines().collect();
+        assert_eq!(lines[4], "start ADD R6, R6, #-1");
+        assert_eq!(lines[5], "    STR R1, R6, #0 ; reg goes on the stack");
+        assert_eq!(lines[7], "    STR R2, R6, #0 ; reg goes on the stack");
+        assert_eq!(expansion.lines[5], LineOrigin { file: 0, line_num: 5, macro_line: Some(("PUSH".to_string(), 3)) });
+        assert_eq!(expansion.lines[7].line_num, 6);
+        assert_eq!(expansion.original_line_num(1), 1);
+    }
+
+    #[test]
+    fn test_local_labels_and_nesting() {
+        let (expansion, errors) = expand(".MACRO COUNT reg, n
+    AND reg, reg, #0
+loop ADD reg, reg, #1
+    UNTIL reg, n, loop
+.ENDM
+.MACRO UNTIL reg, n, target
+    ADD reg, reg, #-n
+    BRn target
+.ENDM
+COUNT R1, 5
+COUNT R2, 5");
+
+        assert!(errors.is_empty());
+        let lines: Vec<&str> = expansion.text.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines[1], "loop$1 ADD R1, R1, #1");
+        assert_eq!(lines[2], "    ADD R1, R1, #-5");
+        assert_eq!(lines[3], "    BRn loop$1");
+        assert_eq!(lines[5], "loop$3 ADD R2, R2, #1");
+
+        // A label in front of the invocation gets its own line before a local label
+        let (expansion, errors) = expand(".MACRO ABS reg\n.done ADD reg, reg, #0\n.ENDM\n.MACRO ZERO\n.FILL x0\n.ENDM\nMAIN ABS R1\nDATA ZERO");
+        assert!(errors.is_empty());
+        let lines: Vec<&str> = expansion.text.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines, vec!["MAIN", ".done ADD R1, R1, #0", "DATA .FILL x0"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_, errors) = expand(".MACRO PUSH reg\nADD reg, reg, #1\n.ENDM\nPUSH R1, R2");
+        assert_eq!(errors[0].code, "MC003");
+
+        let (_, errors) = expand(".MACRO FOREVER\nFOREVER\n.ENDM\nFOREVER");
+        assert_eq!(errors[0].code, "MC004");
+
+        // Two invocations of itself would double at every level
+        let (_, errors) = expand(".MACRO F\nF\nF\n.ENDM\nF\nF");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "MC004");
+
+        let (_, errors) = expand(".MACRO A\nB\n.ENDM\n.MACRO B\nA\n.ENDM\nA");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].generate_msg().contains("A -> B"));
+
+        // Every macro invokes the next one twice, so the last one is expanded 2^20 times
+        let mut file = String::from("M0");
+        for i in 0..20 {
+            file += &format!("\n.MACRO M{}\nM{}\nM{}\n.ENDM", i, i + 1, i + 1);
+        }
+        file += "\n.MACRO M20\nHALT\n.ENDM";
+        let (_, errors) = expand(&file);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "MC005");
+
+        let (_, errors) = expand(".MACRO ADD\n.ENDM");
+        assert_eq!(errors[0].code, "MC002");
+
+        let (_, errors) = expand(".MACRO PUSH reg\nADD reg, reg, #1");
+        assert_eq!(errors[0].code, "MC000");
+
+        let (_, errors) = expand("HALT\n.ENDM");
+        assert_eq!(errors[0].code, "MC001");
+
+        // `$` is kept for local labels, but not in strings or comments
+        let (_, errors) = expand("loop$1 HALT\n.STRINGZ \"$5\" ; $\n.FILL '$'");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, "MC006");
+    }
+}
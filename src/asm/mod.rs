@@ -7,3 +7,13 @@ pub mod syntax;
 pub mod lexer;
 pub mod semantic;
 pub mod file;
+pub mod source_map;
+pub mod debug_info;
+pub mod expr;
+pub mod macros;
+pub mod conditional;
+pub mod include;
+pub mod pseudo;
+pub mod object;
+pub mod linker;
+pub mod relax;
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/*
+A relocatable object module, made by `Asm::run_object` from one file of a
+larger program and put together with others by the `Linker`:
+
+    ; print.asm                         ; main.asm
+    .ORIG x3000                         .ORIG x3000
+    .GLOBAL PRINT                       .EXTERN PRINT
+    PRINT   PUTS                                LEA R0, msg
+            RET                                 JSR PRINT
+    .END                                        HALT
+                                        msg     .STRINGZ "hi"
+                                        .END
+
+A module is assembled at its `.ORIG` like any other file, and remembers
+which words have to change when the linker puts it somewhere else:
+
+    Absolute    a whole word that is an address, from `.FILL label` or
+                `LDIMM Rd, label`. A local address moves with the module,
+                an `.EXTERN` address is filled in.
+    PcOffset    the low bits of an instruction that uses an `.EXTERN`
+                label, like `JSR PRINT`. Local PC offsets never change,
+                since a module is always moved as a whole.
+
+The words of an `.EXTERN` relocation are left 0 by the assembler.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RelocationKind {
+    Absolute,
+    // The width of the offset field
+    PcOffset(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relocation {
+    // The address of the word to change, as assembled
+    pub address: u16,
+    pub kind: RelocationKind,
+    // The `.EXTERN` label, or `None` for an address inside the module
+    pub symbol: Option<String>,
+    // The line it came from, for errors from the linker
+    pub line_num: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectModule {
+    pub name: String,
+    // The address the module was assembled at
+    pub origin: u16,
+    pub words: Vec<u16>,
+    // Every `.GLOBAL` label and its address, as assembled
+    pub globals: BTreeMap<String, u16>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[allow(dead_code)]
+impl ObjectModule {
+    /* The object file format, which is JSON. */
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("Expected that an object module could always be serialized");
+    }
+
+    pub fn from_json(text: &str) -> Result<ObjectModule, String> {
+        return serde_json::from_str(text).map_err(|err| format!("not a valid object module: {}", err));
+    }
+
+    /* Every `.EXTERN` label the module uses. */
+    pub fn externs(&self) -> Vec<&str> {
+        let mut externs: Vec<&str> = self.relocations.iter().filter_map(|relocation| relocation.symbol.as_deref()).collect();
+        externs.sort();
+        externs.dedup();
+        return externs;
+    }
+}
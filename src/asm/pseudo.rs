@@ -0,0 +1,142 @@
+use super::asm_ins::OpcodeIns;
+
+/*
+Pseudo-instructions, which the assembler turns into short sequences of
+real LC-3 instructions. They are off unless `Asm::enable_pseudo_ops` is
+called, since older programs may use these names as labels.
+
+    MOV   Rd, Rs            ADD Rd, Rs, #0
+    CLR   Rd                AND Rd, Rd, #0
+    INC   Rd                ADD Rd, Rd, #1
+    DEC   Rd                ADD Rd, Rd, #-1
+    NEG   Rd                NOT Rd, Rd        ADD Rd, Rd, #1
+    SUB   Rd, Rs1, Rs2      Rd <- Rs1 - Rs2, in three instructions
+    PUSH  Rs                ADD R6, R6, #-1   STR Rs, R6, #0
+    POP   Rd                LDR Rd, R6, #0    ADD R6, R6, #1
+    LDIMM Rd, value         LD Rd, #1         BRnzp #1          .FILL value
+
+Only Rd (and R6, for PUSH and POP) is changed. The condition codes are
+set from Rd, except after PUSH and POP, where they are set from R6.
+*/
+pub fn expand(instruction: &OpcodeIns, operands: &[u16]) -> Vec<u16> {
+    match instruction {
+        OpcodeIns::Mov => return vec![add_imm(operands[0], operands[1], 0)],
+        OpcodeIns::Clr => return vec![and_imm(operands[0], operands[0], 0)],
+        OpcodeIns::Inc => return vec![add_imm(operands[0], operands[0], 1)],
+        OpcodeIns::Dec => return vec![add_imm(operands[0], operands[0], -1)],
+        OpcodeIns::Neg => return vec![not(operands[0], operands[0]), add_imm(operands[0], operands[0], 1)],
+        OpcodeIns::Sub => return expand_sub(operands[0], operands[1], operands[2]),
+        OpcodeIns::Push => return vec![add_imm(6, 6, -1), 0b0111_000_110_000000 | (operands[0] << 9)],
+        OpcodeIns::Pop => return vec![0b0110_000_110_000000 | (operands[0] << 9), add_imm(6, 6, 1)],
+        OpcodeIns::Ldimm => return vec![0b0010_000_000000001 | (operands[0] << 9), 0b0000_111_000000001, operands[1]],
+        _ => unreachable!("{:?} is not a pseudo-instruction", instruction),
+    }
+}
+
+/*
+Rs1 - Rs2 is NOT(NOT(Rs1) + Rs2), or NOT(Rs2) + 1 + Rs1. Which one is used
+depends on which register Rd overwrites first.
+*/
+fn expand_sub(dr: u16, sr1: u16, sr2: u16) -> Vec<u16> {
+    if dr == sr1 && dr == sr2 {
+        return vec![and_imm(dr, dr, 0), add_imm(dr, dr, 0), add_imm(dr, dr, 0)];
+    }
+    if dr == sr2 {
+        return vec![not(dr, sr2), add_reg(dr, dr, sr1), add_imm(dr, dr, 1)];
+    }
+    return vec![not(dr, sr1), add_reg(dr, dr, sr2), not(dr, dr)];
+}
+
+fn add_reg(dr: u16, sr1: u16, sr2: u16) -> u16 {
+    return 0b0001_000_000_000_000 | (dr << 9) | (sr1 << 6) | sr2;
+}
+
+fn add_imm(dr: u16, sr: u16, imm5: i16) -> u16 {
+    return 0b0001_000_000_1_00000 | (dr << 9) | (sr << 6) | (imm5 as u16 & 0x1F);
+}
+
+fn and_imm(dr: u16, sr: u16, imm5: i16) -> u16 {
+    return 0b0101_000_000_1_00000 | (dr << 9) | (sr << 6) | (imm5 as u16 & 0x1F);
+}
+
+fn not(dr: u16, sr: u16) -> u16 {
+    return 0b1001_000_000_111111 | (dr << 9) | (sr << 6);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::{RunOutcome, VM};
+
+    #[test]
+    fn test_sizes_match() {
+        let pseudo_ops = [
+            OpcodeIns::Mov, OpcodeIns::Clr, OpcodeIns::Inc, OpcodeIns::Dec, OpcodeIns::Neg,
+            OpcodeIns::Sub, OpcodeIns::Push, OpcodeIns::Pop, OpcodeIns::Ldimm,
+        ];
+
+        for instruction in pseudo_ops {
+            assert!(instruction.is_pseudo());
+            assert_eq!(expand(&instruction, &[1, 2, 3]).len(), instruction.get_word_count() as usize, "{:?}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_pseudo_ops_run() {
+        let mut asm = Asm::new();
+        asm.enable_pseudo_ops().unwrap();
+        let binary_file = asm.run(String::from(".ORIG x3000
+        LD R6, stack
+        LDIMM R0, x8001
+        LDIMM R1, #100
+        MOV R2, R1
+        INC R2
+        DEC R1
+        DEC R1
+        SUB R3, R2, R1
+        SUB R2, R2, R1
+        SUB R1, R0, R1
+        SUB R4, R4, R4
+        PUSH R3
+        CLR R3
+        NEG R0
+        POP R5
+        LDIMM R7, stack
+        HALT
+stack   .FILL x4000
+        .END"));
+        assert!(asm.errors.is_empty());
+
+        let mut vm = VM::new();
+        assert_eq!(vm.run(binary_file), RunOutcome::Halted);
+        let r = vm.get_registers().r;
+
+        assert_eq!(r[0], 0x7FFF);
+        assert_eq!(r[1], 0x8001_u16.wrapping_sub(98));
+        assert_eq!(r[2], 3);
+        assert_eq!(r[3], 0);
+        assert_eq!(r[4], 0);
+        assert_eq!(r[5], 3);
+        assert_eq!(r[6], 0x4000);
+        assert_eq!(r[7], asm.get_symbol_address("stack").unwrap());
+
+        // The value of LDIMM is data, not an instruction
+        assert!(asm.source_map.is_instruction(0x3001));
+        assert!(!asm.source_map.is_instruction(0x3003));
+        assert_eq!(asm.source_map.get_line_num(0x3003), Some(3));
+    }
+
+    #[test]
+    fn test_pseudo_ops_are_optional() {
+        // Without pseudo-instructions, `inc` is just a label
+        let mut asm = Asm::new();
+        asm.run(String::from(".ORIG x3000\ninc HALT\n.END"));
+        assert!(asm.errors.is_empty());
+
+        let mut asm = Asm::new();
+        asm.enable_pseudo_ops().unwrap();
+        asm.run(String::from(".ORIG x3000\nLDIMM R0, #35000*2\n.END"));
+        assert_eq!(asm.errors[0].code, "SM015");
+    }
+}
@@ -0,0 +1,157 @@
+use super::asm_ins::OpcodeIns;
+use super::pseudo;
+
+/*
+Long-branch relaxation, which `Asm::enable_relaxation` turns on. A BR, LD
+or LEA whose label is further away than its 9-bit PC offset can reach is
+rewritten into a sequence that reaches the whole memory, through a
+pointer literal right next to it:
+
+    BRz  far            BRnp #3             ; not taken, skip the jump
+                        LD   Rs, #1
+                        JMP  Rs
+                        .FILL far
+
+    LD   R0, far        LD   R0, #1
+                        BRnzp #1
+                        .FILL far
+                        LDR  R0, R0, #0
+
+    LEA  R0, far        LD   R0, #1
+                        BRnzp #1
+                        .FILL far
+
+An unconditional branch has nothing to skip, so its first word is a BR
+that does not move, which is the same in every simulator whether it
+treats a BR without `nzp` as never taken or as always taken.
+
+A long branch needs a scratch register `Rs`, which it overwrites. A long
+LEA sets the condition codes, which a plain LEA does not.
+
+Rewriting an instruction makes the program longer, which can push other
+labels out of range, so the layout is redone until nothing changes.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relaxation {
+    // The address of the first word of the sequence
+    pub address: u16,
+    pub line_num: usize,
+    // The instruction as it was written, like `BRz far`
+    pub text: String,
+    pub words: Vec<u16>,
+}
+
+#[allow(dead_code)]
+impl Relaxation {
+    pub fn describe(&self) -> String {
+        return format!(
+            "x{:04X} (line {}): `{}` was out of range, so it became {} instructions",
+            self.address, self.line_num, self.text, self.words.len(),
+        );
+    }
+}
+
+pub fn is_relaxable(instruction: &OpcodeIns) -> bool {
+    return matches!(instruction, OpcodeIns::Br(_, _, _) | OpcodeIns::Ld | OpcodeIns::Lea);
+}
+
+/* How many words the long form of `instruction` takes. */
+pub fn get_word_count(instruction: &OpcodeIns) -> i32 {
+    match instruction {
+        OpcodeIns::Br(_, _, _) | OpcodeIns::Ld => return 4,
+        OpcodeIns::Lea => return 3,
+        _ => unreachable!("{:?} cannot be relaxed", instruction),
+    }
+}
+
+/* Which word of the long form of `instruction` is the pointer literal. */
+pub fn get_pointer_index(instruction: &OpcodeIns) -> usize {
+    match instruction {
+        OpcodeIns::Br(_, _, _) => return 3,
+        _ => return 2,
+    }
+}
+
+/*
+The long form of `instruction`. `register` is the destination of LD and
+LEA, or the scratch register of BR.
+*/
+pub fn expand(instruction: &OpcodeIns, register: u16, target: u16) -> Vec<u16> {
+    match instruction {
+        OpcodeIns::Br(n, z, p) => {
+            let skip = if n == z && z == p {
+                0b0000_000_000000000
+            } else {
+                0b0000_000_000000011 | (!n as u16) << 11 | (!z as u16) << 10 | (!p as u16) << 9
+            };
+            let load = 0b0010_000_000000001 | (register << 9);
+            let jump = 0b1100_000_000_000000 | (register << 6);
+            return vec![skip, load, jump, target];
+        },
+        OpcodeIns::Ld => {
+            let mut words = pseudo::expand(&OpcodeIns::Ldimm, &[register, target]);
+            words.push(0b0110_000_000_000000 | (register << 9) | (register << 6));
+            return words;
+        },
+        OpcodeIns::Lea => return pseudo::expand(&OpcodeIns::Ldimm, &[register, target]),
+        _ => unreachable!("{:?} cannot be relaxed", instruction),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::{RunOutcome, VM};
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand(&OpcodeIns::Br(false, true, false), 5, 0x5000), vec![0b0000_101_000000011, 0x2A01, 0xC140, 0x5000]);
+        assert_eq!(expand(&OpcodeIns::Br(true, true, true), 5, 0x5000)[0], 0x0000);
+        assert_eq!(expand(&OpcodeIns::Ld, 0, 0x5000), vec![0x2001, 0x0E01, 0x5000, 0x6000]);
+        for instruction in [OpcodeIns::Br(true, true, true), OpcodeIns::Ld, OpcodeIns::Lea] {
+            assert_eq!(expand(&instruction, 1, 0).len(), get_word_count(&instruction) as usize);
+        }
+    }
+
+    fn far_program() -> String {
+        return String::from(".ORIG x3000
+        ADD R3, R3, #0
+        BRnzp start
+back    HALT
+start   LD R1, value
+        LEA R2, value
+        ADD R3, R3, #1
+        .BLKW #250
+        BRnzp back
+        .BLKW #10
+value   .FILL #42
+        .END");
+    }
+
+    #[test]
+    fn test_relaxation() {
+        let mut asm = Asm::new();
+        asm.run(far_program());
+        let codes: Vec<&str> = asm.errors.iter().map(|error| error.code.as_str()).collect();
+        assert_eq!(codes, vec!["SM026", "SM026"]);
+
+        let mut asm = Asm::new();
+        asm.enable_relaxation(6).unwrap();
+        let binary_file = asm.run(far_program());
+        assert!(asm.errors.is_empty());
+
+        // `back` is only out of range once the two rewrites before it push it away
+        let lines: Vec<usize> = asm.relaxations.iter().map(|relaxation| relaxation.line_num).collect();
+        assert_eq!(lines, vec![5, 6, 9]);
+        assert!(asm.relaxations[0].describe().starts_with("x3003 (line 5): `LD R1, value` was out of range"));
+        assert!(!asm.source_map.is_instruction(0x3005));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.run(binary_file), RunOutcome::Halted);
+        let r = vm.get_registers().r;
+        assert_eq!(r[1], 42);
+        assert_eq!(r[2], asm.get_symbol_address("value").unwrap());
+        assert_eq!(r[3], 1);
+    }
+}
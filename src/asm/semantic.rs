@@ -1,7 +1,12 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use super::{asm_error::{AsmError, ErrorType}, asm_ins::{OpcodeIns, OperandType}, directive::Directive, token};
 use super::token::*;
 use super::file::AsmFile;
+use super::debug_info::Assertion;
+use super::expr::Expr;
+use super::relax;
+use crate::vm::extension::Extension;
+use crate::vm::arch::Architecture;
 
 const ARCH_LIMIT: i32 = 16;
 
@@ -24,6 +29,46 @@ const CODE_NUMBER_OUT_OF_BOUNDS: &'static str = "SM015";
 const CODE_ORIG_NOT_GIVEN_NUMBER: &'static str = "SM016";
 const CODE_FILE_NOT_VALID: &'static str = "SM017";
 const CODE_FILE_EMPTY: &'static str = "SM018";
+const CODE_INVALID_ASSERTION: &'static str = "SM019";
+const CODE_ORIG_NOT_ALIGNED: &'static str = "SM020";
+const CODE_EXPECTED_NOTHING_RECEIVED_EXPRESSION: &'static str = "SM021";
+const CODE_RECEIVED_UNEXPECTED_EXPRESSION: &'static str = "SM022";
+const CODE_INVALID_EXPRESSION: &'static str = "SM023";
+const CODE_EXTERN_NOT_LINKED: &'static str = "SM024";
+const CODE_EXTERN_DEFINED: &'static str = "SM025";
+const CODE_LABEL_OUT_OF_RANGE: &'static str = "SM026";
+const CODE_EXTERN_AND_GLOBAL: &'static str = "SM027";
+const CODE_TARGET_NOT_ALIGNED: &'static str = "SM028";
+
+/*
+An expression operand that has to wait until every label is defined
+before it can be evaluated and bound checked.
+*/
+struct PendingExpression {
+    token: Token,
+    expr: Expr,
+    // The instruction or directive it belongs to, for errors
+    instruction: String,
+    width: i32,
+    unsigned: bool,
+    // The PC for a PC-relative operand, which makes the value an offset
+    pc: Option<i32>,
+}
+
+/*
+A label operand of an instruction, which is PC-relative. It is checked
+once every label is defined, since the label may be further down.
+*/
+struct PendingOffset {
+    token: Token,
+    instruction: String,
+    // The token index of the instruction
+    index: usize,
+    // Whether the instruction can be rewritten when the label is too far away
+    relaxable: bool,
+    pc: i32,
+    width: i32,
+}
 
 #[allow(dead_code)]
 pub struct SemanticChecker {
@@ -33,10 +78,31 @@ pub struct SemanticChecker {
     used_labels: HashMap<String, Token>,
     memory_location: i32,
     in_blkw_directive: bool,
+    in_stringp_directive: bool,
+    in_assert_directive: bool,
+    pending_expressions: Vec<PendingExpression>,
+    extension: Option<Extension>,
+    architecture: Architecture,
+    // Whether the file is an object module, which may use `.EXTERN` labels
+    relocatable: bool,
+    // `.EXTERN` labels, which are defined by another module
+    pub externs: HashMap<String, Token>,
+    // `.GLOBAL` labels, which other modules may use
+    pub globals: Vec<String>,
+    // `.EQU` and `.SET` constants and their lines, which are in the symbol table but take up no memory
+    pub constants: HashMap<String, (i32, usize)>,
+    pending_offsets: Vec<PendingOffset>,
+    // Whether out-of-range BR, LD and LEA instructions may be rewritten, see `relax`
+    relaxation: bool,
+    // The instructions, by token index, that are laid out in their long form
+    pub relaxed: HashSet<usize>,
+    // The instructions that were found out of range by the last `run`, and have to be relaxed
+    pub to_relax: Vec<usize>,
 
     // refactor items
     expected_operands: VecDeque<OperandType>,
     curr_ins_token: Token,
+    curr_ins_index: usize,
     end_encountered: bool,
 }
 
@@ -50,8 +116,22 @@ impl SemanticChecker {
             used_labels: HashMap::new(),
             memory_location: 0,
             in_blkw_directive: false,
+            in_stringp_directive: false,
+            in_assert_directive: false,
+            pending_expressions: vec![],
+            extension: None,
+            architecture: Architecture::Lc3,
+            relocatable: false,
+            externs: HashMap::new(),
+            globals: vec![],
+            constants: HashMap::new(),
+            pending_offsets: vec![],
+            relaxation: false,
+            relaxed: HashSet::new(),
+            to_relax: vec![],
             expected_operands: VecDeque::new(),
             curr_ins_token: Token::get_useless_token(),
+            curr_ins_index: 0,
             end_encountered: false,
         }
     }
@@ -59,6 +139,12 @@ impl SemanticChecker {
     #[allow(unused_variables)]
     pub fn run(&mut self, tokens: &Vec<Token>, file: String) {
         self.original_file = AsmFile::new(file);
+        self.reset();
+        for (name, (value, line_num)) in self.constants.iter() {
+            let mut token = Token::get_useless_token();
+            token.line_num = *line_num;
+            self.symbol_table.insert(name.clone(), (*value, token));
+        }
         
         if self.tokens_is_empty(tokens) {
             return;
@@ -66,9 +152,10 @@ impl SemanticChecker {
         
         self.handle_orig(tokens);
 
-        for token in tokens {
+        for (index, token) in tokens.iter().enumerate() {
             match &token.inner_token {
                 TokenType::Instruction(instruction) => {
+                    self.curr_ins_index = index;
                     self.handle_instruction(token, instruction);
                 }
                 TokenType::Directive(directive) => {
@@ -86,13 +173,19 @@ impl SemanticChecker {
                 TokenType::String(string) => {
                     self.handle_string(token, string);
                 }
+                TokenType::Expression(text) => {
+                    self.handle_expression(token, text);
+                }
                 _ => {
                     // AsmError::new()
                 }
             }
         }
 
+        self.verify_pending_expressions();
+        self.verify_label_offsets();
         self.verify_all_used_labels_defined();
+        self.verify_externs();
 
         if !self.end_encountered {
             self.errors.push(AsmError::new(
@@ -126,11 +219,84 @@ impl SemanticChecker {
             ));
         }                   
         self.curr_ins_token = token.clone(); // These should be optimized out. In errors they are acceptable, but we should not take a performance hit to valid code.
-        self.memory_location += 1;
+        if self.relaxed.contains(&self.curr_ins_index) {
+            self.advance(relax::get_word_count(instruction));
+        } else {
+            self.advance(instruction.get_word_count());
+        }
+
+        self.expected_operands = match (instruction, &self.extension) {
+            (OpcodeIns::Reserved, Some(extension)) => extension.get_expected_operands(),
+            _ => instruction.get_expected_operands(),
+        };
+    }
+
+    pub fn set_extension(&mut self, extension: Extension) {
+        self.extension = Some(extension);
+    }
 
-        self.expected_operands = instruction.get_expected_operands();
+    pub fn set_architecture(&mut self, architecture: Architecture) {
+        self.architecture = architecture;
+    }
+
+    /* Lets the file use `.EXTERN` labels, for `Asm::run_object`. */
+    pub fn set_relocatable(&mut self, relocatable: bool) {
+        self.relocatable = relocatable;
+    }
+
+    /*
+    Lets out-of-range BR, LD and LEA instructions be put in `to_relax`
+    instead of being errors, for `Asm::enable_relaxation`.
+    */
+    pub fn set_relaxation(&mut self, relaxation: bool) {
+        self.relaxation = relaxation;
+    }
+
+    /*
+    Forgets everything from the last `run`, so a file can be checked again
+    with a different layout.
+    */
+    fn reset(&mut self) {
+        self.symbol_table = HashMap::new();
+        self.errors = vec![];
+        self.used_labels = HashMap::new();
+        self.memory_location = 0;
+        self.in_blkw_directive = false;
+        self.in_stringp_directive = false;
+        self.in_assert_directive = false;
+        self.pending_expressions = vec![];
+        self.pending_offsets = vec![];
+        self.to_relax = vec![];
+        self.externs = HashMap::new();
+        self.globals = vec![];
+        self.expected_operands = VecDeque::new();
+        self.curr_ins_token = Token::get_useless_token();
+        self.end_encountered = false;
+    }
+
+    /* Moves past `words` words of memory, which are two bytes each on the LC-3b. */
+    fn advance(&mut self, words: i32) {
+        self.memory_location += words * self.architecture.word_size() as i32;
     }
     
+    /* Moves past the words a `.BLKW` reserves, unless there is a negative number of them. */
+    fn reserve_words(&mut self, token: &Token, count: i32) {
+        self.in_blkw_directive = false;
+
+        if count < 0 {
+            let msg = format!("`{}` reserves {} words, but the count cannot be negative.", self.curr_ins_token.original_match, count);
+            self.errors.push(AsmError::from(
+                String::from(CODE_NUMBER_OUT_OF_BOUNDS),
+                &self.original_file.get_line(token.line_num),
+                token.clone(),
+                ErrorType::OperandError,
+                &msg,
+            ));
+            return;
+        }
+        self.advance(count);
+    }
+
     pub fn handle_directive(&mut self, token: &Token, directive: &Directive) {
         if self.expected_operands.len() > 0 {
             // Syntactically, it is not possible to get a directive as an argument, so an instruction must have terminated early
@@ -172,8 +338,22 @@ impl SemanticChecker {
         let expected = self.expected_operands.pop_front().unwrap();
 
         match expected {
-            OperandType::Label => { /* ... */ 
-                self.used_labels.insert(token.original_match.clone(), token.clone());
+            OperandType::Label | OperandType::ImmOrLabel => { /* ... */ 
+                match self.curr_ins_token.inner_token {
+                    TokenType::Directive(Directive::EXTERN) => {
+                        self.externs.insert(label.clone(), token.clone());
+                    },
+                    TokenType::Directive(Directive::GLOBAL) => {
+                        self.globals.push(label.clone());
+                        self.used_labels.insert(label.clone(), token.clone());
+                    },
+                    _ => {
+                        self.used_labels.insert(label.clone(), token.clone());
+                        if matches!(expected, OperandType::Label) {
+                            self.push_pending_offset(token);
+                        }
+                    },
+                }
             },
             _ => {
                 self.errors.push(AsmError::from(
@@ -202,12 +382,11 @@ impl SemanticChecker {
         let expected: OperandType = self.expected_operands.pop_front().unwrap();
 
         match expected {
-            OperandType::Imm | OperandType::RegOrImm => {
+            OperandType::Imm | OperandType::RegOrImm | OperandType::ImmOrLabel => {
                 self.verify_immediate_value_in_range(token);
 
                 if self.in_blkw_directive {
-                    self.in_blkw_directive = false;
-                    self.memory_location += *number as i32;
+                    self.reserve_words(token, *number as i32);
                 }
             },
             _ => {
@@ -265,7 +444,15 @@ impl SemanticChecker {
 
         match expected {
             OperandType::String => {
-                self.memory_location += string.len() as i32;
+                if self.in_assert_directive {
+                    self.in_assert_directive = false;
+                    self.verify_assertion(token, string);
+                } else if self.in_stringp_directive {
+                    self.in_stringp_directive = false;
+                    self.advance(Directive::pack_string(string).len() as i32);
+                } else {
+                    self.advance(string.len() as i32);
+                }
             },
             _ => {
                 self.errors.push(AsmError::from(
@@ -279,6 +466,178 @@ impl SemanticChecker {
         }
     }
 
+    pub fn handle_expression(&mut self, token: &Token, text: &str) {
+        if self.expected_operands.len() == 0 {
+            self.errors.push(AsmError::from(
+                String::from(CODE_EXPECTED_NOTHING_RECEIVED_EXPRESSION),
+                &self.original_file.get_line(token.line_num),
+                token.clone(),
+                ErrorType::OperandError,
+                "no operands were expected, but received an expression instead.",
+            ));
+            return;
+        }
+
+        let expected = self.expected_operands.pop_front().unwrap();
+
+        if !matches!(expected, OperandType::Imm | OperandType::RegOrImm | OperandType::ImmOrLabel | OperandType::Label) {
+            self.errors.push(AsmError::from(
+                String::from(CODE_RECEIVED_UNEXPECTED_EXPRESSION),
+                &self.original_file.get_line(token.line_num),
+                token.clone(),
+                ErrorType::OperandError,
+                &format!("{} was expected, but received an expression instead.", expected.as_string()),
+            ));
+            return;
+        }
+
+        let expr = match Expr::parse(text) {
+            Ok(expr) => expr,
+            Err(msg) => {
+                self.push_invalid_expression(token, &msg);
+                return;
+            },
+        };
+        for label in expr.labels() {
+            self.used_labels.insert(label.to_string(), token.clone());
+        }
+
+        let (width, unsigned) = self.get_immediate_width();
+        let instruction = self.curr_ins_token.original_match.clone();
+
+        match &self.curr_ins_token.inner_token {
+            TokenType::Directive(directive @ (Directive::ORIG | Directive::BLKW)) => {
+                // Everything after these depends on their value, so they cannot wait for the
+                // whole symbol table. Only the labels above them can be used.
+                let blkw = *directive == Directive::BLKW;
+                let result = expr.evaluate(&|label| self.symbol_table.get(label).map(|(address, _)| *address));
+
+                match result {
+                    Ok(value) if blkw => self.reserve_words(token, value),
+                    Ok(value) => self.verify_number_in_range(token, value, &instruction, width, unsigned),
+                    Err(msg) => {
+                        let msg = format!("{}. `{}` can only use labels that are defined above it", msg, instruction);
+                        self.push_invalid_expression(token, &msg);
+                    },
+                }
+            },
+            _ => {
+                let pc = match expected {
+                    OperandType::Label => Some(self.memory_location),
+                    _ => None,
+                };
+                self.pending_expressions.push(PendingExpression {
+                    token: token.clone(),
+                    expr,
+                    instruction,
+                    width,
+                    unsigned,
+                    pc,
+                });
+            },
+        }
+    }
+
+    fn verify_pending_expressions(&mut self) {
+        for pending in std::mem::take(&mut self.pending_expressions) {
+            let result = pending.expr.evaluate(&|label| self.symbol_table.get(label).map(|(address, _)| *address));
+
+            match result {
+                Ok(value) => {
+                    let word_size = self.architecture.word_size() as i32;
+                    let value = match pending.pc {
+                        // The offset counts words, so a target between two words cannot be reached
+                        Some(pc) if (value - pc) % word_size != 0 => {
+                            self.errors.push(AsmError::from(
+                                String::from(CODE_TARGET_NOT_ALIGNED),
+                                &self.original_file.get_line(pending.token.line_num),
+                                pending.token.clone(),
+                                ErrorType::BoundError,
+                                &format!("the LC-3b can only go to an even address, but `{}` is x{:04X}, which is odd.", pending.token.original_match, value),
+                            ));
+                            continue;
+                        },
+                        Some(pc) => (value - pc) / word_size,
+                        None => value,
+                    };
+                    self.verify_number_in_range(&pending.token, value, &pending.instruction, pending.width, pending.unsigned);
+                },
+                Err(msg) => {
+                    if let Some(label) = pending.expr.labels().into_iter().find(|label| self.externs.contains_key(*label)) {
+                        let msg = format!("`{}` is an `.EXTERN` label, so its address is not known until linking. It can only be used on its own, not in an expression.", label);
+                        self.push_invalid_expression(&pending.token, &msg);
+                    // An undefined label is already reported by `verify_all_used_labels_defined`
+                    } else if pending.expr.labels().iter().all(|label| self.symbol_table.contains_key(*label)) {
+                        self.push_invalid_expression(&pending.token, &msg);
+                    }
+                },
+            }
+        }
+    }
+
+    fn push_pending_offset(&mut self, token: &Token) {
+        let TokenType::Instruction(instruction) = &self.curr_ins_token.inner_token else {
+            return;
+        };
+        // The long form reaches every address
+        if self.relaxed.contains(&self.curr_ins_index) {
+            return;
+        }
+
+        let (width, _) = self.get_immediate_width();
+        self.pending_offsets.push(PendingOffset {
+            token: token.clone(),
+            instruction: self.curr_ins_token.original_match.clone(),
+            index: self.curr_ins_index,
+            relaxable: self.relaxation && relax::is_relaxable(instruction),
+            pc: self.memory_location,
+            width,
+        });
+    }
+
+    fn verify_label_offsets(&mut self) {
+        for pending in std::mem::take(&mut self.pending_offsets) {
+            let TokenType::Label(label) = &pending.token.inner_token else {
+                continue;
+            };
+            // An undefined label is already reported by `verify_all_used_labels_defined`
+            let Some((address, _)) = self.symbol_table.get(label) else {
+                continue;
+            };
+
+            let offset = (address - pending.pc) / self.architecture.word_size() as i32;
+            let (lower, upper) = self.get_twos_complement_range(pending.width);
+            if offset >= lower && offset <= upper {
+                continue;
+            }
+
+            if pending.relaxable {
+                self.to_relax.push(pending.index);
+                continue;
+            }
+            self.errors.push(AsmError::from(
+                String::from(CODE_LABEL_OUT_OF_RANGE),
+                &self.original_file.get_line(pending.token.line_num),
+                pending.token.clone(),
+                ErrorType::BoundError,
+                &format!(
+                    "`{}` is {} words away, but `{}` can only reach `[{}, {}]`. HINT: Load the address with `.FILL` and use `LDR` or `JMP`, or turn on relaxation.",
+                    pending.token.original_match, offset, pending.instruction, lower, upper,
+                ),
+            ));
+        }
+    }
+
+    fn push_invalid_expression(&mut self, token: &Token, msg: &str) {
+        self.errors.push(AsmError::from(
+            String::from(CODE_INVALID_EXPRESSION),
+            &self.original_file.get_line(token.line_num),
+            token.clone(),
+            ErrorType::OperandError,
+            msg,
+        ));
+    }
+
     pub fn tokens_is_empty(&mut self, tokens: &Vec<Token>) -> bool {
         if tokens.len() == 0 {
             self.errors.push(AsmError::new(
@@ -332,9 +691,26 @@ impl SemanticChecker {
     }
 
     pub fn set_memory_orig(&mut self, tokens: &Vec<Token>) {
-        match tokens[1].inner_token {
-            TokenType::Number(location) => {
-                self.memory_location = location as i32;
+        let origin = match &tokens[1].inner_token {
+            TokenType::Number(location) => Some(*location as i32),
+            // Errors in the expression are reported when the loop gets to it
+            TokenType::Expression(text) => Expr::parse(text).and_then(|expr| expr.evaluate(&|_| None)).ok(),
+            _ => None,
+        };
+
+        match origin {
+            Some(location) => {
+                self.memory_location = location;
+
+                if self.architecture.is_byte_addressable() && location % 2 != 0 {
+                    self.errors.push(AsmError::from(
+                        String::from(CODE_ORIG_NOT_ALIGNED),
+                        &self.original_file.get_line(tokens[1].line_num),
+                        tokens[1].clone(),
+                        ErrorType::BoundError,
+                        &format!("the LC-3b can only start a program on an even address, but `{}` is odd.", tokens[1].original_match),
+                    ));
+                }
             },
             _ => {
                 AsmError::from(
@@ -359,7 +735,7 @@ impl SemanticChecker {
     pub fn move_memory_location_directive(&mut self, directive: &Directive) {
         match directive {
             Directive::FILL => {
-                self.memory_location += 1;
+                self.advance(1);
             },
             Directive::BLKW => {
                 // Unfortunately, the number token will have to handle this, since we cannot have clairvoyance.
@@ -368,22 +744,61 @@ impl SemanticChecker {
             Directive::STRINGZ => {
                 // Strings can handle themselves, since this is the only syntactically valid position
                 // for a string in LC-3 assembly
-            }
+            },
+            Directive::STRINGP => {
+                // Same as .STRINGZ, but the string needs to know it will be packed
+                self.in_stringp_directive = true;
+            },
+            Directive::ASSERT => {
+                // The "string" is an expression, and takes up no memory
+                self.in_assert_directive = true;
+            },
             _ => {
 
             }
         }
     }
 
+    pub fn verify_assertion(&mut self, token: &Token, expression: &str) {
+        match Assertion::parse_expression(expression) {
+            Ok((lhs, _, rhs)) => {
+                for label in lhs.labels().into_iter().chain(rhs.labels()) {
+                    self.used_labels.insert(label.to_string(), token.clone());
+                }
+            },
+            Err(msg) => {
+                self.errors.push(AsmError::from(
+                    String::from(CODE_INVALID_ASSERTION),
+                    &self.original_file.get_line(token.line_num),
+                    token.clone(),
+                    ErrorType::OperandError,
+                    &msg,
+                ));
+            },
+        }
+    }
+
     pub fn define_label(&mut self, label: String, token: Token) {
         if self.symbol_table.contains_key(&label) {
             let (_, other) = self.symbol_table.get(&label).unwrap();
+            // Local labels only clash inside their scope, see `Lexer::resolve_label`
+            let msg = match get_label_scope(&label) {
+                _ if self.constants.contains_key(&label) => match other.line_num {
+                    0 => format!("attempted to define a label named like the constant `{}`, which was defined outside the file", label),
+                    line_num => format!("attempted to define a label named like the constant `{}`, which was defined on line {}", label, line_num),
+                },
+                Some(scope) if !scope.is_empty() => format!(
+                    "attempted to redefine the local label `{}`, which was already defined after `{}` on line {}",
+                    token.original_match, scope, other.line_num,
+                ),
+                _ => format!("attempted to redefine a label that was already defined on line {}", other.line_num),
+            };
             self.errors.push(AsmError::from(
                 String::from(CODE_REDEFINED_LABEL),
                 &self.original_file.get_line(token.line_num),
                 token.clone(),
                 ErrorType::LabelError,
-                &format!("attempted to redefine a label that was already defined on line {}", other.line_num),
+                &msg,
             ));
             return;
         }
@@ -391,70 +806,131 @@ impl SemanticChecker {
     }
 
     fn verify_all_used_labels_defined(&mut self) {
-        println!("USED LABELS = {:?}", self.used_labels);
-        println!("\n\nDEFINED LABELS = {:?}", self.symbol_table);
-        for label in self.used_labels.keys() {
-            if !self.symbol_table.contains_key(label) {
+        for (label, token) in self.used_labels.iter() {
+            if !self.symbol_table.contains_key(label) && !self.externs.contains_key(label) {
+                let msg = match get_label_scope(label) {
+                    _ if label.starts_with(':') => format!(
+                        "`{}` refers to an anonymous label, but there is no `:` label that far {} this line.",
+                        token.original_match, if token.original_match.contains('+') { "after" } else { "before" },
+                    ),
+                    Some(scope) if !scope.is_empty() => format!("the local label `{}` was never defined after `{}`.", token.original_match, scope),
+                    _ => format!("the label `{}` was never defined within the file.", label),
+                };
                 self.errors.push(AsmError::from(
                     String::from(CODE_USED_UNDEFINED_LABEL),
-                    &self.original_file.get_line(self.used_labels.get(label).unwrap().line_num),
-                    self.used_labels.get(label).unwrap().clone(),
+                    &self.original_file.get_line(token.line_num),
+                    token.clone(),
                     ErrorType::LabelError,
-                    &format!("the label `{}` was never defined within the file.", label),
+                    &msg,
                 ))
             }
         }
     }
     
+    fn verify_externs(&mut self) {
+        for (label, token) in self.externs.iter() {
+            let (code, msg) = if !self.relocatable {
+                (CODE_EXTERN_NOT_LINKED, format!("`{}` is an `.EXTERN` label, which only an object module can use. Assemble this file as an object module and link it.", label))
+            } else if self.globals.contains(label) {
+                (CODE_EXTERN_AND_GLOBAL, format!("`{}` is both `.EXTERN` and `.GLOBAL`. A module can only give other modules the labels it defines.", label))
+            } else if let Some((_, other)) = self.symbol_table.get(label) {
+                (CODE_EXTERN_DEFINED, format!("`{}` is an `.EXTERN` label, but it is also defined on line {}.", label, other.line_num))
+            } else {
+                continue;
+            };
+
+            self.errors.push(AsmError::from(
+                String::from(code),
+                &self.original_file.get_line(token.line_num),
+                token.clone(),
+                ErrorType::LabelError,
+                &msg,
+            ));
+        }
+    }
+
     fn verify_immediate_value_in_range(&mut self, value: &Token) {
-        let width: i32;
+        match &value.inner_token {
+            TokenType::Number(number) => {
+                let (width, unsigned) = self.get_immediate_width();
+                let instruction = self.curr_ins_token.original_match.clone();
+                self.verify_number_in_range(value, *number as i32, &instruction, width, unsigned);
+            },
+            TokenType::Label(_label) => {
 
+            },
+            _ => {
+                unreachable!();
+            }
+        }
+    }
+
+    /*
+    The width of the immediate value the current instruction or directive takes,
+    and whether it is unsigned.
+    */
+    fn get_immediate_width(&self) -> (i32, bool) {
         match &self.curr_ins_token.inner_token {
+            TokenType::Instruction(OpcodeIns::Reserved) => {
+                // The operand being checked was already taken off `expected_operands`
+                let extension = self.extension.as_ref().unwrap();
+                let index = extension.operands.len() - self.expected_operands.len() - 1;
+                return (extension.get_immediate_value_width(index).unwrap(), false);
+            },
             TokenType::Instruction(opcode_ins) => {
-                width = opcode_ins.get_immediate_value_width()
+                let width = opcode_ins.get_immediate_value_width()
                     .expect("Somehow we are trying to verify that a value is within range when the instruction does not take in a value. THIS SHOULD NOT BE POSSIBLE!");
+                return (width, opcode_ins.has_unsigned_immediate());
             },
             TokenType::Directive(_) => {
-                width = ARCH_LIMIT; // This is because directives only store information in memory. They don't have limits, other than architecture.
+                return (ARCH_LIMIT, false); // This is because directives only store information in memory. They don't have limits, other than architecture.
             }
             _ => {
                 panic!("semantic::SemanticChecker::verify_value_in_range(): A non-instruction/directive was given as a token that can take an immediate value");
             },
         }
-         
-        match &value.inner_token {
-            TokenType::Number(number) => {
-                let number = *number as i32;
-                let (lower, upper) = self.get_twos_complement_range(width);
-                
-                if number < lower || number > upper {
-                    self.errors.push(AsmError::from(
-                        String::from(CODE_NUMBER_OUT_OF_BOUNDS),
-                        &self.original_file.get_line(value.line_num),
-                        value.clone(),
-                        ErrorType::BoundError,
-                        &format!(
-                            "the number `{}` (or `{}`) is out of the bounds of `{}`, which takes a(n) {}-bit immediate value. Therefore, the accepted range is `[{}, {}]`
-        REMEMBER: The LC-3 takes only accepts 2's complement values as immediate values.",
-                            value.original_match,
-                            number,
-                            self.curr_ins_token.original_match,
-                            width,
-                            lower,
-                            upper,
-                        )
-                    ));
-                }
-            },
-            TokenType::Label(_label) => {
+    }
 
-            },
-            _ => {
-                unreachable!();
-            }
+    fn verify_number_in_range(&mut self, value: &Token, number: i32, instruction: &str, width: i32, unsigned: bool) {
+        let (lower, upper) = if unsigned {
+            self.get_unsigned_range(width)
+        } else if width == ARCH_LIMIT {
+            // A whole word, so both x8000 and #-32768 fit
+            (self.get_twos_complement_range(width).0, self.get_unsigned_range(width).1)
+        } else {
+            self.get_twos_complement_range(width)
+        };
+        let reminder = if unsigned {
+            "REMEMBER: Trap vectors and shift amounts are unsigned, so they cannot be negative."
+        } else {
+            "REMEMBER: The LC-3 takes only accepts 2's complement values as immediate values."
+        };
+
+        if number < lower || number > upper {
+            self.errors.push(AsmError::from(
+                String::from(CODE_NUMBER_OUT_OF_BOUNDS),
+                &self.original_file.get_line(value.line_num),
+                value.clone(),
+                ErrorType::BoundError,
+                &format!(
+                    "the number `{}` (or `{}`) is out of the bounds of `{}`, which takes a(n) {}-bit immediate value. Therefore, the accepted range is `[{}, {}]`
+        {}",
+                    value.original_match,
+                    number,
+                    instruction,
+                    width,
+                    lower,
+                    upper,
+                    reminder,
+                )
+            ));
         }
     }
 
+    fn get_unsigned_range(&self, width: i32) -> (i32, i32) {
+        return (0, 2_i32.pow(width as u32) - 1);
+    }
+
     fn get_twos_complement_range(&self, width: i32) -> (i32, i32) {
         let upper = 2_i32.pow(width as u32 - 1) - 1;
         let lower = -(2_i32.pow(width as u32 - 1));
@@ -462,6 +938,14 @@ impl SemanticChecker {
     }
 }
 
+/*
+The global label a local label like `MAIN.loop` belongs to, which is empty
+for a local label above every other label.
+*/
+fn get_label_scope(label: &str) -> Option<&str> {
+    return label.split_once('.').map(|(scope, _)| scope);
+}
+
 #[cfg(test)]
 mod tests {
     use crate::asm::{asm_error::*, lexer::*};
@@ -533,6 +1017,33 @@ name ret
         assert_eq!(errors[0].code, CODE_REDEFINED_LABEL);
     }
 
+    #[test]
+    fn test_local_label_scopes() {
+        let file = r#"
+.ORIG x3000
+MAIN ret
+.loop ret
+@loop ret
+SUB ret
+.loop ret
+BRz .done
+BRz :+
+.END
+        "#;
+
+        let errors: Vec<AsmError> = get_semantic_errors(file);
+
+        // Only the second `.loop` of MAIN is a redefinition
+        assert_eq!(errors[0].code, CODE_REDEFINED_LABEL);
+        assert!(errors[0].generate_msg().contains("already defined after `MAIN` on line 4"));
+        let undefined: Vec<String> = errors.iter()
+            .filter(|error| error.code == CODE_USED_UNDEFINED_LABEL)
+            .map(|error| error.generate_msg())
+            .collect();
+        assert_eq!(undefined.len(), 2);
+        assert!(undefined.iter().any(|msg| msg.contains("`.done` was never defined after `SUB`")));
+    }
+
     #[test]
     fn test_use_undefined_label() {
         let file = r#"
@@ -765,6 +1276,31 @@ ADD R0, R2, #16
         assert_eq!(errors[0].code, CODE_NUMBER_OUT_OF_BOUNDS)
     }
 
+    #[test]
+    fn test_trap_vector_out_of_bounds() {
+        let valid = get_semantic_errors(r#"
+.ORIG x3000
+TRAP x20
+TRAP xFF
+.END
+        "#);
+        assert_eq!(valid.len(), 0);
+
+        let errors: Vec<AsmError> = get_semantic_errors(r#"
+.ORIG x3000
+TRAP x100
+.END
+        "#);
+        assert_eq!(errors[0].code, CODE_NUMBER_OUT_OF_BOUNDS);
+
+        let errors: Vec<AsmError> = get_semantic_errors(r#"
+.ORIG x3000
+TRAP #-1
+.END
+        "#);
+        assert_eq!(errors[0].code, CODE_NUMBER_OUT_OF_BOUNDS);
+    }
+
     #[test]
     fn test_get_twos_complement_range() {
         let sm = SemanticChecker::new();
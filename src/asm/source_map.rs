@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+/*
+The source map links every word the assembler emits back to the line of
+the `.asm` file it came from. The line numbers start at 1, like
+`Token::line_num`. A word from a macro maps to the line that invoked it,
+and a word from an included file to its line in that file.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordKind {
+    Instruction,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    // The file number, like `Token::file`
+    pub file: usize,
+    pub line_num: usize,
+    pub kind: WordKind,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    entries: BTreeMap<u16, SourceLine>,
+}
+
+#[allow(dead_code)]
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, address: u16, file: usize, line_num: usize, kind: WordKind) {
+        self.entries.insert(address, SourceLine { file, line_num, kind });
+    }
+
+    pub fn get(&self, address: u16) -> Option<&SourceLine> {
+        return self.entries.get(&address);
+    }
+
+    pub fn get_line_num(&self, address: u16) -> Option<usize> {
+        return self.entries.get(&address).map(|line| line.line_num);
+    }
+
+    pub fn is_instruction(&self, address: u16) -> bool {
+        return matches!(self.get(address), Some(SourceLine { kind: WordKind::Instruction, .. }));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u16, &SourceLine)> {
+        return self.entries.iter();
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = (&u16, &SourceLine)> {
+        return self.entries.iter().filter(|(_, line)| line.kind == WordKind::Instruction);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+}
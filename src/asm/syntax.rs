@@ -1,6 +1,7 @@
 use regex::Regex;
 
 use super::asm_error::{AsmError, ErrorType};
+use crate::vm::arch::Architecture;
 
 const CODE_SYNTAX_ERROR: &'static str = "SX000";
 
@@ -8,12 +9,18 @@ const CODE_SYNTAX_ERROR: &'static str = "SX000";
 pub struct SyntaxChecker {
     instruction_line: Regex,
     directive_line: Regex,
+    assert_line: Regex,
     ignore_line: Regex,
     instruction_name: Regex,
+    pseudo_name: Regex,
+    pseudo_ops: bool,
     directive_name: Regex,
     register: Regex,
     label: Regex,
+    local_label: Regex,
+    anonymous_label: Regex,
     imm: Regex,
+    expression: Regex,
     string_whole: Regex,
     string_start: Regex,
     string_end: Regex,
@@ -22,7 +29,16 @@ pub struct SyntaxChecker {
 #[allow(dead_code)]
 impl SyntaxChecker {
     pub fn new() -> SyntaxChecker {
-        let label = r#"^[A-Za-z_][A-Za-z0-9_]*"#;
+        return SyntaxChecker::with_architecture(Architecture::Lc3);
+    }
+
+    pub fn with_architecture(architecture: Architecture) -> SyntaxChecker {
+        // `loop$1` is a label a macro made local to one expansion, see `macros`
+        let label = r#"^[A-Za-z_][A-Za-z0-9_]*(\$[0-9]+)?"#;
+        // `.loop` or `@loop`, see `Lexer::resolve_label`
+        let local_label = r#"^[.@][A-Za-z_][A-Za-z0-9_]*"#;
+        // `:` defines one, `:+` and `:-` use the next and the last one
+        let anonymous_label = r#"^:(\++|-+)?"#;
         let reg = r#"^(R|r)[0-7]$"#;
         let imm = r##"^(([#][-]?[0-9]+)|([x][0-9A-F]+))$"##;
         let ignore = r#"^(\s)*(;.*)?$"#;
@@ -31,27 +47,46 @@ impl SyntaxChecker {
         let string_end = Regex::new(r#".*["]$"#).unwrap();
 
         // let ins_line_regex: Regex = Regex::new(r#"([A-Za-z_][A-Za-z0-9_]*\s)?(\s)*[A-Za-z]+(\s)*(\s([A-Za-z_][A-Za-z0-9_]*|#[0-9]+|(R|r)[0-7]|PC)(,(\s)+([A-Za-z_][A-Za-z0-9_]*|#[0-9]+|(R|r)[0-7]|PC)(,(\s)+([A-Za-z_][A-Za-z0-9_]*|#[0-9]+|(R|r)[0-7]|PC))?)?)?(\s)*(;.*)?"#).unwrap();
-        let ins_line_regex: Regex = Regex::new(
-            r#"^\s*([A-Za-z_][A-Za-z0-9_]*\s)?\s*([A-Za-z]+)(\s+((((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*)|(((x|X)[0-9A-Fa-f]+)|#[0-9]+))(\s*,\s*((((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*)|(((x|X)[0-9A-Fa-f]+)|#[0-9]+)))(\s*,\s*((((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*)|(((x|X)[0-9A-Fa-f]+)|#[0-9]+))))?)?)?)?\s*(;.*)?$"#
-        ).unwrap();
-        let dir_line_regex: Regex = Regex::new(
-            r#"^\s*([A-Za-z_][A-Za-z0-9_]*\s)?\s*([.][A-Za-z]+)\s*(\s((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*)|(".*")|(((x|X)[0-9A-Fa-f]+)|#[0-9]+))?\s*(;.*)?$"#
-        ).unwrap();
-
-        let ins_name = Regex::new(
-            r#"^((BR[N]?[Z]?[P]?)|ADD|AND|JMP|JSR|JSRR|LD|LDI|LDR|LEA|NOT|RET|RTI|ST|STI|STR|GETC|OUT|PUTS|IN|HALT)$"#
-        ).unwrap();
-        let dir_name = Regex::new(r"[.](ORIG|FILL|BLKW|STRINGZ|END)$").unwrap();
+        // An expression, see `expr`. Whether it makes sense is up to the expression parser, but
+        // it needs an operator, a parenthesis or a character, so a bare `5` is still not an operand.
+        let expr_atom = r#"([.@]?[A-Za-z_][A-Za-z0-9_]*(\$[0-9]+)?|[xX][0-9A-Fa-f]+|#-?[0-9]+|[0-9]+|'[^\s;,']')"#;
+        let expr_tail = format!(r#"([-+*/][-+(]*{expr_atom}\)*)"#);
+        let expr = format!(r#"([-+(]*{expr_atom}\)*{expr_tail}+|[-+(]+{expr_atom}\)*{expr_tail}*|'[^\s;,']')"#);
+        let operand = format!(r#"(((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*(\$[0-9]+)?)|([.@][A-Za-z_][A-Za-z0-9_]*)|(:(\++|-+))|(((x|X)[0-9A-Fa-f]+)|#[-]?[0-9]+)|({expr}))"#);
+        let defined_label = r#"([.@]?[A-Za-z_][A-Za-z0-9_]*(\$[0-9]+)?|:)"#;
+
+        let ins_line_regex: Regex = Regex::new(&format!(
+            r#"^\s*({defined_label}\s)?\s*([A-Za-z]+)(\s+({operand}(\s*,\s*({operand})(\s*,\s*({operand}))?)?)?)?\s*(;.*)?$"#
+        )).unwrap();
+        let dir_line_regex: Regex = Regex::new(&format!(
+            r#"^\s*({defined_label}\s)?\s*([.][A-Za-z]+)\s*(\s((r|R)[0-7])|([A-Za-z_][A-Za-z0-9_]*(\$[0-9]+)?)|([.@][A-Za-z_][A-Za-z0-9_]*)|(:(\++|-+))|(".*")|(((x|X)[0-9A-Fa-f]+)|#[-]?[0-9]+)|({expr}))?\s*(;.*)?$"#
+        )).unwrap();
+
+        let ins_name = Regex::new(match architecture {
+            Architecture::Lc3 => r#"^((BR[N]?[Z]?[P]?)|ADD|AND|JMP|JSR|JSRR|LD|LDI|LDR|LEA|NOT|RET|RTI|ST|STI|STR|GETC|OUT|PUTS|IN|PUTSP|HALT|TRAP)$"#,
+            Architecture::Lc3b => r#"^((BR[N]?[Z]?[P]?)|ADD|AND|JMP|JSR|JSRR|LDB|LDW|LEA|NOT|RET|RTI|STB|STW|XOR|LSHF|RSHFL|RSHFA|GETC|OUT|PUTS|IN|PUTSP|HALT|TRAP)$"#,
+        }).unwrap();
+        let pseudo_name = Regex::new(r#"^(MOV|CLR|SUB|NEG|PUSH|POP|LDIMM|INC|DEC)$"#).unwrap();
+        // The expression is checked by the semantic checker, since it has its own little syntax
+        let assert_line_regex: Regex = Regex::new(&format!(r#"^\s*({defined_label}\s)?\s*[.](?i:assert)\s+[^;\s][^;]*(;.*)?$"#)).unwrap();
+
+        let dir_name = Regex::new(r"[.](ORIG|FILL|BLKW|STRINGZ|STRINGP|BREAK|ASSERT|GLOBAL|EXTERN|END)$").unwrap();
 
         SyntaxChecker {
             instruction_line: ins_line_regex,
             directive_line: dir_line_regex,
+            assert_line: assert_line_regex,
             ignore_line: Regex::new(ignore).unwrap(),
             instruction_name: ins_name,
+            pseudo_name: pseudo_name,
+            pseudo_ops: false,
             directive_name: dir_name,
             register: Regex::new(&format!("{reg}$")).unwrap(),
             label: Regex::new(&format!("{label}$")).unwrap(),
+            local_label: Regex::new(&format!("{local_label}$")).unwrap(),
+            anonymous_label: Regex::new(&format!("{anonymous_label}$")).unwrap(),
             imm: Regex::new(&format!("{imm}$")).unwrap(),
+            expression: Regex::new(&format!("^{expr}$")).unwrap(),
             string_whole: string_whole,
             string_start: string_start,
             string_end: string_end,
@@ -59,6 +94,11 @@ impl SyntaxChecker {
     }
 
     pub fn is_syntactically_valid_file(&self, file: &str) -> bool {
+        return self.get_syntax_errors(file).is_empty();
+    }
+
+    /* One error for every line that is not syntactically valid. */
+    pub fn get_syntax_errors(&self, file: &str) -> Vec<AsmError> {
         let split_file: Vec<&str> = file.split('\n').collect();
         let mut errors: Vec<AsmError> = vec![];
 
@@ -69,6 +109,9 @@ impl SyntaxChecker {
             if self.directive_line.is_match(line) {
                 continue;
             }
+            if self.assert_line.is_match(line) {
+                continue;
+            }
             if self.ignore_line.is_match(line) {
                 continue;
             }
@@ -82,13 +125,7 @@ impl SyntaxChecker {
             ))
         }
 
-        let file_is_valid = errors.len() == 0;
-
-        for err in errors.into_iter() {
-            println!("{}", err.generate_msg());
-        }
-
-        return file_is_valid;
+        return errors;
     }
 
     pub fn is_ins(&self, line: &str) -> bool {
@@ -103,8 +140,13 @@ impl SyntaxChecker {
         return self.ignore_line.is_match(line);
     }
 
+    /* Makes the pseudo-instructions instruction names, see `pseudo`. */
+    pub fn set_pseudo_ops(&mut self, enabled: bool) {
+        self.pseudo_ops = enabled;
+    }
+
     pub fn is_instruction_name(&self, word: &str) -> bool {
-        return self.instruction_name.is_match(word);
+        return self.instruction_name.is_match(word) || (self.pseudo_ops && self.pseudo_name.is_match(word));
     }
 
     pub fn is_directive_name(&self, word: &str) -> bool {
@@ -119,10 +161,22 @@ impl SyntaxChecker {
         return self.label.is_match(word);
     }
 
+    pub fn is_valid_local_label(&self, word: &str) -> bool {
+        return self.local_label.is_match(word);
+    }
+
+    pub fn is_valid_anonymous_label(&self, word: &str) -> bool {
+        return self.anonymous_label.is_match(word);
+    }
+
     pub fn is_valid_immediate_value(&self, word: &str) -> bool {
         return self.imm.is_match(word);
     }
 
+    pub fn is_valid_expression(&self, word: &str) -> bool {
+        return self.expression.is_match(word);
+    }
+
     pub fn is_valid_string_whole(&self, word: &str) -> bool {
         return self.string_whole.is_match(word);
     }
@@ -162,9 +216,13 @@ mod tests {
         assert!(s.is_ins(r"here RET"));
         assert!(s.is_ins(r"add r1,r1, #1"));
         assert!(s.is_ins(r"                NOT     R0, R0"));
+        assert!(s.is_ins(r".loop  add  r1, r1, #-1"));
+        assert!(s.is_ins(r":      brp  @loop"));
+        assert!(s.is_ins(r"       brz  :++"));
         assert!(s.is_ins(r"       hello    NOT     R0, R0 ; Whitespace must be allowed before labels"));
         assert!(s.is_ins(r"in"));
         assert!(s.is_ins(r"rin"));
+        assert!(s.is_ins(r"       add  r1, r1, #-1 "));
 
         assert!(!s.is_ins(r"12 add r1, #1, #1"));
         assert!(!s.is_ins(r"hi add r1, 1, #1"));
@@ -283,6 +341,41 @@ mod tests {
         assert!(!s.is_instruction_name(" "));
     }
 
+    #[test]
+    fn test_lc3b_instruction_name() {
+        let s = SyntaxChecker::with_architecture(Architecture::Lc3b);
+
+        assert!(s.is_instruction_name("LDB"));
+        assert!(s.is_instruction_name("STW"));
+        assert!(s.is_instruction_name("XOR"));
+        assert!(s.is_instruction_name("RSHFA"));
+        assert!(s.is_instruction_name("ADD"));
+        assert!(s.is_instruction_name("NOT"));
+
+        assert!(!s.is_instruction_name("LD"));
+        assert!(!s.is_instruction_name("LDR"));
+        assert!(!s.is_instruction_name("STI"));
+        assert!(!SyntaxChecker::new().is_instruction_name("LDB"));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let s = SyntaxChecker::new();
+
+        assert!(s.is_ins(r"       ld   r0, table+2"));
+        assert!(s.is_ins(r"       add  r1, r1, #10*4 ; comment"));
+        assert!(s.is_ins(r"       add  r1, r1, 'A'"));
+        assert!(s.is_dir(r"       .blkw (end-start)/2"));
+        assert!(s.is_dir(r"       .fill -x10"));
+
+        assert!(s.is_valid_expression("(1)"));
+        assert!(s.is_valid_expression("'A'+1"));
+        assert!(!s.is_valid_expression("5"));
+        assert!(!s.is_valid_expression("label"));
+        assert!(!s.is_valid_expression("1+"));
+        assert!(!s.is_ins(r"       add  r1, r1, #1 + 2"));
+    }
+
     #[test]
     fn test_register_regex() {
         let s = SyntaxChecker::new();
@@ -12,6 +12,8 @@ pub enum TokenType {
     Number(i16),
     String(String),
     Register(u16),
+    // An arithmetic expression, kept as text until the symbol table exists. See `expr`.
+    Expression(String),
     INVALID(String),
 }
 
@@ -24,6 +26,8 @@ pub struct Token {
     pub file_relative_to: usize,
     pub file_relative_from: usize,
     pub line_num: usize,
+    // The number of the file the token is in, see `include`. The main file is 0.
+    pub file: usize,
     pub original_match: String,
 }
 
@@ -48,6 +52,7 @@ impl Token {
             file_relative_to: file_relative_to - 1,
             file_relative_from: file_relative_from,
             line_num: line_num as usize,
+            file: 0,
             original_match: original_match,
         }
     }
@@ -60,6 +65,7 @@ impl Token {
             file_relative_from: 0,
             file_relative_to: 0,
             line_num: 0,
+            file: 0,
             original_match: "".to_string(),
         }
     }
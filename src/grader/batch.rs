@@ -0,0 +1,405 @@
+use super::runner::{CaseResult, SpecReport, SpecRunner};
+use super::spec::TestSpec;
+use crate::vm::coverage::{Coverage, CoverageReport};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubmissionResult {
+    pub name: String,
+    // Assembler errors, or why the submission could not be graded at all
+    pub diagnostics: Vec<String>,
+    pub report: SpecReport,
+    pub instructions: u64,
+    // (lines executed, lines with instructions), over every case combined
+    pub lines_executed: (usize, usize),
+}
+
+/*
+Grades a whole directory of submissions against one `TestSpec`.
+
+Every submission is assembled and run on its own `Asm` and `VM`s, so one
+submission can never see another's state, and a submission that crashes
+the assembler or the VM only fails itself. Submissions are spread over
+`jobs` threads.
+*/
+#[allow(dead_code)]
+pub struct BatchGrader {
+    spec: TestSpec,
+    pub jobs: usize,
+}
+
+#[allow(dead_code)]
+impl BatchGrader {
+    pub fn new(spec: TestSpec) -> BatchGrader {
+        BatchGrader {
+            spec,
+            jobs: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+
+    /* Grades every `.asm` file in `dir`, in file name order. */
+    pub fn grade_directory<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<SubmissionResult>, String> {
+        let dir = dir.as_ref();
+        let entries = fs::read_dir(dir)
+            .map_err(|err| format!("could not read {}: {}", dir.display(), err))?;
+
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("asm")))
+            .collect();
+        paths.sort();
+
+        let submissions: Vec<(String, Result<String, String>)> = paths.iter()
+            .map(|path| {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                let source = fs::read_to_string(path)
+                    .map_err(|err| format!("could not read {}: {}", path.display(), err));
+                (name, source)
+            })
+            .collect();
+
+        return Ok(self.grade_all(&submissions));
+    }
+
+    /* Grades `(name, source)` pairs in parallel. The results are in the same order. */
+    pub fn grade_all(&self, submissions: &[(String, Result<String, String>)]) -> Vec<SubmissionResult> {
+        let next = AtomicUsize::new(0);
+        let jobs = self.jobs.clamp(1, submissions.len().max(1));
+
+        let mut graded: Vec<(usize, SubmissionResult)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| scope.spawn(|| {
+                    let mut graded = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((name, source)) = submissions.get(i) else {
+                            return graded;
+                        };
+                        let result = match source {
+                            Ok(source) => self.grade_source(name, source),
+                            Err(err) => self.not_graded(name, vec![err.clone()]),
+                        };
+                        graded.push((i, result));
+                    }
+                }))
+                .collect();
+
+            return workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+        });
+
+        graded.sort_by_key(|(i, _)| *i);
+        return graded.into_iter().map(|(_, result)| result).collect();
+    }
+
+    pub fn grade_source(&self, name: &str, source: &str) -> SubmissionResult {
+        let graded = panic::catch_unwind(AssertUnwindSafe(|| {
+            let runner = match SpecRunner::assemble(source) {
+                Ok(runner) => runner,
+                Err(diagnostics) => return self.not_graded(name, diagnostics),
+            };
+
+            let mut report = runner.run(&self.spec);
+            report.source = name.to_string();
+            let coverage = report.coverage();
+
+            return SubmissionResult {
+                name: name.to_string(),
+                diagnostics: vec![],
+                instructions: report.total_instructions(),
+                lines_executed: CoverageReport::new(source, &runner.source_map, &coverage).lines_executed(),
+                report,
+            };
+        }));
+
+        match graded {
+            Ok(result) => return result,
+            Err(panic) => {
+                let reason = panic.downcast_ref::<String>().cloned()
+                    .or(panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                return self.not_graded(name, vec![format!("the grader crashed: {}", reason)]);
+            },
+        }
+    }
+
+    /* Every case fails with the first diagnostic. */
+    fn not_graded(&self, name: &str, diagnostics: Vec<String>) -> SubmissionResult {
+        let reason = if diagnostics.iter().any(|d| d.starts_with("could not read") || d.starts_with("the grader crashed")) {
+            diagnostics[0].clone()
+        } else {
+            "the source file did not assemble".to_string()
+        };
+
+        return SubmissionResult {
+            name: name.to_string(),
+            report: SpecReport {
+                source: name.to_string(),
+                cases: self.spec.cases.iter()
+                    .map(|case| CaseResult {
+                        name: case.name.clone(),
+                        failures: vec![reason.clone()],
+                        outcome: None,
+                        output: String::new(),
+                        instructions: 0,
+                        coverage: Coverage::new(),
+                    })
+                    .collect(),
+            },
+            diagnostics,
+            instructions: 0,
+            lines_executed: (0, 0),
+        };
+    }
+}
+
+#[allow(dead_code)]
+impl SubmissionResult {
+    pub fn graded(&self) -> bool {
+        return self.diagnostics.is_empty();
+    }
+}
+
+#[allow(dead_code)]
+/* A gradebook with one row per submission and one column per case. */
+pub fn to_csv(spec: &TestSpec, results: &[SubmissionResult]) -> String {
+    let mut header = vec![
+        "submission".to_string(),
+        "assembled".to_string(),
+        "passed".to_string(),
+        "total".to_string(),
+        "instructions".to_string(),
+        "lines_executed".to_string(),
+        "lines_total".to_string(),
+    ];
+    header.extend(spec.cases.iter().map(|case| case.name.clone()));
+    header.push("diagnostics".to_string());
+
+    let mut csv = csv_row(&header);
+
+    for result in results.iter() {
+        let mut row = vec![
+            result.name.clone(),
+            result.graded().to_string(),
+            result.report.passed().to_string(),
+            result.report.cases.len().to_string(),
+            result.instructions.to_string(),
+            result.lines_executed.0.to_string(),
+            result.lines_executed.1.to_string(),
+        ];
+        row.extend(result.report.cases.iter().map(|case| (if case.passed() { "PASS" } else { "FAIL" }).to_string()));
+        // Only the first line of each diagnostic, the rest is the source line and carets
+        row.push(result.diagnostics.iter()
+            .map(|d| d.lines().next().unwrap_or(""))
+            .collect::<Vec<&str>>()
+            .join("; "));
+
+        csv += &csv_row(&row);
+    }
+
+    return csv;
+}
+
+#[allow(dead_code)]
+/* A JUnit XML report with one test suite per submission, which CI systems can display. */
+pub fn to_junit(results: &[SubmissionResult]) -> String {
+    let tests: usize = results.iter().map(|result| result.report.cases.len()).sum();
+    let failures: usize = results.iter().map(|result| junit_counts(result).0).sum();
+    let errors: usize = results.iter().map(|result| junit_counts(result).1).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!("<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", tests, failures, errors);
+
+    for result in results.iter() {
+        let (failures, errors) = junit_counts(result);
+        let name = xml_escape(&result.name);
+
+        xml += &format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", name, result.report.cases.len(), failures, errors);
+        xml += "    <properties>\n";
+        xml += &format!("      <property name=\"instructions\" value=\"{}\"/>\n", result.instructions);
+        xml += &format!("      <property name=\"lines_executed\" value=\"{}/{}\"/>\n", result.lines_executed.0, result.lines_executed.1);
+        xml += "    </properties>\n";
+
+        for case in result.report.cases.iter() {
+            xml += &format!("    <testcase name=\"{}\" classname=\"{}\"", xml_escape(&case.name), name);
+            if case.passed() {
+                xml += "/>\n";
+                continue;
+            }
+
+            let tag = if result.graded() { "failure" } else { "error" };
+            let details = if result.graded() { case.failures.join("\n") } else { result.diagnostics.join("\n") };
+            xml += ">\n";
+            xml += &format!("      <{} message=\"{}\">{}</{}>\n", tag, xml_escape(&case.failures[0]), xml_escape(&details), tag);
+            if !case.output.is_empty() {
+                xml += &format!("      <system-out>{}</system-out>\n", xml_escape(&case.output));
+            }
+            xml += "    </testcase>\n";
+        }
+
+        xml += "  </testsuite>\n";
+    }
+
+    xml += "</testsuites>\n";
+    return xml;
+}
+
+#[allow(dead_code)]
+/* `(failures, errors)`. A submission that could not be graded is an error, not a failure. */
+fn junit_counts(result: &SubmissionResult) -> (usize, usize) {
+    if result.graded() {
+        return (result.report.failed(), 0);
+    }
+    return (0, result.report.cases.len());
+}
+
+#[allow(dead_code)]
+fn csv_row(fields: &[String]) -> String {
+    let fields: Vec<String> = fields.iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.clone()
+            }
+        })
+        .collect();
+
+    return fields.join(",") + "\n";
+}
+
+#[allow(dead_code)]
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\n' | '\t' | '\r' => escaped.push(c),
+            // Other control characters are not allowed in XML 1.0 at all
+            c if (c as u32) < 0x20 => escaped += &format!("\\x{:02X}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::VM;
+
+    const SPEC: &str = r#"
+[[case]]
+name = "3 times 4"
+registers = { R0 = 3, R1 = 4 }
+expect = { registers = { R2 = 12 } }
+
+[[case]]
+name = "times zero"
+registers = { R0 = 3 }
+max_instructions = 1000
+expect = { registers = { R2 = 0 } }
+"#;
+
+    const CORRECT: &str = ".ORIG x3000
+        and r2, r2, #0
+        add r1, r1, #0
+        brz done
+loop    add r2, r2, r0
+        add r1, r1, #-1
+        brp loop
+done    halt
+        .END";
+
+    // Forgets to check for zero, so it loops 65536 times
+    const NO_ZERO_CHECK: &str = ".ORIG x3000
+        and r2, r2, #0
+loop    add r2, r2, r0
+        add r1, r1, #-1
+        brnp loop
+        halt
+        .END";
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_vm_and_asm_are_send() {
+        assert_send::<VM>();
+        assert_send::<Asm>();
+    }
+
+    fn grade() -> (TestSpec, Vec<SubmissionResult>) {
+        let spec = TestSpec::from_toml(SPEC).unwrap();
+        let mut grader = BatchGrader::new(spec.clone());
+        grader.jobs = 2;
+
+        let results = grader.grade_all(&[
+            ("alice".to_string(), Ok(CORRECT.to_string())),
+            ("bob".to_string(), Ok(NO_ZERO_CHECK.to_string())),
+            ("carol".to_string(), Ok(".ORIG x3000\nadd r1\n.END".to_string())),
+            ("dave".to_string(), Err("could not read dave.asm".to_string())),
+        ]);
+
+        return (spec, results);
+    }
+
+    #[test]
+    fn test_grade_all() {
+        let (_, results) = grade();
+        let names: Vec<&str> = results.iter().map(|result| result.name.as_str()).collect();
+        assert_eq!(names, vec!["alice", "bob", "carol", "dave"]);
+
+        assert!(results[0].report.all_passed());
+        assert_eq!(results[0].lines_executed, (7, 7));
+        assert!(results[0].instructions > 0);
+
+        assert_eq!(results[1].report.passed(), 1);
+        assert_eq!(results[1].lines_executed, (5, 5));
+
+        assert!(!results[2].graded());
+        assert!(results[2].diagnostics[0].contains("On line 2"));
+        assert_eq!(results[2].report.cases[0].failures, vec!["the source file did not assemble".to_string()]);
+
+        assert_eq!(results[3].report.cases[1].failures, vec!["could not read dave.asm".to_string()]);
+    }
+
+    #[test]
+    fn test_csv() {
+        let (spec, results) = grade();
+        let csv = to_csv(&spec, &results);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines[0], "submission,assembled,passed,total,instructions,lines_executed,lines_total,3 times 4,times zero,diagnostics");
+        assert!(lines[1].starts_with("alice,true,2,2,"));
+        assert!(lines[1].ends_with(",7,7,PASS,PASS,"));
+        assert!(lines[2].ends_with(",5,5,PASS,FAIL,"));
+        assert!(lines[3].starts_with("carol,false,0,2,0,0,0,FAIL,FAIL,\"[SM002] OperandError: On line 2,"));
+        assert_eq!(csv_row(&["a\"b".to_string(), "c".to_string()]), "\"a\"\"b\",c\n");
+    }
+
+    #[test]
+    fn test_junit() {
+        let (_, results) = grade();
+        let xml = to_junit(&results);
+
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"8\" failures=\"1\" errors=\"4\">\n"));
+        assert!(xml.contains("<testsuite name=\"alice\" tests=\"2\" failures=\"0\" errors=\"0\">"));
+        assert!(xml.contains("<testcase name=\"3 times 4\" classname=\"alice\"/>"));
+        assert!(xml.contains("<testsuite name=\"bob\" tests=\"2\" failures=\"1\" errors=\"0\">"));
+        assert!(xml.contains("<failure message=\"expected the program to halt, but it ran out of budget"));
+        assert!(xml.contains("<testsuite name=\"carol\" tests=\"2\" failures=\"0\" errors=\"2\">"));
+        assert!(xml.contains("<error message=\"the source file did not assemble\">"));
+        assert_eq!(xml_escape("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+}
@@ -0,0 +1,3 @@
+pub mod spec;
+pub mod runner;
+pub mod batch;
@@ -0,0 +1,448 @@
+use super::spec::{ExpectedOutcome, SpecValue, TestCase, TestSpec};
+use crate::asm::asm::Asm;
+use crate::asm::source_map::SourceMap;
+use crate::asm::debug_info::Assertion;
+use crate::vm::budget::Budget;
+use crate::vm::coverage::Coverage;
+use crate::vm::vm::{RunOutcome, VM};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+// Keeps a program that never halts from hanging the whole run
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+// Only reached by a program with slow custom traps, or on a very busy machine
+pub const DEFAULT_MAX_MILLISECONDS: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseResult {
+    pub name: String,
+    pub failures: Vec<String>,
+    pub outcome: Option<RunOutcome>,
+    pub output: String,
+    pub instructions: u64,
+    pub coverage: Coverage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpecReport {
+    pub source: String,
+    pub cases: Vec<CaseResult>,
+}
+
+/*
+Runs the cases of a `TestSpec`. The program is assembled once, and every
+case gets a fresh `VM` with the program loaded, so cases cannot affect
+each other.
+*/
+#[allow(dead_code)]
+pub struct SpecRunner {
+    binary_file: Vec<u16>,
+    symbols: HashMap<String, u16>,
+    // The `.ASSERT`s in the source, which every case checks
+    assertions: Vec<Assertion>,
+    pub source_map: SourceMap,
+}
+
+#[allow(dead_code)]
+impl SpecRunner {
+    pub fn new(source: &str) -> Result<SpecRunner, String> {
+        return SpecRunner::assemble(source).map_err(|_| "the source file did not assemble".to_string());
+    }
+
+    /* Like `new`, but keeps every assembler error message when the source does not assemble. */
+    pub fn assemble(source: &str) -> Result<SpecRunner, Vec<String>> {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(source.to_string());
+
+        if binary_file.is_empty() {
+            let mut errors: Vec<String> = asm.errors.iter().map(|err| err.generate_msg()).collect();
+            if errors.is_empty() {
+                errors.push("the source file is empty".to_string());
+            }
+            return Err(errors);
+        }
+
+        return Ok(SpecRunner {
+            binary_file,
+            symbols: asm.get_symbols(),
+            assertions: asm.debug_info.assertions,
+            source_map: asm.source_map,
+        });
+    }
+
+    /* Reads the spec at `path` and the source file it names, then runs every case. */
+    pub fn run_file<P: AsRef<Path>>(path: P) -> Result<SpecReport, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        let spec = TestSpec::from_toml(&text)?;
+
+        let source_path = path.parent().unwrap_or(Path::new("")).join(&spec.source);
+        let source = fs::read_to_string(&source_path)
+            .map_err(|err| format!("could not read {}: {}", source_path.display(), err))?;
+
+        return Ok(SpecRunner::run_source(&spec, &source));
+    }
+
+    /* Runs every case against `source`. If it does not assemble, every case fails. */
+    pub fn run_source(spec: &TestSpec, source: &str) -> SpecReport {
+        match SpecRunner::new(source) {
+            Ok(runner) => return runner.run(spec),
+            Err(err) => {
+                return SpecReport {
+                    source: spec.source.clone(),
+                    cases: spec.cases.iter()
+                        .map(|case| CaseResult {
+                            name: case.name.clone(),
+                            failures: vec![err.clone()],
+                            outcome: None,
+                            output: String::new(),
+                            instructions: 0,
+                            coverage: Coverage::new(),
+                        })
+                        .collect(),
+                };
+            },
+        }
+    }
+
+    pub fn run(&self, spec: &TestSpec) -> SpecReport {
+        return SpecReport {
+            source: spec.source.clone(),
+            cases: spec.cases.iter().map(|case| self.run_case(case)).collect(),
+        };
+    }
+
+    pub fn run_case(&self, case: &TestCase) -> CaseResult {
+        let mut result = CaseResult {
+            name: case.name.clone(),
+            failures: vec![],
+            outcome: None,
+            output: String::new(),
+            instructions: 0,
+            coverage: Coverage::new(),
+        };
+
+        let mut vm = VM::with_input(&case.input);
+        vm.enable_coverage();
+        vm.enable_assertions(&self.assertions);
+        vm.load(self.binary_file.clone());
+        vm.set_symbols(self.symbols.clone());
+        vm.set_budget(Budget {
+            max_instructions: Some(case.max_instructions.unwrap_or(DEFAULT_MAX_INSTRUCTIONS)),
+            max_duration: Some(Duration::from_millis(case.max_milliseconds.unwrap_or(DEFAULT_MAX_MILLISECONDS))),
+            ..Budget::new()
+        });
+
+        for (name, value) in case.registers.iter() {
+            match (self.get_register_index(name), value.to_word()) {
+                (Ok(Some(index)), Ok(word)) => vm.get_registers_mut().r[index] = word,
+                (Ok(None), Ok(word)) => vm.get_registers_mut().pc = word,
+                (Err(err), _) | (_, Err(err)) => result.failures.push(format!("preset {}: {}", name, err)),
+            }
+        }
+        for (location, value) in case.memory.iter() {
+            match (self.get_address(location), value.to_word()) {
+                (Ok(address), Ok(word)) => vm.get_memory_mut().load_words(address, &[word]),
+                (Err(err), _) | (_, Err(err)) => result.failures.push(format!("preset {}: {}", location, err)),
+            }
+        }
+        if !result.failures.is_empty() {
+            return result;
+        }
+
+        let outcome = vm.resume();
+        result.output = vm.get_output().unwrap_or("").to_string();
+        result.instructions = vm.get_instruction_count();
+        result.coverage = vm.get_coverage().cloned().unwrap_or_default();
+
+        self.check_outcome(case, &outcome, &mut result.failures);
+        self.check_registers(case, &vm, &mut result.failures);
+        self.check_memory(case, &vm, &mut result.failures);
+        self.check_output(case, &result.output.clone(), &mut result.failures);
+        for failure in vm.get_assertion_failures().iter() {
+            result.failures.push(failure.generate_msg());
+        }
+
+        result.outcome = Some(outcome);
+        return result;
+    }
+
+    fn check_outcome(&self, case: &TestCase, outcome: &RunOutcome, failures: &mut Vec<String>) {
+        let expected = case.expect.outcome.unwrap_or(ExpectedOutcome::Halt);
+
+        let matches = match expected {
+            ExpectedOutcome::Halt => *outcome == RunOutcome::Halted,
+            ExpectedOutcome::Fault => *outcome != RunOutcome::Halted,
+            ExpectedOutcome::Budget => matches!(outcome, RunOutcome::BudgetExceeded(_)),
+            ExpectedOutcome::InputExhausted => matches!(outcome, RunOutcome::InputExhausted(_)),
+            ExpectedOutcome::WriteFault => matches!(outcome, RunOutcome::WriteFault(_)),
+        };
+
+        if !matches {
+            failures.push(format!("expected the program to {}, but it {}", expected.as_string(), describe_outcome(outcome)));
+        }
+    }
+
+    fn check_registers(&self, case: &TestCase, vm: &VM, failures: &mut Vec<String>) {
+        for (name, value) in case.expect.registers.iter() {
+            let actual = match self.get_register_index(name) {
+                Ok(Some(index)) => vm.get_registers().r[index],
+                Ok(None) => vm.get_registers().pc,
+                Err(err) => {
+                    failures.push(format!("expected {}: {}", name, err));
+                    continue;
+                },
+            };
+            self.compare(&name.to_uppercase(), value, actual, failures);
+        }
+    }
+
+    fn check_memory(&self, case: &TestCase, vm: &VM, failures: &mut Vec<String>) {
+        for (location, value) in case.expect.memory.iter() {
+            match self.get_address(location) {
+                Ok(address) => {
+                    let name = format!("memory at {} (x{:04X})", location, address);
+                    self.compare(&name, value, vm.get_memory().get(address), failures);
+                },
+                Err(err) => failures.push(format!("expected {}: {}", location, err)),
+            }
+        }
+    }
+
+    fn check_output(&self, case: &TestCase, output: &str, failures: &mut Vec<String>) {
+        if let Some(expected) = &case.expect.stdout
+            && expected != output {
+            failures.push(format!("stdout: expected {:?}, got {:?}", expected, output));
+        }
+
+        if let Some(pattern) = &case.expect.stdout_regex {
+            match Regex::new(pattern) {
+                Ok(regex) if regex.is_match(output) => {},
+                Ok(_) => failures.push(format!("stdout: {:?} does not match /{}/", output, pattern)),
+                Err(err) => failures.push(format!("stdout_regex is not a valid regex: {}", err)),
+            }
+        }
+    }
+
+    fn compare(&self, name: &str, expected: &SpecValue, actual: u16, failures: &mut Vec<String>) {
+        match expected.to_word() {
+            Ok(word) if word == actual => {},
+            Ok(word) => failures.push(format!("{}: expected x{:04X} ({}), got x{:04X} ({})", name, word, word as i16, actual, actual as i16)),
+            Err(err) => failures.push(format!("expected {}: {}", name, err)),
+        }
+    }
+
+    /* `Some(n)` for Rn, or `None` for the PC. */
+    fn get_register_index(&self, name: &str) -> Result<Option<usize>, String> {
+        let upper = name.to_uppercase();
+
+        if upper == "PC" {
+            return Ok(None);
+        }
+        if let Some(index) = upper.strip_prefix('R').and_then(|n| n.parse::<usize>().ok())
+            && index < 8 {
+            return Ok(Some(index));
+        }
+        return Err(format!("`{}` is not a register", name));
+    }
+
+    /* Labels take priority, so a label that happens to look like a number still works. */
+    fn get_address(&self, location: &str) -> Result<u16, String> {
+        if let Some(address) = self.symbols.get(location) {
+            return Ok(*address);
+        }
+
+        return SpecValue::Text(location.to_string())
+            .to_word()
+            .map_err(|_| format!("`{}` is neither a label nor an address", location));
+    }
+}
+
+#[allow(dead_code)]
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        return self.failures.is_empty();
+    }
+}
+
+#[allow(dead_code)]
+impl SpecReport {
+    pub fn passed(&self) -> usize {
+        return self.cases.iter().filter(|case| case.passed()).count();
+    }
+
+    pub fn failed(&self) -> usize {
+        return self.cases.len() - self.passed();
+    }
+
+    pub fn all_passed(&self) -> bool {
+        return self.failed() == 0;
+    }
+
+    pub fn total_instructions(&self) -> u64 {
+        return self.cases.iter().map(|case| case.instructions).sum();
+    }
+
+    /* The coverage of every case combined. */
+    pub fn coverage(&self) -> Coverage {
+        let mut coverage = Coverage::new();
+        for case in self.cases.iter() {
+            coverage.merge(&case.coverage);
+        }
+        return coverage;
+    }
+
+    pub fn generate_msg(&self) -> String {
+        let mut msg = format!("{}\n", self.source);
+
+        for case in self.cases.iter() {
+            let status = if case.passed() { "PASS" } else { "FAIL" };
+            msg += &format!("  {}  {}\n", status, case.name);
+            for failure in case.failures.iter() {
+                msg += &format!("        {}\n", failure);
+            }
+        }
+        msg += &format!("{} passed, {} failed\n", self.passed(), self.failed());
+
+        return msg;
+    }
+}
+
+pub fn describe_outcome(outcome: &RunOutcome) -> String {
+    match outcome {
+        RunOutcome::Halted => return "halted".to_string(),
+        RunOutcome::Returned => return "returned".to_string(),
+        RunOutcome::BudgetExceeded(exceeded) => {
+            return format!("ran out of budget at x{:04X} after {} instructions", exceeded.last_pc, exceeded.instructions);
+        },
+        RunOutcome::WriteFault(violation) => {
+            return format!("tried to overwrite x{:04X} at x{:04X}", violation.address, violation.pc);
+        },
+        RunOutcome::InputExhausted(pc) => return format!("ran out of input at x{:04X}", pc),
+        RunOutcome::Breakpoint(pc) => return format!("stopped at a breakpoint at x{:04X}", pc),
+        RunOutcome::IllegalOpcode(pc) => return format!("ran an instruction with an illegal opcode at x{:04X}", pc),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::budget::BudgetReason;
+
+    const SOURCE: &str = r#".ORIG x3000
+        and r2, r2, #0
+        add r1, r1, #0
+        brz done
+loop    add r2, r2, r0
+        add r1, r1, #-1
+        brp loop
+done    st r2, result
+        lea r0, msg
+        puts
+        halt
+result  .fill #0
+msg     .stringz "Done!"
+        .END"#;
+
+    fn run(spec: &str) -> SpecReport {
+        return SpecRunner::run_source(&TestSpec::from_toml(spec).unwrap(), SOURCE);
+    }
+
+    #[test]
+    fn test_passing_cases() {
+        let report = run(r#"
+source = "multiply.asm"
+
+[[case]]
+name = "5 times 7"
+registers = { R0 = 5, R1 = 7 }
+expect = { outcome = "halt", registers = { R2 = 35 }, memory = { result = 35 }, stdout = "Done!" }
+
+[[case]]
+name = "times zero"
+registers = { R0 = 5 }
+expect = { registers = { r2 = 0 }, stdout_regex = "^Do" }
+"#);
+
+        assert!(report.all_passed(), "{}", report.generate_msg());
+        assert_eq!(report.passed(), 2);
+        assert_eq!(report.cases[1].instructions, 7);
+        assert_eq!(report.total_instructions(), report.cases[0].instructions + 7);
+        assert_eq!(report.coverage().get_hits(0x3003), 7);
+    }
+
+    #[test]
+    fn test_failing_cases() {
+        let report = run(r#"
+source = "multiply.asm"
+
+[[case]]
+name = "wrong product"
+registers = { R0 = 2, R1 = 2 }
+expect = { registers = { R2 = 5 }, memory = { x4000 = 1 } }
+
+[[case]]
+name = "too slow"
+registers = { R0 = 1, R1 = 100 }
+max_instructions = 20
+
+[[case]]
+name = "bad preset"
+registers = { R9 = 1 }
+"#);
+
+        assert_eq!(report.failed(), 3);
+        assert_eq!(report.cases[0].failures, vec![
+            "R2: expected x0005 (5), got x0004 (4)".to_string(),
+            "memory at x4000 (x4000): expected x0001 (1), got x0000 (0)".to_string(),
+        ]);
+        assert!(report.cases[1].failures[0].starts_with("expected the program to halt, but it ran out of budget"));
+        assert!(report.cases[2].failures[0].contains("`R9` is not a register"));
+        assert!(report.generate_msg().ends_with("0 passed, 3 failed\n"));
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let report = run(r#"
+[[case]]
+name = "out of time"
+registers = { R0 = 1, R1 = "xFFFF" }
+max_instructions = 1_000_000_000
+max_milliseconds = 0
+expect = { outcome = "budget" }
+"#);
+
+        assert!(report.all_passed(), "{}", report.generate_msg());
+        let Some(RunOutcome::BudgetExceeded(exceeded)) = &report.cases[0].outcome else {
+            panic!("expected the time budget to run out");
+        };
+        assert_eq!(exceeded.reason, BudgetReason::Time(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_source_does_not_assemble() {
+        let spec = TestSpec::from_toml("source = \"a.asm\"\n[[case]]\nname = \"a\"").unwrap();
+        let report = SpecRunner::run_source(&spec, ".ORIG x3000\nadd r1\n.END");
+
+        assert!(!report.all_passed());
+        assert_eq!(report.cases[0].failures, vec!["the source file did not assemble".to_string()]);
+
+        let errors = SpecRunner::assemble(".ORIG x3000\nadd r1\n.END").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("On line 2"));
+    }
+
+    #[test]
+    fn test_source_assertions() {
+        let source = SOURCE.replace("done    st r2, result", "        .ASSERT R2 != #0\ndone    st r2, result");
+        let spec = TestSpec::from_toml("[[case]]\nname = \"a\"\nregisters = { R0 = 0, R1 = 2 }").unwrap();
+        let report = SpecRunner::run_source(&spec, &source);
+
+        assert_eq!(report.cases[0].failures.len(), 1);
+        assert!(report.cases[0].failures[0].starts_with("assertion `R2 != #0` on line 8 failed at x3006"));
+    }
+}
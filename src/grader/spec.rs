@@ -0,0 +1,187 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/*
+Test cases for one LC-3 program, written in TOML so that course staff can
+write them without touching Rust:
+
+    source = "multiply.asm"
+
+    [[case]]
+    name = "5 times 7"
+    registers = { R0 = 5, R1 = 7 }
+    memory = { count = 2, x4000 = "xFFFF" }
+    input = "y"
+    max_instructions = 1000
+    max_milliseconds = 500
+
+    [case.expect]
+    outcome = "halt"
+    registers = { R2 = 35 }
+    memory = { result = 35 }
+    stdout = "Done!\n"
+    stdout_regex = "^Done"
+
+`source` is relative to the spec file. Memory is addressed by label or by
+address, and every value can either be a TOML number or a string in the
+same format the assembler uses, like "x41" or "#-1".
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSpec {
+    // Not needed when the batch grader supplies the submissions
+    #[serde(default)]
+    pub source: String,
+    #[serde(default, rename = "case")]
+    pub cases: Vec<TestCase>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    #[serde(default)]
+    pub registers: BTreeMap<String, SpecValue>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, SpecValue>,
+    #[serde(default)]
+    pub input: String,
+    pub max_instructions: Option<u64>,
+    // The wall-clock budget
+    pub max_milliseconds: Option<u64>,
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectations {
+    // A case without an expected outcome is expected to halt
+    pub outcome: Option<ExpectedOutcome>,
+    #[serde(default)]
+    pub registers: BTreeMap<String, SpecValue>,
+    #[serde(default)]
+    pub memory: BTreeMap<String, SpecValue>,
+    pub stdout: Option<String>,
+    pub stdout_regex: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectedOutcome {
+    Halt,
+    // Anything other than halting
+    Fault,
+    Budget,
+    InputExhausted,
+    WriteFault,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum SpecValue {
+    Number(i64),
+    Text(String),
+}
+
+#[allow(dead_code)]
+impl TestSpec {
+    pub fn from_toml(text: &str) -> Result<TestSpec, String> {
+        return toml::from_str(text).map_err(|err| format!("the test spec is not valid: {}", err));
+    }
+}
+
+impl ExpectedOutcome {
+    pub fn as_string(&self) -> String {
+        match self {
+            ExpectedOutcome::Halt => "halt".to_string(),
+            ExpectedOutcome::Fault => "stop without halting".to_string(),
+            ExpectedOutcome::Budget => "run out of budget".to_string(),
+            ExpectedOutcome::InputExhausted => "run out of input".to_string(),
+            ExpectedOutcome::WriteFault => "write to protected memory".to_string(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl SpecValue {
+    /* The value as a 16-bit word. Negative numbers are stored in 2's complement. */
+    pub fn to_word(&self) -> Result<u16, String> {
+        let number = match self {
+            SpecValue::Number(number) => Some(*number),
+            SpecValue::Text(text) => parse_number(text),
+        };
+
+        match number {
+            Some(number) if number >= i16::MIN as i64 && number <= u16::MAX as i64 => return Ok(number as u16),
+            Some(number) => return Err(format!("`{}` does not fit in 16 bits", number)),
+            None => return Err(format!("`{}` is not a number", self.as_string())),
+        }
+    }
+
+    pub fn as_string(&self) -> String {
+        match self {
+            SpecValue::Number(number) => return number.to_string(),
+            SpecValue::Text(text) => return text.clone(),
+        }
+    }
+}
+
+/* Parses `x3000`, `#-5` or plain decimal numbers. */
+pub fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim();
+
+    if let Some(hex) = text.strip_prefix('x').or(text.strip_prefix('X')) {
+        return i64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(decimal) = text.strip_prefix('#') {
+        return decimal.parse().ok();
+    }
+    return text.parse().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = TestSpec::from_toml(r##"
+source = "multiply.asm"
+
+[[case]]
+name = "5 times 7"
+registers = { R0 = 5, R1 = "x7" }
+
+[case.expect]
+outcome = "halt"
+registers = { R2 = 35 }
+memory = { result = "#35" }
+
+[[case]]
+name = "loops forever"
+max_instructions = 100
+max_milliseconds = 50
+expect = { outcome = "budget" }
+"##).unwrap();
+
+        assert_eq!(spec.source, "multiply.asm");
+        assert_eq!(spec.cases.len(), 2);
+        assert_eq!(spec.cases[0].registers["R1"].to_word(), Ok(7));
+        assert_eq!(spec.cases[0].expect.memory["result"].to_word(), Ok(35));
+        assert_eq!(spec.cases[1].max_instructions, Some(100));
+        assert_eq!(spec.cases[1].max_milliseconds, Some(50));
+        assert_eq!(spec.cases[1].expect.outcome, Some(ExpectedOutcome::Budget));
+
+        assert!(TestSpec::from_toml("source = \"a.asm\"\nunknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_spec_values() {
+        assert_eq!(SpecValue::Text("xFFFF".to_string()).to_word(), Ok(0xFFFF));
+        assert_eq!(SpecValue::Text("#-1".to_string()).to_word(), Ok(0xFFFF));
+        assert_eq!(SpecValue::Number(-1).to_word(), Ok(0xFFFF));
+        assert!(SpecValue::Number(65536).to_word().is_err());
+        assert!(SpecValue::Text("five".to_string()).to_word().is_err());
+    }
+}
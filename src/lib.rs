@@ -2,6 +2,7 @@ mod asm;
 mod vm;
 mod web;
 mod output;
+mod grader;
 use crate::asm::lexer::*;
 use crate::asm::token::*;
 use wasm_bindgen::prelude::*;
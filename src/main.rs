@@ -2,28 +2,120 @@ pub mod vm;
 pub mod asm;
 pub mod web;
 pub mod output;
+pub mod grader;
 
 use crate::vm::vm::VM;
 use crate::asm::asm::Asm;
+use crate::asm::conditional;
+use crate::grader::batch::{self, BatchGrader};
+use crate::grader::spec::TestSpec;
 use std::env;
 use std::fs;
+use std::process;
+
+const GRADE_USAGE: &str = "usage: lc3-emulator grade <submissions dir> <spec.toml> [--csv <file>] [--junit <file>] [--jobs <n>]";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    if args.get(1).map(|arg| arg.as_str()) == Some("grade") {
+        if let Err(err) = grade(&args[2..]) {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+        return;
+    }
+
     // Assume argument 1 is the file path
 /*     let file_path = args.get(1)
         .expect("Must provide a file path") */;
 
     let file_path = "SecretProject.asm";
     // let file_path = "test.asm";
-    let file = fs::read_to_string(file_path)
-        .expect("The provided file path was not valid");
-
-
+    // Reads the file itself, so `.INCLUDE` can find files next to it
     let mut asm = Asm::new();
-    let binary_file = asm.run(file);
+    if let Err(err) = define_all(&mut asm, &args[1..]) {
+        eprintln!("{}", err);
+        process::exit(2);
+    }
+    let binary_file = asm.run_file(file_path)
+        .expect("The provided file path was not valid");
+    for error in asm.errors.iter() {
+        println!("{}", error.generate_msg());
+    }
     
     let mut vm = VM::new();
     vm.run(binary_file);
 }
+
+/* Defines every constant given with `-D`. */
+fn define_all(asm: &mut Asm, args: &[String]) -> Result<(), String> {
+    for (name, value) in get_definitions(args)? {
+        asm.define(&name, value)?;
+    }
+    return Ok(());
+}
+
+/* The constants given with `-D NAME=value` or `-DNAME=value`. */
+fn get_definitions(args: &[String]) -> Result<Vec<(String, i32)>, String> {
+    let mut definitions = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let definition = match arg.strip_prefix("-D") {
+            Some("") => args.next().ok_or("-D needs a definition, like -D SOLUTION=1")?.as_str(),
+            Some(definition) => definition,
+            None => continue,
+        };
+        definitions.push(conditional::parse_definition(definition)?);
+    }
+
+    return Ok(definitions);
+}
+
+/* Grades every submission in a directory and writes the gradebook and JUnit report. */
+fn grade(args: &[String]) -> Result<(), String> {
+    let mut positional = vec![];
+    let mut csv_path = None;
+    let mut junit_path = None;
+    let mut jobs = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--csv" => csv_path = Some(args.next().ok_or(GRADE_USAGE)?),
+            "--junit" => junit_path = Some(args.next().ok_or(GRADE_USAGE)?),
+            "--jobs" => {
+                let n = args.next().ok_or(GRADE_USAGE)?;
+                jobs = Some(n.parse::<usize>().map_err(|_| format!("`{}` is not a number of jobs", n))?);
+            },
+            _ => positional.push(arg),
+        }
+    }
+    let [dir, spec_path] = positional[..] else {
+        return Err(GRADE_USAGE.to_string());
+    };
+
+    let spec_text = fs::read_to_string(spec_path)
+        .map_err(|err| format!("could not read {}: {}", spec_path, err))?;
+    let spec = TestSpec::from_toml(&spec_text)?;
+
+    let mut grader = BatchGrader::new(spec.clone());
+    if let Some(jobs) = jobs {
+        grader.jobs = jobs;
+    }
+    let results = grader.grade_directory(dir)?;
+
+    for result in results.iter() {
+        println!("{:<24} {}/{} passed", result.name, result.report.passed(), result.report.cases.len());
+    }
+
+    if let Some(path) = csv_path {
+        fs::write(path, batch::to_csv(&spec, &results)).map_err(|err| format!("could not write {}: {}", path, err))?;
+    }
+    if let Some(path) = junit_path {
+        fs::write(path, batch::to_junit(&results)).map_err(|err| format!("could not write {}: {}", path, err))?;
+    }
+
+    return Ok(());
+}
@@ -1,14 +1,51 @@
+use std::collections::VecDeque;
+use std::fs;
 use std::io::*;
+use std::path::Path;
 
+// Send so that a VM can be moved to another thread, like the batch grader does
 #[allow(dead_code)]
-pub trait SystemIO {
+pub trait SystemIO: Send {
     fn print_char(&mut self, c: char);
-    fn get_char(&mut self) -> char;
+    /* `None` once there is no more input to read. */
+    fn get_char(&mut self) -> Option<char>;
+
+    /* Whether a character is waiting to be read, which is what KBSR reports. */
+    fn has_char(&mut self) -> bool {
+        return true;
+    }
+
+    /* Everything printed so far, if this IO keeps it. */
+    fn get_output(&self) -> Option<&str> {
+        return None;
+    }
+
+    /* The input that was not read yet, if this IO knows it. */
+    fn get_input(&self) -> Option<String> {
+        return None;
+    }
+
+    /* Replaces the input that was not read yet. Returns false if this IO cannot. */
+    fn set_input(&mut self, _input: &str) -> bool {
+        return false;
+    }
 }
 
 #[allow(dead_code)]
 pub struct StdIO;
 
+/*
+Input comes from a predefined sequence instead of stdin, and everything
+printed is kept in a buffer instead of going to stdout. This is what
+automated tests of interactive programs should use.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
+pub struct BufferedIO {
+    input: VecDeque<char>,
+    output: String,
+}
+
 /* The main purpose of WebIO is to allow JS to get and set a char that is controlled by
 an HTML tag with a  */
 #[allow(dead_code)]
@@ -20,24 +57,66 @@ pub struct WebIO {
 impl SystemIO for StdIO {
     fn print_char(&mut self, c: char) {
         print!("{c}");
+        // Without this, a prompt may not show up until after the input it asks for was read
+        let _ = stdout().flush();
     }
-    
-    fn get_char(&mut self) -> char {
-        let input: Option<u8> = std::io::stdin()
+
+    fn get_char(&mut self) -> Option<char> {
+        return std::io::stdin()
             .bytes()
             .next()
             .and_then(|result| result.ok())
-            .map(|byte| byte);
-
-        // Since input is an Option<i64>, which is an enum, we have to consider it's cases: Some and None.
-        match input {
-            Some(input) => input as char,
-            None => {
-                println!("Char: None");
-                return '\0';
-            },
+            .map(|byte| byte as char);
+    }
+}
+
+#[allow(dead_code)]
+impl BufferedIO {
+    pub fn new(input: &str) -> BufferedIO {
+        BufferedIO {
+            input: input.chars().collect(),
+            output: String::new(),
         }
     }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BufferedIO> {
+        let bytes = fs::read(path)?;
+        return Ok(BufferedIO {
+            input: bytes.into_iter().map(|byte| byte as char).collect(),
+            output: String::new(),
+        });
+    }
+
+    pub fn push_input(&mut self, input: &str) {
+        self.input.extend(input.chars());
+    }
+}
+
+impl SystemIO for BufferedIO {
+    fn print_char(&mut self, c: char) {
+        self.output.push(c);
+    }
+
+    fn get_char(&mut self) -> Option<char> {
+        return self.input.pop_front();
+    }
+
+    fn has_char(&mut self) -> bool {
+        return !self.input.is_empty();
+    }
+
+    fn get_output(&self) -> Option<&str> {
+        return Some(&self.output);
+    }
+
+    fn get_input(&self) -> Option<String> {
+        return Some(self.input.iter().collect());
+    }
+
+    fn set_input(&mut self, input: &str) -> bool {
+        self.input = input.chars().collect();
+        return true;
+    }
 }
 
 // impl SystemIO for WebIO {
@@ -45,3 +124,23 @@ impl SystemIO for StdIO {
 //         self.current_char = c;
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffered_io() {
+        let mut io = BufferedIO::new("ab");
+
+        assert!(io.has_char());
+        assert_eq!(io.get_char(), Some('a'));
+        assert_eq!(io.get_char(), Some('b'));
+        assert!(!io.has_char());
+        assert_eq!(io.get_char(), None);
+
+        io.print_char('h');
+        io.print_char('i');
+        assert_eq!(io.get_output(), Some("hi"));
+    }
+}
@@ -0,0 +1,43 @@
+/*
+The instruction set a program is written for. LC-3b is the byte
+addressable variant: every address is a byte address, words sit at even
+addresses with the low byte first, and PC-relative offsets count words,
+so they are shifted left by one before being added to the PC.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Architecture {
+    #[default]
+    Lc3,
+    Lc3b,
+}
+
+#[allow(dead_code)]
+impl Architecture {
+    pub fn from_name(name: &str) -> Option<Architecture> {
+        match name.to_ascii_lowercase().as_str() {
+            "lc3" | "lc-3" => return Some(Architecture::Lc3),
+            "lc3b" | "lc-3b" => return Some(Architecture::Lc3b),
+            _ => return None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Architecture::Lc3 => return "LC-3",
+            Architecture::Lc3b => return "LC-3b",
+        }
+    }
+
+    /* How much an address grows from one word to the next. */
+    pub fn word_size(&self) -> u16 {
+        match self {
+            Architecture::Lc3 => return 1,
+            Architecture::Lc3b => return 2,
+        }
+    }
+
+    pub fn is_byte_addressable(&self) -> bool {
+        return *self == Architecture::Lc3b;
+    }
+}
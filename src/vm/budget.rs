@@ -0,0 +1,206 @@
+use super::registers::Registers;
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+pub const TRACE_WINDOW: usize = 16;
+const LOOP_DETECTOR_CAPACITY: usize = 4096;
+
+/*
+Limits for unattended runs. Every limit is off by default, so a VM without
+a budget behaves exactly like it always has.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Budget {
+    pub max_instructions: Option<u64>,
+    pub max_duration: Option<Duration>,
+    pub detect_loops: bool,
+}
+
+#[allow(dead_code)]
+impl Budget {
+    pub fn new() -> Budget {
+        Budget::default()
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        return self.max_instructions.is_none() && self.max_duration.is_none() && !self.detect_loops;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetReason {
+    Instructions(u64),
+    Time(Duration),
+    InfiniteLoop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetExceeded {
+    pub reason: BudgetReason,
+    pub last_pc: u16,
+    pub instructions: u64,
+    pub recent_trace: Vec<u16>,
+}
+
+#[allow(dead_code)]
+impl BudgetExceeded {
+    pub fn generate_msg(&self) -> String {
+        let reason = match &self.reason {
+            BudgetReason::Instructions(limit) => format!("the instruction budget of {} was used up", limit),
+            BudgetReason::Time(limit) => format!("the time budget of {:?} was used up", limit),
+            BudgetReason::InfiniteLoop => "the machine returned to a state it had already been in, so it will never halt".to_string(),
+        };
+
+        let mut msg = format!(
+            "Budget exceeded: {} after {} instructions. Last PC: x{:04X}\nRecent trace:",
+            reason,
+            self.instructions,
+            self.last_pc,
+        );
+        for pc in self.recent_trace.iter() {
+            msg += &format!("\n\tx{:04X}", pc);
+        }
+        msg += "\n";
+
+        return msg;
+    }
+}
+
+/*
+A tight loop that never writes to memory and never traps can only ever
+revisit the same handful of register states. So if we see the exact same
+registers (PC and condition codes included) twice without memory changing in
+between, the program is stuck.
+
+Any memory write or trap might change what the loop does next, so the seen
+states are forgotten whenever either happens.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct LoopDetector {
+    seen: HashSet<([u16; 8], u16, bool, bool, bool)>,
+    last_memory_writes: u64,
+}
+
+impl LoopDetector {
+    pub fn new() -> LoopDetector {
+        LoopDetector::default()
+    }
+
+    pub fn forget(&mut self) {
+        self.seen.clear();
+    }
+
+    /* Returns true if this exact state was already seen since the last memory write. */
+    pub fn check(&mut self, reg: &Registers, memory_writes: u64) -> bool {
+        if memory_writes != self.last_memory_writes || self.seen.len() >= LOOP_DETECTOR_CAPACITY {
+            self.last_memory_writes = memory_writes;
+            self.seen.clear();
+        }
+
+        return !self.seen.insert((reg.r, reg.pc, reg.n, reg.z, reg.p));
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    window: VecDeque<u16>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    pub fn push(&mut self, pc: u16) {
+        if self.window.len() == TRACE_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(pc);
+    }
+
+    pub fn to_vec(&self) -> Vec<u16> {
+        return self.window.iter().copied().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::{RunOutcome, VM};
+    use crate::vm::vm::tests::run_with;
+    use super::*;
+
+    #[test]
+    fn test_halts_within_budget() {
+        let (_, _, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+        add r1, r1, #1
+        halt
+        .END", |vm, _| vm.set_budget(Budget { max_instructions: Some(10), ..Budget::new() }));
+
+        assert_eq!(outcome, RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let (_, _, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+loop    add r1, r1, #1
+        br loop
+        .END", |vm, _| vm.set_budget(Budget { max_instructions: Some(100), ..Budget::new() }));
+
+        match outcome {
+            RunOutcome::BudgetExceeded(exceeded) => {
+                assert_eq!(exceeded.reason, BudgetReason::Instructions(100));
+                assert_eq!(exceeded.instructions, 100);
+                assert_eq!(exceeded.last_pc, 0x3001);
+                assert_eq!(exceeded.recent_trace.len(), TRACE_WINDOW);
+                assert_eq!(exceeded.recent_trace[TRACE_WINDOW - 2..], [0x3000, 0x3001]);
+                assert!(exceeded.generate_msg().contains("Last PC: x3001"));
+            },
+            _ => panic!("expected the instruction budget to be exceeded"),
+        }
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let (_, _, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+loop    add r1, r1, #1
+        br loop
+        .END", |vm, _| vm.set_budget(Budget { max_duration: Some(Duration::from_millis(20)), ..Budget::new() }));
+
+        match outcome {
+            RunOutcome::BudgetExceeded(exceeded) => {
+                assert_eq!(exceeded.reason, BudgetReason::Time(Duration::from_millis(20)));
+            },
+            _ => panic!("expected the time budget to be exceeded"),
+        }
+    }
+
+    #[test]
+    fn test_detect_tight_loop() {
+        let (_, _, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+        add r1, r1, #1
+loop    and r2, r2, #0
+        br loop
+        .END", |vm, _| vm.set_budget(Budget { detect_loops: true, ..Budget::new() }));
+
+        match outcome {
+            RunOutcome::BudgetExceeded(exceeded) => {
+                assert_eq!(exceeded.reason, BudgetReason::InfiniteLoop);
+                assert!(exceeded.instructions < 10);
+            },
+            _ => panic!("expected the loop to be detected"),
+        }
+    }
+
+    #[test]
+    fn test_counting_loop_is_not_flagged() {
+        let (_, _, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+        add r1, r1, #10
+loop    add r1, r1, #-1
+        brp loop
+        halt
+        .END", |vm, _| vm.set_budget(Budget { detect_loops: true, ..Budget::new() }));
+
+        assert_eq!(outcome, RunOutcome::Halted);
+    }
+}
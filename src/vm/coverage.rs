@@ -0,0 +1,218 @@
+use crate::asm::source_map::SourceMap;
+use std::collections::{BTreeMap, HashMap};
+
+/*
+Records how many times each address was executed by the VM.
+Combined with the assembler's `SourceMap`, it can be turned into
+per-line hit counts with `CoverageReport`.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    hits: HashMap<u16, u64>,
+}
+
+#[allow(dead_code)]
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            hits: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, address: u16) {
+        *self.hits.entry(address).or_insert(0) += 1;
+    }
+
+    pub fn get_hits(&self, address: u16) -> u64 {
+        return *self.hits.get(&address).unwrap_or(&0);
+    }
+
+    pub fn was_executed(&self, address: u16) -> bool {
+        return self.hits.contains_key(&address);
+    }
+
+    pub fn executed_addresses(&self) -> Vec<u16> {
+        let mut addresses: Vec<u16> = self.hits.keys().copied().collect();
+        addresses.sort();
+        return addresses;
+    }
+
+    /* Adds the hits from `other`, e.g. to combine the coverage of several test runs. */
+    pub fn merge(&mut self, other: &Coverage) {
+        for (address, hits) in other.hits.iter() {
+            *self.hits.entry(*address).or_insert(0) += hits;
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct CoverageReport<'a> {
+    source: &'a str,
+    source_map: &'a SourceMap,
+    coverage: &'a Coverage,
+}
+
+#[allow(dead_code)]
+impl<'a> CoverageReport<'a> {
+    pub fn new(source: &'a str, source_map: &'a SourceMap, coverage: &'a Coverage) -> CoverageReport<'a> {
+        CoverageReport {
+            source,
+            source_map,
+            coverage,
+        }
+    }
+
+    /*
+    Hit counts for every line that contains an instruction. A line that
+    emits several instructions reports its most executed one.
+    */
+    pub fn line_hits(&self) -> BTreeMap<usize, u64> {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+
+        // Only the main file is annotated, not the files it includes
+        for (address, line) in self.source_map.instructions().filter(|(_, line)| line.file == 0) {
+            let hits = self.coverage.get_hits(*address);
+            let entry = lines.entry(line.line_num).or_insert(0);
+            *entry = (*entry).max(hits);
+        }
+
+        return lines;
+    }
+
+    /* Every instruction that was never executed, as `(address, line_num)`. */
+    pub fn unreached(&self) -> Vec<(u16, usize)> {
+        return self.source_map
+            .instructions()
+            .filter(|(address, _)| !self.coverage.was_executed(**address))
+            .map(|(address, line)| (*address, line.line_num))
+            .collect();
+    }
+
+    /*
+    The source file with a hit count in front of every line, in the style of gcov.
+    `-` marks lines without instructions and `#####` marks instructions that never ran.
+    */
+    pub fn annotated_source(&self) -> String {
+        let line_hits = self.line_hits();
+        let mut output = String::new();
+
+        for (i, line) in self.source.lines().enumerate() {
+            let count = match line_hits.get(&(i + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string(),
+            };
+            output.push_str(&format!("{:>8} | {:>4} | {}\n", count, i + 1, line));
+        }
+
+        return output;
+    }
+
+    /* An lcov tracefile (`.info`) for a single source file. */
+    pub fn lcov(&self, source_name: &str) -> String {
+        let line_hits = self.line_hits();
+        let mut output = format!("TN:\nSF:{}\n", source_name);
+
+        for (line_num, hits) in line_hits.iter() {
+            output.push_str(&format!("DA:{},{}\n", line_num, hits));
+        }
+
+        let lines_hit = line_hits.values().filter(|hits| **hits > 0).count();
+        output.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", line_hits.len(), lines_hit));
+
+        return output;
+    }
+
+    /* `(lines executed, lines with instructions)` */
+    pub fn lines_executed(&self) -> (usize, usize) {
+        let line_hits = self.line_hits();
+        let lines_hit = line_hits.values().filter(|hits| **hits > 0).count();
+        return (lines_hit, line_hits.len());
+    }
+
+    pub fn summary(&self) -> String {
+        let line_hits = self.line_hits();
+        let lines_hit = line_hits.values().filter(|hits| **hits > 0).count();
+        let unreached = self.unreached();
+        let lines: Vec<&str> = self.source.lines().collect();
+
+        let mut output = format!("{}/{} instruction lines executed\n", lines_hit, line_hits.len());
+
+        if unreached.is_empty() {
+            return output;
+        }
+
+        output.push_str("Never reached:\n");
+        for (address, line_num) in unreached {
+            let content = lines.get(line_num.wrapping_sub(1)).map(|l| l.trim()).unwrap_or("");
+            output.push_str(&format!("\t{:#06x}\tline {}:\t{}\n", address, line_num, content));
+        }
+
+        return output;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::VM;
+    use crate::vm::vm::tests::run_with;
+    use super::*;
+
+    const FILE: &str = ".ORIG x3000
+        add r1, r1, #1
+        brp skip
+        add r2, r2, #1  ; never runs
+skip    add r3, r3, #1
+        halt
+        .END";
+
+    #[test]
+    fn test_line_hits() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_coverage());
+        let report = CoverageReport::new(FILE, &asm.source_map, vm.get_coverage().unwrap());
+        let hits = report.line_hits();
+
+        assert_eq!(hits.get(&2), Some(&1));
+        assert_eq!(hits.get(&3), Some(&1));
+        assert_eq!(hits.get(&4), Some(&0));
+        assert_eq!(hits.get(&5), Some(&1));
+        assert_eq!(hits.get(&6), Some(&1));
+        assert_eq!(hits.get(&1), None);
+    }
+
+    #[test]
+    fn test_unreached() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_coverage());
+        let report = CoverageReport::new(FILE, &asm.source_map, vm.get_coverage().unwrap());
+
+        assert_eq!(report.unreached(), vec![(0x3002, 4)]);
+        assert_eq!(report.lines_executed(), (4, 5));
+        assert!(report.summary().contains("4/5 instruction lines executed"));
+        assert!(report.annotated_source().contains("#####"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let (_, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_coverage());
+        let mut coverage = Coverage::new();
+        coverage.record(0x3002);
+        coverage.merge(vm.get_coverage().unwrap());
+        coverage.merge(vm.get_coverage().unwrap());
+
+        assert_eq!(coverage.get_hits(0x3000), 2);
+        assert_eq!(coverage.get_hits(0x3002), 1);
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_coverage());
+        let report = CoverageReport::new(FILE, &asm.source_map, vm.get_coverage().unwrap());
+        let lcov = report.lcov("test.asm");
+
+        assert!(lcov.starts_with("TN:\nSF:test.asm\n"));
+        assert!(lcov.contains("DA:4,0\n"));
+        assert!(lcov.contains("LF:5\nLH:4\n"));
+        assert!(lcov.ends_with("end_of_record\n"));
+    }
+}
@@ -0,0 +1,242 @@
+use super::instructions::Instruction;
+use super::memory::Memory;
+use super::registers::Registers;
+use crate::asm::asm_ins::{OpcodeIns, OperandType};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub const EXTENSION_OPCODE: u16 = 0b1101;
+const OPERAND_BITS: u8 = 12;
+
+/*
+A course-defined instruction on the reserved opcode 1101. One definition
+is given to both the assembler (`Asm::set_extension`) and the VM
+(`VM::set_extension`), so the mnemonic, the operand format, the encoding
+and what the instruction does can never disagree.
+
+Operands are packed from bit 11 downwards, in the order they are written:
+
+    MUL R1, R2, R3     [Reg, Reg, Reg]      | 1101 | 001 | 010 | 011 | 000 |
+    SHF R1, R2, #-3    [Reg, Reg, Imm(6)]   | 1101 | 001 | 010 | 111101   |
+
+The closure gets the decoded operands: register numbers for `Reg`, and
+sign extended values for `Imm` and `Label` (a PC-relative offset, like LD).
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtensionOperand {
+    Reg,
+    Imm(u8),
+    Label(u8),
+}
+
+pub type ExtensionFn = Arc<dyn Fn(&[u16], &mut Registers, &mut Memory) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Extension {
+    pub name: String,
+    pub operands: Vec<ExtensionOperand>,
+    execute: ExtensionFn,
+}
+
+#[allow(dead_code)]
+impl ExtensionOperand {
+    pub fn width(&self) -> u8 {
+        match self {
+            ExtensionOperand::Reg => return 3,
+            ExtensionOperand::Imm(width) | ExtensionOperand::Label(width) => return *width,
+        }
+    }
+
+    pub fn as_operand_type(&self) -> OperandType {
+        match self {
+            ExtensionOperand::Reg => return OperandType::Reg,
+            ExtensionOperand::Imm(_) => return OperandType::Imm,
+            ExtensionOperand::Label(_) => return OperandType::Label,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Extension {
+    pub fn new<F>(name: &str, operands: Vec<ExtensionOperand>, execute: F) -> Result<Extension, String>
+    where
+        F: Fn(&[u16], &mut Registers, &mut Memory) + Send + Sync + 'static,
+    {
+        let name = name.to_uppercase();
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("`{}` is not a valid instruction name, it may only contain letters", name));
+        }
+        if name.len() >= 2 && OpcodeIns::from(&name) != OpcodeIns::INVALID {
+            return Err(format!("`{}` is already the name of an instruction", name));
+        }
+        // The syntax checker allows at most three operands on a line
+        if operands.len() > 3 {
+            return Err(format!("`{}` has {} operands, but an instruction can have at most 3", name, operands.len()));
+        }
+        if operands.iter().any(|operand| operand.width() == 0) {
+            return Err(format!("every operand of `{}` needs at least one bit", name));
+        }
+        let bits: u32 = operands.iter().map(|operand| operand.width() as u32).sum();
+        if bits > OPERAND_BITS as u32 {
+            return Err(format!("the operands of `{}` need {} bits, but only {} are available", name, bits, OPERAND_BITS));
+        }
+
+        return Ok(Extension {
+            name,
+            operands,
+            execute: Arc::new(execute),
+        });
+    }
+
+    pub fn get_expected_operands(&self) -> VecDeque<OperandType> {
+        return self.operands.iter().map(|operand| operand.as_operand_type()).collect();
+    }
+
+    /* The width of the immediate value at operand `index`, if it is one. */
+    pub fn get_immediate_value_width(&self, index: usize) -> Option<i32> {
+        match self.operands.get(index) {
+            Some(ExtensionOperand::Imm(width)) | Some(ExtensionOperand::Label(width)) => return Some(*width as i32),
+            _ => return None,
+        }
+    }
+
+    /*
+    Builds the instruction word. `values` are register numbers, immediate
+    values or PC offsets, in operand order.
+    */
+    pub fn encode(&self, values: &[u16]) -> u16 {
+        let mut output = EXTENSION_OPCODE << OPERAND_BITS;
+        let mut shift = OPERAND_BITS;
+
+        for (operand, value) in self.operands.iter().zip(values) {
+            let width = operand.width();
+            shift -= width;
+            output |= (value & ((1 << width) - 1)) << shift;
+        }
+
+        return output;
+    }
+
+    /* The operands of an instruction word, with `Imm` and `Label` sign extended. */
+    pub fn decode(&self, value: u16) -> Vec<u16> {
+        let mut output = vec![];
+        let mut shift = OPERAND_BITS;
+
+        for operand in self.operands.iter() {
+            let width = operand.width();
+            shift -= width;
+            let field = (value >> shift) & ((1 << width) - 1);
+
+            match operand {
+                ExtensionOperand::Reg => output.push(field),
+                _ => output.push(sign_extend(field, width)),
+            }
+        }
+
+        return output;
+    }
+}
+
+impl Instruction for Extension {
+    fn exe(&self, value: u16, reg: &mut Registers, mem: &mut Memory) {
+        let operands = self.decode(value);
+        (self.execute)(&operands, reg, mem);
+    }
+}
+
+fn sign_extend(value: u16, width: u8) -> u16 {
+    let shift = 16 - width as u32;
+    return (((value << shift) as i16) >> shift) as u16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::{RunOutcome, VM};
+    use crate::vm::vm::tests::run_with;
+
+    fn nothing(_: &[u16], _: &mut Registers, _: &mut Memory) {}
+
+    #[test]
+    fn test_encode_and_decode() {
+        let shf = Extension::new("shf", vec![ExtensionOperand::Reg, ExtensionOperand::Reg, ExtensionOperand::Imm(6)], nothing).unwrap();
+
+        assert_eq!(shf.name, "SHF");
+        assert_eq!(shf.encode(&[1, 2, (-3_i16) as u16]), 0b1101_001_010_111101);
+        assert_eq!(shf.decode(0b1101_001_010_111101), vec![1, 2, 0xFFFD]);
+        assert_eq!(shf.get_immediate_value_width(2), Some(6));
+        assert_eq!(shf.get_immediate_value_width(0), None);
+    }
+
+    #[test]
+    fn test_invalid_extensions() {
+        assert!(Extension::new("add", vec![], nothing).is_err());
+        assert!(Extension::new("brz", vec![], nothing).is_err());
+        assert!(Extension::new("mul2", vec![], nothing).is_err());
+        assert!(Extension::new("m", vec![], nothing).is_ok());
+        assert!(Extension::new("big", vec![ExtensionOperand::Reg, ExtensionOperand::Imm(10)], nothing).is_err());
+        assert!(Extension::new("zero", vec![ExtensionOperand::Imm(0)], nothing).is_err());
+    }
+
+    #[test]
+    fn test_mul() {
+        let mul = Extension::new("mul", vec![ExtensionOperand::Reg, ExtensionOperand::Reg, ExtensionOperand::Reg], |ops, reg, _| {
+            let product = reg.get(ops[1] as usize).wrapping_mul(reg.get(ops[2] as usize));
+            reg.set(ops[0] as usize, product);
+        }).unwrap();
+
+        let mut asm = Asm::new();
+        asm.set_extension(mul.clone()).unwrap();
+        let (_, vm, outcome) = run_with(asm, VM::with_input(""), ".ORIG x3000
+        add r0, r0, #6
+        add r1, r1, #-7
+        MUL r2, r0, r1
+        halt
+        .END", |vm, _| vm.set_extension(mul).unwrap());
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(vm.get_memory().get(0x3002), 0b1101_010_000_001_000);
+        assert_eq!(vm.get_registers().r[2] as i16, -42);
+    }
+
+    #[test]
+    fn test_label_and_immediate_operands() {
+        // Adds an immediate value to the word at a label
+        let addm = Extension::new("addm", vec![ExtensionOperand::Imm(3), ExtensionOperand::Label(9)], |ops, reg, mem| {
+            let address = reg.pc.wrapping_add(ops[1]);
+            mem.set(address, mem.get(address).wrapping_add(ops[0]));
+        }).unwrap();
+
+        let mut asm = Asm::new();
+        asm.set_extension(addm.clone()).unwrap();
+        let (asm, vm, outcome) = run_with(asm, VM::with_input(""), ".ORIG x3000
+        addm #-2, count
+        addm #3, count
+        halt
+count   .FILL #10
+        .END", |vm, _| vm.set_extension(addm).unwrap());
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(vm.get_memory().get(asm.get_symbol_address("count").unwrap()), 11);
+    }
+
+    #[test]
+    fn test_extension_operands_are_checked() {
+        let addm = Extension::new("addm", vec![ExtensionOperand::Imm(3), ExtensionOperand::Label(9)], nothing).unwrap();
+        let mut asm = Asm::new();
+        asm.set_extension(addm).unwrap();
+
+        asm.run(".ORIG x3000\naddm #4, count\ncount .FILL #0\n.END".to_string());
+        assert_eq!(asm.errors.len(), 1);
+        assert_eq!(asm.errors[0].code, "SM015");
+
+        asm.run(".ORIG x3000\naddm r1, count\ncount .FILL #0\n.END".to_string());
+        assert_eq!(asm.errors[0].code, "SM008");
+
+        // Without the extension, the mnemonic is just a label
+        assert!(Asm::new().run(".ORIG x3000\naddm #1, count\ncount .FILL #0\n.END".to_string()).is_empty());
+    }
+}
@@ -7,7 +7,7 @@ Uses the command pattern to execute functions dynamically
 */
 
 #[allow(dead_code)]
-pub trait Instruction {
+pub trait Instruction: Send {
     /*
     value is the raw instruction interpreted from the asm,
     *excluding* the opcode.
@@ -63,12 +63,12 @@ impl Instruction for Add {
                 let v1 = reg.get(sr1 as usize);
                 let v2 = reg.get(sr2 as usize);
         
-                new_value = v1 + v2;
+                new_value = v1.wrapping_add(v2);
             },
             1 => {
                 let reg_val = reg.get(sr1 as usize);
                 let imm_val = get_offset(value, 5);
-                new_value = (reg_val as i16 + imm_val as i16) as u16;
+                new_value = reg_val.wrapping_add(imm_val);
             }
             _ => unreachable!()
         }
@@ -112,9 +112,8 @@ impl Instruction for And {
                 new_value = v1 & v2;
             },
             1 => {
-                i -= code >> 5;
                 let reg_val = reg.get(sr1 as usize);
-                let imm_val = i;
+                let imm_val = get_offset(value, 5);
                 new_value = reg_val & imm_val;
             },
             _ => {
@@ -134,8 +133,6 @@ impl Instruction for Br {
               | ---- --- --------- |
               | op   nzp pcoffset9 |
         */
-        print!("IN BR");
-        print!(" - {:#018b}\n", value);
         let n = get_bit_index(value, 11);
         let z = get_bit_index(value, 10);
         let p = get_bit_index(value, 9);
@@ -144,7 +141,7 @@ impl Instruction for Br {
             (n == 1 && reg.n) ||
             (z == 1 && reg.z) ||
             (p == 1 && reg.p) {
-            reg.pc += get_offset(value, 9);
+            reg.pc = get_pcoffset_location(reg, get_offset(value, 9));
         }
     }
 }
@@ -184,13 +181,12 @@ impl Instruction for Jsr {
 
         match code {
             0 => {
-                let offset_reg = value >> 6;
-                let offset = reg.r[offset_reg as usize];
-                reg.pc += offset;
+                let base_reg = value >> 6;
+                reg.pc = reg.r[base_reg as usize];
             },
             1 => {
                 let offset = get_offset(value, 11);
-                reg.pc = offset;
+                reg.pc = get_pcoffset_location(reg, offset);
             },
             _ => unreachable!(),
         }
@@ -211,8 +207,9 @@ impl Instruction for Ld {
         let pcoffset9 = get_offset(value, 9);
 
         let relative_pc_address = get_pcoffset_location(reg, pcoffset9);
-        let new_value = mem.get(relative_pc_address);
+        let new_value = mem.read(relative_pc_address);
         reg.set(dr as usize, new_value);
+        set_nzp(reg, new_value);
     }
 }
 
@@ -223,12 +220,13 @@ impl Instruction for Ldi {
               | ---- --- --------- |
               | op   dr  pcoffset9 |
         */
-        let dr = value << 9;
-        let ptr = get_offset(value, 9);
+        let dr = value >> 9;
+        let pcoffset9 = get_offset(value, 9);
 
-        let address = mem.get(ptr);
-        let new_value = mem.get(address);
+        let address = mem.read(get_pcoffset_location(reg, pcoffset9));
+        let new_value = mem.read(address);
         reg.set(dr as usize, new_value);
+        set_nzp(reg, new_value);
     }
 }
 
@@ -245,9 +243,10 @@ impl Instruction for Ldr {
         let base_r = buffer >> 6;
         let offset = get_offset(value, 6);
 
-        let address = mem.get(base_r) + offset;
-        let new_value = mem.get(address);
+        let address = reg.get(base_r as usize).wrapping_add(offset);
+        let new_value = mem.read(address);
         reg.set(dr as usize, new_value);
+        set_nzp(reg, new_value);
     }
 }
 
@@ -308,8 +307,9 @@ impl Instruction for St {
         */
         let sr = value >> 9;
         let pcoffset9 = get_offset(value, 9);
+        let location = get_pcoffset_location(reg, pcoffset9);
 
-        mem.set(reg.pc + pcoffset9, reg.get(sr as usize));
+        mem.set(location, reg.get(sr as usize));
     }
 }
 
@@ -322,7 +322,7 @@ impl Instruction for Sti {
         */
         let sr = value >> 9;
         let pcoffset9 = get_offset(value, 9);
-        let indirect = mem.get(reg.pc + pcoffset9);
+        let indirect = mem.read(get_pcoffset_location(reg, pcoffset9));
 
         mem.set(indirect, reg.get(sr as usize));
     }
@@ -342,7 +342,7 @@ impl Instruction for Str {
         let base_r = buffer >> 6;
         let offset6 = get_offset(buffer, 6);
 
-        mem.set(reg.get(base_r as usize) + offset6, reg.get(sr as usize));
+        mem.set(reg.get(base_r as usize).wrapping_add(offset6), reg.get(sr as usize));
     }
 }
 
@@ -353,20 +353,21 @@ impl Instruction for Trap {
                | ---- ---- -------- |
                | op        trapvec8 |
         */
-        let code = get_offset(value, 8);
-
-        match code {
-            20 => self.get_c(reg),
-            21 => self.out(reg),
-            22 => self.put_s(reg, mem),
-            23 => self.r#in(reg, mem),
-            25 => self.halt(reg),
-            _ => unreachable!(),
+        let trapvect8 = value & 0xFF;
+
+        match trapvect8 {
+            0x20 => self.get_c(reg, mem),
+            0x21 => self.out(reg, mem),
+            0x22 => self.put_s(reg, mem),
+            0x23 => self.r#in(reg, mem),
+            0x24 => self.put_sp(reg, mem),
+            0x25 => self.halt(reg),
+            _ => self.call_service_routine(trapvect8, reg, mem),
         }
     }
 }
 
-fn get_offset(mut value: u16, num_bits: i32) -> u16 {
+pub fn get_offset(mut value: u16, num_bits: i32) -> u16 {
     /*
     Every number passed here is a 2's complement signed integer.
     Therefore, we need to check if the right-most bit is a `1`.
@@ -396,11 +397,11 @@ fn get_offset(mut value: u16, num_bits: i32) -> u16 {
     return buf;
 }
 
-fn get_bit_index(value: u16, index: i32) -> u16 {
+pub fn get_bit_index(value: u16, index: i32) -> u16 {
     return value >> index & 1;
 }
 
-fn set_nzp(reg: &mut Registers, value: u16) {
+pub fn set_nzp(reg: &mut Registers, value: u16) {
     reg.n = false;
     reg.z = false;
     reg.p = false;
@@ -418,7 +419,7 @@ fn set_nzp(reg: &mut Registers, value: u16) {
     }
 }
 
-fn get_pcoffset_location(reg: &mut Registers, value: u16) -> u16 {
+pub fn get_pcoffset_location(reg: &mut Registers, value: u16) -> u16 {
     if value == 0 || value as i16 > 0 {
         return reg.pc + value;
     }
@@ -500,6 +501,12 @@ mod test {
 
         assert!(reg.get(2) == 9);
 
+        reg.set(1, 35);
+        ins = 0b0000_010_001_1_00000;
+        and.exe(ins, &mut reg, &mut mem);
+
+        assert!(reg.get(2) == 0);
+
         // TODO: Account for NZP bits
     }
 
@@ -0,0 +1,270 @@
+use super::instructions::{get_bit_index, get_offset, get_pcoffset_location, set_nzp, Instruction};
+use super::memory::Memory;
+use super::registers::Registers;
+
+/*
+The LC-3b instructions that differ from the LC-3. ADD, AND, JMP/RET, RTI,
+NOT (which is XOR with #-1) and TRAP are shared. Opcodes 1010 and 1011
+are unused on the LC-3b.
+*/
+#[allow(dead_code)]
+pub struct Br;
+pub struct Jsr;
+pub struct Lea;
+pub struct Ldb;
+pub struct Stb;
+pub struct Ldw;
+pub struct Stw;
+pub struct Xor;
+pub struct Shf;
+
+impl Instruction for Br {
+    fn exe(&self, value: u16, reg: &mut Registers, _mem: &mut Memory) {
+        /*
+        BR  - | 0000 000 000000000 |
+              | ---- --- --------- |
+              | op   nzp pcoffset9 |   PC <- PC + LSHF(SEXT(pcoffset9), 1)
+        */
+        let n = get_bit_index(value, 11);
+        let z = get_bit_index(value, 10);
+        let p = get_bit_index(value, 9);
+
+        if (n == 0 && z == 0 && p == 0) ||
+            (n == 1 && reg.n) ||
+            (z == 1 && reg.z) ||
+            (p == 1 && reg.p) {
+            reg.pc = get_pcoffset_location(reg, get_offset(value, 9) << 1);
+        }
+    }
+}
+
+impl Instruction for Jsr {
+    fn exe(&self, value: u16, reg: &mut Registers, _mem: &mut Memory) {
+        /*
+        JSR - | 0100 1 00000000000   |
+              | ---- - -----------   |
+              | op   c pcoffset11    |   PC <- PC + LSHF(SEXT(pcoffset11), 1)
+              +----------------------+
+        JSRR- | 0100 0 00 000 000000 |
+              | ---- - -- --- ------ |
+              | op   c -- br  ------ |
+        */
+        let inc_pc = reg.pc;
+
+        if get_bit_index(value, 11) == 1 {
+            reg.pc = get_pcoffset_location(reg, get_offset(value, 11) << 1);
+        } else {
+            reg.pc = reg.get((value >> 6 & 0b111) as usize);
+        }
+
+        reg.r[7] = inc_pc;
+    }
+}
+
+impl Instruction for Lea {
+    fn exe(&self, value: u16, reg: &mut Registers, _mem: &mut Memory) {
+        /*
+        LEA - | 1110 000 000000000 |
+              | ---- --- --------- |
+              | op   dr  pcoffset9 |   DR <- PC + LSHF(SEXT(pcoffset9), 1)
+
+        Unlike the LC-3, the LC-3b LEA does not set the condition codes.
+        */
+        let dr = value >> 9;
+        let address = get_pcoffset_location(reg, get_offset(value, 9) << 1);
+        reg.set(dr as usize, address);
+    }
+}
+
+impl Instruction for Ldb {
+    fn exe(&self, value: u16, reg: &mut Registers, mem: &mut Memory) {
+        /*
+        LDB - | 0010 000 000 000000  |
+              | ---- --- --- ------  |
+              | op   dr  br  boffset6|   DR <- SEXT(mem[BaseR + SEXT(boffset6)])
+        */
+        let (dr, base_r) = get_registers(value);
+        let address = reg.get(base_r).wrapping_add(get_offset(value, 6));
+
+        let new_value = mem.read_byte(address) as i8 as i16 as u16;
+        reg.set(dr, new_value);
+        set_nzp(reg, new_value);
+    }
+}
+
+impl Instruction for Stb {
+    fn exe(&self, value: u16, reg: &mut Registers, mem: &mut Memory) {
+        /*
+        STB - | 0011 000 000 000000  |
+              | ---- --- --- ------  |
+              | op   sr  br  boffset6|   mem[BaseR + SEXT(boffset6)] <- SR[7:0]
+        */
+        let (sr, base_r) = get_registers(value);
+        let address = reg.get(base_r).wrapping_add(get_offset(value, 6));
+
+        mem.set_byte(address, (reg.get(sr) & 0xFF) as u8);
+    }
+}
+
+impl Instruction for Ldw {
+    fn exe(&self, value: u16, reg: &mut Registers, mem: &mut Memory) {
+        /*
+        LDW - | 0110 000 000 000000 |
+              | ---- --- --- ------ |
+              | op   dr  br  offset6|   DR <- MEM[BaseR + LSHF(SEXT(offset6), 1)]
+        */
+        let (dr, base_r) = get_registers(value);
+        let address = reg.get(base_r).wrapping_add(get_offset(value, 6) << 1);
+
+        let new_value = mem.read(address & !1);
+        reg.set(dr, new_value);
+        set_nzp(reg, new_value);
+    }
+}
+
+impl Instruction for Stw {
+    fn exe(&self, value: u16, reg: &mut Registers, mem: &mut Memory) {
+        /*
+        STW - | 0111 000 000 000000 |
+              | ---- --- --- ------ |
+              | op   sr  br  offset6|   MEM[BaseR + LSHF(SEXT(offset6), 1)] <- SR
+        */
+        let (sr, base_r) = get_registers(value);
+        let address = reg.get(base_r).wrapping_add(get_offset(value, 6) << 1);
+
+        mem.set(address, reg.get(sr));
+    }
+}
+
+impl Instruction for Xor {
+    fn exe(&self, value: u16, reg: &mut Registers, _mem: &mut Memory) {
+        /*
+        XOR - | 1001 000 000 0 00 000 |
+              | ---- --- --- - -- --- |
+              | op   dr  sr1 -    sr2 |
+              +-----------------------+
+        XOR - | 1001 000 000 1 00000  |
+              | ---- --- --- - -----  |
+              | op   dr  sr1 - imm5   |   NOT DR, SR is XOR DR, SR, #-1
+        */
+        let (dr, sr1) = get_registers(value);
+
+        let operand = if get_bit_index(value, 5) == 1 {
+            get_offset(value, 5)
+        } else {
+            reg.get((value & 0b111) as usize)
+        };
+
+        let new_value = reg.get(sr1) ^ operand;
+        reg.set(dr, new_value);
+        set_nzp(reg, new_value);
+    }
+}
+
+impl Instruction for Shf {
+    fn exe(&self, value: u16, reg: &mut Registers, _mem: &mut Memory) {
+        /*
+        SHF - | 1101 000 000 0 0 0000  |
+              | ---- --- --- - - ----  |
+              | op   dr  sr  a d amount4 |
+
+        d = 0 is LSHF. Otherwise a = 0 is RSHFL and a = 1 is RSHFA.
+        */
+        let (dr, sr) = get_registers(value);
+        let amount = (value & 0xF) as u32;
+        let source = reg.get(sr);
+
+        let new_value = match (get_bit_index(value, 5), get_bit_index(value, 4)) {
+            (_, 0) => source << amount,
+            (0, _) => source >> amount,
+            _ => ((source as i16) >> amount) as u16,
+        };
+
+        reg.set(dr, new_value);
+        set_nzp(reg, new_value);
+    }
+}
+
+/* The register fields in bits [11:9] and [8:6]. */
+fn get_registers(value: u16) -> (usize, usize) {
+    return ((value >> 9 & 0b111) as usize, (value >> 6 & 0b111) as usize);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::asm::Asm;
+    use crate::vm::arch::Architecture;
+    use crate::vm::extension::Extension;
+    use crate::vm::vm::{RunOutcome, VM};
+    use crate::vm::vm::tests::run_with;
+
+    #[test]
+    fn test_bytes_and_words() {
+        let (asm, vm, outcome) = run_with(Asm::with_architecture(Architecture::Lc3b), VM::with_architecture(Architecture::Lc3b), ".ORIG x3000
+        lea r0, data
+        ldb r1, r0, #1
+        ldw r2, r0, #0
+        stb r1, r0, #2
+        stw r2, r0, #2
+        halt
+data    .FILL x80FF
+        .FILL #0
+        .FILL #0
+        .END", |_, _| {});
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        let data = asm.get_symbol_address("data").unwrap();
+        assert_eq!(data, 0x300C);
+        assert_eq!(vm.get_registers().r[1], 0xFF80);
+        assert_eq!(vm.get_registers().r[2], 0x80FF);
+        assert_eq!(vm.get_memory().get(data + 2), 0x0080);
+        assert_eq!(vm.get_memory().get(data + 4), 0x80FF);
+    }
+
+    #[test]
+    fn test_shifts_xor_and_branches() {
+        let (_, vm, outcome) = run_with(Asm::with_architecture(Architecture::Lc3b), VM::with_architecture(Architecture::Lc3b), ".ORIG x3000
+        add r0, r0, #-8
+        lshf r1, r0, #2
+        rshfl r2, r0, #12
+        rshfa r3, r0, #1
+        xor r4, r0, #5
+        not r5, r0
+        and r6, r6, #0
+loop    add r6, r6, #1
+        add r7, r6, #-3
+        brn loop
+        jsr done
+        add r6, r6, #1
+done    halt
+        .END", |_, _| {});
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        let r = vm.get_registers().r;
+        assert_eq!(r[1] as i16, -32);
+        assert_eq!(r[2], 0xF);
+        assert_eq!(r[3] as i16, -4);
+        assert_eq!(r[4] as i16, -8 ^ 5);
+        assert_eq!(r[5], 7);
+        assert_eq!(r[6], 3);
+    }
+
+    #[test]
+    fn test_lc3_only_instructions_are_rejected() {
+        let mut asm = Asm::with_architecture(Architecture::Lc3b);
+
+        // Without LD, `ld` is a label, and the line no longer makes sense
+        asm.run(".ORIG x3000\nld r0, data\ndata .FILL #0\n.END".to_string());
+        assert!(!asm.errors.is_empty());
+
+        asm.run(".ORIG x3001\nhalt\n.END".to_string());
+        assert_eq!(asm.errors[0].code, "SM020");
+
+        asm.run(".ORIG x3000\nlshf r0, r0, #-1\n.END".to_string());
+        assert_eq!(asm.errors[0].code, "SM015");
+
+        let ext = Extension::new("mul", vec![], |_, _, _| {}).unwrap();
+        assert!(asm.set_extension(ext.clone()).is_err());
+        assert!(VM::with_architecture(Architecture::Lc3b).set_extension(ext).is_err());
+    }
+}
@@ -1,8 +1,59 @@
+use super::arch::Architecture;
+use crate::output::{StdIO, SystemIO};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 const POW_2_16: usize = 2_usize.pow(16);
 
+// Memory-mapped device registers
+pub const KBSR: u16 = 0xFE00;
+pub const KBDR: u16 = 0xFE02;
+pub const DSR: u16 = 0xFE04;
+pub const DDR: u16 = 0xFE06;
+const DEVICE_READY: u16 = 0x8000;
+// How many KBSR reads in a row can find no input before polling counts as stuck
+const MAX_EMPTY_POLLS: u32 = 100;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    // The write is blocked and the run stops
+    ReadOnly,
+    // The write goes through, but is reported
+    Watch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteViolation {
+    pub pc: u16,
+    pub address: u16,
+    pub value: u16,
+    pub protection: Protection,
+}
+
+/*
+What the console devices hold besides memory. KBSR and KBDR are worked out
+from the pending input when they are read, so this is all a snapshot needs.
+*/
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct DeviceState {
+    // `None` if the input comes from somewhere that cannot be saved, like stdin
+    pub pending_input: Option<String>,
+    pub input_exhausted: bool,
+    pub empty_polls: u32,
+}
+
 pub struct Memory {
     inner: [u16; POW_2_16],
+    writes: u64,
+    protection: HashMap<u16, Protection>,
+    violations: Vec<(u16, u16, Protection)>,
+    io: Box<dyn SystemIO>,
+    input_exhausted: bool,
+    // KBSR reads in a row that found no input
+    empty_polls: u32,
+    // On the LC-3b, addresses are byte addresses and words are at even ones
+    architecture: Architecture,
 }
 
 #[allow(dead_code)]
@@ -10,26 +61,220 @@ impl Memory {
     pub fn new() -> Memory {
         Memory {
             inner: [0; POW_2_16],
+            writes: 0,
+            protection: HashMap::new(),
+            violations: vec![],
+            io: Box::new(StdIO),
+            input_exhausted: false,
+            empty_polls: 0,
+            architecture: Architecture::Lc3,
         }
     }
 
+    pub fn set_architecture(&mut self, architecture: Architecture) {
+        self.architecture = architecture;
+    }
+
+    pub fn get_architecture(&self) -> Architecture {
+        return self.architecture;
+    }
+
+    /* The address of the word after the one at `loc`. */
+    pub fn next_address(&self, loc: u16) -> u16 {
+        return loc.wrapping_add(self.architecture.word_size());
+    }
+
     pub fn load_file(&mut self, file: Vec<u16>) {
-        let mut mem_i = file[0] as usize; // origin
+        let mut mem_i = file[0]; // origin
         let mut vec_i = 1;
 
         while vec_i < file.len() {
-            self.inner[mem_i] = file[vec_i];
+            self.inner[self.align(mem_i) as usize] = file[vec_i];
             
             vec_i += 1;
-            mem_i += 1;
+            mem_i = self.next_address(mem_i);
+        }
+    }
+
+    /* Word accesses on the LC-3b ignore bit 0 of the address. */
+    fn align(&self, loc: u16) -> u16 {
+        if self.architecture.is_byte_addressable() {
+            return loc & !1;
         }
+        return loc;
     }
 
     pub fn get(&self, loc: u16) -> u16 {
-        return self.inner[loc as usize];
+        return self.inner[self.align(loc) as usize];
+    }
+
+    /* The byte at `loc` on the LC-3b, where the low byte of a word comes first. */
+    pub fn get_byte(&self, loc: u16) -> u8 {
+        return byte_of(self.get(loc), loc);
+    }
+
+    /* Like `get_byte`, but with the side effects of `read`. */
+    pub fn read_byte(&mut self, loc: u16) -> u8 {
+        return byte_of(self.read(self.align(loc)), loc);
+    }
+
+    /* Changes one byte of the word at `loc`, going through `set`. */
+    pub fn set_byte(&mut self, loc: u16, val: u8) {
+        let word = self.get(loc);
+        let word = if loc & 1 == 1 {
+            (word & 0x00FF) | (val as u16) << 8
+        } else {
+            (word & 0xFF00) | val as u16
+        };
+        self.set(self.align(loc), word);
+    }
+
+    /*
+    Like `get`, but reading a device register has the same side effects
+    it would on the LC-3, e.g. reading KBDR takes a character from the input.
+    */
+    pub fn read(&mut self, loc: u16) -> u16 {
+        match loc {
+            KBSR => {
+                if self.io.has_char() {
+                    self.empty_polls = 0;
+                    return DEVICE_READY;
+                }
+                // Nothing else can ever arrive, so a program that keeps polling KBSR
+                // would never stop. A single check is fine.
+                self.empty_polls += 1;
+                if self.empty_polls >= MAX_EMPTY_POLLS {
+                    self.input_exhausted = true;
+                }
+                return 0;
+            },
+            KBDR => return self.read_char().map(|c| c as u16 & 0xFF).unwrap_or(0),
+            DSR => return DEVICE_READY,
+            _ => return self.get(loc),
+        }
     }
 
     pub fn set(&mut self, loc: u16, val: u16) {
+        let loc = self.align(loc);
+        if loc == DDR {
+            self.io.print_char((val & 0xFF) as u8 as char);
+        }
+
+        if let Some(protection) = self.protection.get(&loc) {
+            self.violations.push((loc, val, *protection));
+            if *protection == Protection::ReadOnly {
+                return;
+            }
+        }
+
         self.inner[loc as usize] = val;
+        self.writes += 1;
+    }
+
+    /*
+    Writes words starting at `start` without going through the write
+    protection, the same way `load_file` does.
+    */
+    pub fn load_words(&mut self, start: u16, words: &[u16]) {
+        let mut loc = start;
+        for word in words.iter() {
+            self.inner[self.align(loc) as usize] = *word;
+            loc = self.next_address(loc);
+        }
+        self.writes += 1;
+    }
+
+    pub fn set_io(&mut self, io: Box<dyn SystemIO>) {
+        self.io = io;
+        self.clear_input_exhausted();
+    }
+
+    pub fn get_output(&self) -> Option<&str> {
+        return self.io.get_output();
+    }
+
+    pub fn print(&mut self, output: &str) {
+        for c in output.chars() {
+            self.io.print_char(c);
+        }
+    }
+
+    /* The next input character, or `None` if the input ran out. */
+    pub fn read_char(&mut self) -> Option<char> {
+        let c = self.io.get_char();
+        if c.is_none() {
+            self.input_exhausted = true;
+        }
+        return c;
+    }
+
+    pub fn is_input_exhausted(&self) -> bool {
+        return self.input_exhausted;
+    }
+
+    pub fn get_device_state(&self) -> DeviceState {
+        DeviceState {
+            pending_input: self.io.get_input(),
+            input_exhausted: self.input_exhausted,
+            empty_polls: self.empty_polls,
+        }
+    }
+
+    /*
+    Puts the devices back the way `state` has them. The pending input is
+    only restored if the IO can take it.
+    */
+    pub fn set_device_state(&mut self, state: &DeviceState) {
+        if let Some(input) = &state.pending_input {
+            self.io.set_input(input);
+        }
+        self.input_exhausted = state.input_exhausted;
+        self.empty_polls = state.empty_polls;
+    }
+
+    /* Forgets that the input ran out, once that has been reported. */
+    pub fn clear_input_exhausted(&mut self) {
+        self.input_exhausted = false;
+        self.empty_polls = 0;
+    }
+
+    pub fn protect(&mut self, loc: u16, protection: Protection) {
+        self.protection.insert(self.align(loc), protection);
+    }
+
+    pub fn unprotect_all(&mut self) {
+        self.protection.clear();
+    }
+
+    /*
+    Every `(address, value, protection)` write that hit a protected
+    address since the last call.
+    */
+    pub fn take_violations(&mut self) -> Vec<(u16, u16, Protection)> {
+        return std::mem::take(&mut self.violations);
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        return &self.inner;
+    }
+
+    pub fn clear(&mut self) {
+        self.inner = [0; POW_2_16];
+        self.writes += 1;
+    }
+
+    /*
+    The number of writes made through `set`, used to tell whether memory
+    changed between two points in time.
+    */
+    pub fn get_write_count(&self) -> u64 {
+        return self.writes;
+    }
+}
+
+fn byte_of(word: u16, loc: u16) -> u8 {
+    if loc & 1 == 1 {
+        return (word >> 8) as u8;
     }
+    return (word & 0xFF) as u8;
 }
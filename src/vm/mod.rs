@@ -2,4 +2,11 @@ pub mod vm;
 pub mod instructions;
 pub mod registers;
 pub mod memory;
-pub mod trap;
\ No newline at end of file
+pub mod trap;
+pub mod coverage;
+pub mod profiler;
+pub mod budget;
+pub mod snapshot;
+pub mod extension;
+pub mod arch;
+pub mod lc3b;
\ No newline at end of file
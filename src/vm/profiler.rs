@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+
+const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR",
+    "RTI", "NOT", "LDI", "STI", "JMP", "RESERVED", "LEA", "TRAP",
+];
+
+const JSR_OPCODE: usize = 4;
+const JMP_OPCODE: usize = 12;
+const R7: u16 = 7;
+const ROOT_NAME: &str = "main";
+const HOTTEST_ADDRESSES_SHOWN: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+/*
+Opt-in execution profiler for the VM.
+
+Subroutines are tracked with a shadow call stack: every JSR/JSRR pushes the
+address it jumped to, and every RET (`JMP R7`) pops it again. The bottom of
+the stack is the address execution started at, which is reported as `main`
+unless a label says otherwise.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    total: u64,
+    opcode_counts: [u64; 16],
+    address_counts: HashMap<u16, u64>,
+    call_stack: Vec<u16>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    folded: HashMap<Vec<u16>, u64>,
+}
+
+#[allow(dead_code)]
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /*
+    `pc` is the address `cmd` was fetched from, and `next_pc` is the
+    value of the PC after `cmd` was executed.
+    */
+    pub fn record(&mut self, pc: u16, cmd: u16, next_pc: u16) {
+        if self.call_stack.is_empty() {
+            self.call_stack.push(pc);
+            self.subroutines.entry(pc).or_default().calls += 1;
+        }
+
+        let opcode = (cmd >> 12) as usize;
+
+        self.total += 1;
+        self.opcode_counts[opcode] += 1;
+        *self.address_counts.entry(pc).or_insert(0) += 1;
+        *self.folded.entry(self.call_stack.clone()).or_insert(0) += 1;
+
+        let current = *self.call_stack.last().unwrap();
+        self.subroutines.entry(current).or_default().exclusive += 1;
+
+        // Recursive calls only count once towards a subroutine's inclusive total
+        let mut seen: Vec<u16> = vec![];
+        for address in self.call_stack.iter() {
+            if !seen.contains(address) {
+                seen.push(*address);
+                self.subroutines.entry(*address).or_default().inclusive += 1;
+            }
+        }
+
+        match opcode {
+            JSR_OPCODE => {
+                self.call_stack.push(next_pc);
+                self.subroutines.entry(next_pc).or_default().calls += 1;
+            },
+            JMP_OPCODE if (cmd >> 6) & 0b111 == R7 && self.call_stack.len() > 1 => {
+                self.call_stack.pop();
+            },
+            _ => {},
+        }
+    }
+
+    pub fn get_total(&self) -> u64 {
+        return self.total;
+    }
+
+    /* Executed instructions per opcode name, skipping opcodes that never ran. */
+    pub fn opcode_mix(&self) -> Vec<(&'static str, u64)> {
+        return OPCODE_NAMES.iter()
+            .zip(self.opcode_counts.iter())
+            .filter(|(_, count)| **count > 0)
+            .map(|(name, count)| (*name, *count))
+            .collect();
+    }
+
+    /* The `n` most executed addresses, most executed first. */
+    pub fn hottest_addresses(&self, n: usize) -> Vec<(u16, u64)> {
+        let mut addresses: Vec<(u16, u64)> = self.address_counts.iter()
+            .map(|(address, count)| (*address, *count))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses.truncate(n);
+        return addresses;
+    }
+
+    pub fn get_subroutine(&self, address: u16) -> Option<&SubroutineStats> {
+        return self.subroutines.get(&address);
+    }
+
+    /*
+    A text table of everything the profiler collected. `labels` maps
+    addresses to the names they should be reported with.
+    */
+    pub fn report(&self, labels: &HashMap<u16, String>) -> String {
+        let mut output = format!("Instructions executed: {}\n", self.total);
+
+        output.push_str("\nOpcode mix:\n");
+        for (name, count) in self.opcode_mix() {
+            output.push_str(&format!("  {:<8} {:>10} {:>7.2}%\n", name, count, self.percent(count)));
+        }
+
+        output.push_str("\nHottest addresses:\n");
+        for (address, count) in self.hottest_addresses(HOTTEST_ADDRESSES_SHOWN) {
+            let label = labels.get(&address).map(|l| l.as_str()).unwrap_or("");
+            output.push_str(&format!("  x{:04X}  {:<16} {:>10}\n", address, label, count));
+        }
+
+        output.push_str("\nSubroutines:\n");
+        output.push_str(&format!("  {:<16} {:>8} {:>10} {:>10}\n", "name", "calls", "inclusive", "exclusive"));
+
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (address, stats) in subroutines {
+            output.push_str(&format!(
+                "  {:<16} {:>8} {:>10} {:>10}\n",
+                self.get_name(*address, labels),
+                stats.calls,
+                stats.inclusive,
+                stats.exclusive,
+            ));
+        }
+
+        return output;
+    }
+
+    /*
+    One line per distinct call stack, `main;OUTER;INNER count`, which is
+    the input format of flamegraph.pl and inferno.
+    */
+    pub fn folded_stacks(&self, labels: &HashMap<u16, String>) -> String {
+        let mut lines: BTreeMap<String, u64> = BTreeMap::new();
+
+        for (stack, count) in self.folded.iter() {
+            let names: Vec<String> = stack.iter()
+                .map(|address| self.get_name(*address, labels))
+                .collect();
+            *lines.entry(names.join(";")).or_insert(0) += count;
+        }
+
+        let mut output = String::new();
+        for (stack, count) in lines {
+            output.push_str(&format!("{} {}\n", stack, count));
+        }
+
+        return output;
+    }
+
+    fn get_name(&self, address: u16, labels: &HashMap<u16, String>) -> String {
+        if let Some(label) = labels.get(&address) {
+            return label.clone();
+        }
+        if self.call_stack.first() == Some(&address) {
+            return ROOT_NAME.to_string();
+        }
+        return format!("x{:04X}", address);
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        return count as f64 * 100.0 / self.total as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::asm::Asm;
+    use crate::vm::vm::VM;
+    use crate::vm::vm::tests::run_with;
+    use super::*;
+
+    const FILE: &str = ".ORIG x3000
+        jsr twice
+        jsr once
+        halt
+twice   st r7, save
+        jsr once
+        jsr once
+        ld r7, save
+        ret
+once    add r1, r1, #1
+        ret
+save    .fill #0
+        .END";
+
+    #[test]
+    fn test_counts() {
+        let (_, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_profiler());
+        let profiler = vm.get_profiler().unwrap();
+
+        // main: 3, twice: 5 (x1 call), once: 2 (x3 calls)
+        assert_eq!(profiler.get_total(), 14);
+        assert_eq!(
+            profiler.opcode_mix(),
+            vec![("ADD", 3), ("LD", 1), ("ST", 1), ("JSR", 4), ("JMP", 4), ("TRAP", 1)]
+        );
+        assert_eq!(profiler.hottest_addresses(1), vec![(0x3008, 3)]);
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_profiler());
+        let profiler = vm.get_profiler().unwrap();
+
+        let main = profiler.get_subroutine(0x3000).unwrap();
+        assert_eq!(*main, SubroutineStats { calls: 1, inclusive: 14, exclusive: 3 });
+
+        let twice = profiler.get_subroutine(asm.get_symbol_address("twice").unwrap()).unwrap();
+        assert_eq!(*twice, SubroutineStats { calls: 1, inclusive: 9, exclusive: 5 });
+
+        let once = profiler.get_subroutine(asm.get_symbol_address("once").unwrap()).unwrap();
+        assert_eq!(*once, SubroutineStats { calls: 3, inclusive: 6, exclusive: 6 });
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_profiler());
+        let folded = vm.get_profiler().unwrap().folded_stacks(&asm.get_address_labels());
+
+        assert_eq!(folded, "main 3\nmain;once 2\nmain;twice 5\nmain;twice;once 4\n");
+    }
+
+    #[test]
+    fn test_report() {
+        let (asm, vm, _) = run_with(Asm::new(), VM::new(), FILE, |vm, _| vm.enable_profiler());
+        let report = vm.get_profiler().unwrap().report(&asm.get_address_labels());
+
+        assert!(report.starts_with("Instructions executed: 14\n"));
+        assert!(report.contains("  JSR               4   28.57%\n"));
+        assert!(report.contains("  once                    3          6          6\n"));
+    }
+}
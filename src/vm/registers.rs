@@ -1,4 +1,22 @@
 
+// Register indices, for APIs like `VM::call_subroutine`
+#[allow(dead_code)]
+pub const R0: usize = 0;
+#[allow(dead_code)]
+pub const R1: usize = 1;
+#[allow(dead_code)]
+pub const R2: usize = 2;
+#[allow(dead_code)]
+pub const R3: usize = 3;
+#[allow(dead_code)]
+pub const R4: usize = 4;
+#[allow(dead_code)]
+pub const R5: usize = 5;
+#[allow(dead_code)]
+pub const R6: usize = 6;
+#[allow(dead_code)]
+pub const R7: usize = 7;
+
 pub struct Registers {
     pub r: [u16; 8],
     pub pc: u16,
@@ -42,6 +60,17 @@ impl Registers {
 
         self.r[reg_value] = new_value;
     }
+
+    /* Only the condition codes (bits 2-0) of the PSR are modelled. */
+    pub fn get_psr(&self) -> u16 {
+        return (self.n as u16) << 2 | (self.z as u16) << 1 | self.p as u16;
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.n = psr >> 2 & 1 == 1;
+        self.z = psr >> 1 & 1 == 1;
+        self.p = psr & 1 == 1;
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,327 @@
+use super::memory::{DeviceState, Memory};
+use super::registers::Registers;
+use serde::{Deserialize, Serialize};
+
+// Zero runs shorter than this stay inside a segment instead of splitting it
+const SEGMENT_GAP: usize = 8;
+const MEMORY_SIZE: usize = u16::MAX as usize + 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemorySegment {
+    pub start: u16,
+    pub words: Vec<u16>,
+}
+
+/*
+The full state of the machine at one point in time. Memory is stored as
+segments of non-zero words, since a typical program only touches a tiny part
+of the 64K address space, which keeps the JSON form small enough to share.
+The devices include the input that was not read yet, so a program waiting
+for input continues with the same input after a restore.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub psr: u16,
+    pub halted: bool,
+    pub memory: Vec<MemorySegment>,
+    // Older snapshots have no devices
+    #[serde(default)]
+    pub devices: DeviceState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterChange {
+    pub name: String,
+    pub before: u16,
+    pub after: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryChange {
+    pub start: u16,
+    pub before: Vec<u16>,
+    pub after: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SnapshotDiff {
+    pub registers: Vec<RegisterChange>,
+    pub memory: Vec<MemoryChange>,
+}
+
+#[allow(dead_code)]
+impl Snapshot {
+    pub fn capture(reg: &Registers, mem: &Memory) -> Snapshot {
+        Snapshot {
+            registers: reg.r,
+            pc: reg.pc,
+            psr: reg.get_psr(),
+            halted: reg.halt,
+            memory: Snapshot::get_segments(mem.as_slice()),
+            devices: mem.get_device_state(),
+        }
+    }
+
+    pub fn restore(&self, reg: &mut Registers, mem: &mut Memory) {
+        reg.r = self.registers;
+        reg.pc = self.pc;
+        reg.set_psr(self.psr);
+        reg.halt = self.halted;
+
+        mem.clear();
+        for segment in self.memory.iter() {
+            mem.load_words(segment.start, &segment.words);
+        }
+        mem.set_device_state(&self.devices);
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self)
+            .expect("Snapshot::to_json(): a snapshot only contains numbers, so it should always serialize");
+    }
+
+    pub fn from_json(json: &str) -> Result<Snapshot, String> {
+        let snapshot: Snapshot = serde_json::from_str(json).map_err(|err| format!("the snapshot is not valid: {}", err))?;
+
+        for segment in snapshot.memory.iter() {
+            if segment.start as usize + segment.words.len() > MEMORY_SIZE {
+                return Err(format!("the snapshot is not valid: the segment at x{:04X} goes past xFFFF", segment.start));
+            }
+        }
+        return Ok(snapshot);
+    }
+
+    /* Expands the memory segments back into all 64K words. */
+    pub fn get_memory(&self) -> Vec<u16> {
+        let mut memory = vec![0; MEMORY_SIZE];
+
+        for segment in self.memory.iter() {
+            for (i, word) in segment.words.iter().enumerate() {
+                memory[segment.start as usize + i] = *word;
+            }
+        }
+
+        return memory;
+    }
+
+    /* Everything that differs going from `self` to `other`. */
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+
+        for i in 0..8 {
+            diff.push_register(&format!("R{}", i), self.registers[i], other.registers[i]);
+        }
+        diff.push_register("PC", self.pc, other.pc);
+        diff.push_register("PSR", self.psr, other.psr);
+        diff.push_register("HALT", self.halted as u16, other.halted as u16);
+
+        let before = self.get_memory();
+        let after = other.get_memory();
+        let mut i = 0;
+
+        while i < before.len() {
+            if before[i] == after[i] {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            while i < before.len() && before[i] != after[i] {
+                i += 1;
+            }
+            diff.memory.push(MemoryChange {
+                start: start as u16,
+                before: before[start..i].to_vec(),
+                after: after[start..i].to_vec(),
+            });
+        }
+
+        return diff;
+    }
+
+    fn get_segments(memory: &[u16]) -> Vec<MemorySegment> {
+        let mut segments: Vec<MemorySegment> = vec![];
+        let mut i = 0;
+
+        while i < memory.len() {
+            if memory[i] == 0 {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            let mut end = i;
+            while i < memory.len() && i - end <= SEGMENT_GAP {
+                if memory[i] != 0 {
+                    end = i;
+                }
+                i += 1;
+            }
+
+            segments.push(MemorySegment {
+                start: start as u16,
+                words: memory[start..=end].to_vec(),
+            });
+            i = end + 1;
+        }
+
+        return segments;
+    }
+}
+
+#[allow(dead_code)]
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        return self.registers.is_empty() && self.memory.is_empty();
+    }
+
+    pub fn generate_msg(&self) -> String {
+        if self.is_empty() {
+            return "No differences\n".to_string();
+        }
+
+        let mut msg = String::new();
+
+        for change in self.registers.iter() {
+            msg += &format!("{}: x{:04X} -> x{:04X}\n", change.name, change.before, change.after);
+        }
+
+        for change in self.memory.iter() {
+            let end = change.start as usize + change.before.len() - 1;
+            if change.before.len() == 1 {
+                msg += &format!("x{:04X}:", change.start);
+            } else {
+                msg += &format!("x{:04X}-x{:04X}:", change.start, end);
+            }
+            for (before, after) in change.before.iter().zip(change.after.iter()) {
+                msg += &format!(" x{:04X}->x{:04X}", before, after);
+            }
+            msg += "\n";
+        }
+
+        return msg;
+    }
+
+    fn push_register(&mut self, name: &str, before: u16, after: u16) {
+        if before != after {
+            self.registers.push(RegisterChange {
+                name: name.to_string(),
+                before,
+                after,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::BufferedIO;
+
+    fn get_machine() -> (Registers, Memory) {
+        let mut reg = Registers::new();
+        let mut mem = Memory::new();
+
+        reg.r[0] = 42;
+        reg.r[7] = 0x3005;
+        reg.pc = 0x3001;
+        reg.n = true;
+
+        mem.set(0x3000, 0x1021);
+        mem.set(0x3001, 0xF025);
+        mem.set(0x4000, 7);
+        mem.set(0xFFFF, 1);
+
+        return (reg, mem);
+    }
+
+    #[test]
+    fn test_segments() {
+        let (reg, mem) = get_machine();
+        let snapshot = Snapshot::capture(&reg, &mem);
+
+        assert_eq!(snapshot.memory, vec![
+            MemorySegment { start: 0x3000, words: vec![0x1021, 0xF025] },
+            MemorySegment { start: 0x4000, words: vec![7] },
+            MemorySegment { start: 0xFFFF, words: vec![1] },
+        ]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let (reg, mem) = get_machine();
+        let snapshot = Snapshot::capture(&reg, &mem);
+
+        let mut new_reg = Registers::new();
+        let mut new_mem = Memory::new();
+        new_mem.set(0x5000, 9);
+        snapshot.restore(&mut new_reg, &mut new_mem);
+
+        assert_eq!(new_reg.r, reg.r);
+        assert_eq!(new_reg.pc, 0x3001);
+        assert!(new_reg.n && !new_reg.z && !new_reg.p);
+        assert_eq!(new_mem.as_slice(), mem.as_slice());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let (reg, mem) = get_machine();
+        let snapshot = Snapshot::capture(&reg, &mem);
+
+        let json = snapshot.to_json();
+        assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+
+        assert!(Snapshot::from_json("{ not json").is_err());
+
+        let json = json.replace(r#"{"start":65535,"words":[1]}"#, r#"{"start":65535,"words":[1,2]}"#);
+        assert!(Snapshot::from_json(&json).unwrap_err().contains("goes past xFFFF"));
+    }
+
+    #[test]
+    fn test_devices() {
+        let (reg, mut mem) = get_machine();
+        mem.set_io(Box::new(BufferedIO::new("ab")));
+        mem.read_char();
+        let snapshot = Snapshot::from_json(&Snapshot::capture(&reg, &mem).to_json()).unwrap();
+
+        assert_eq!(snapshot.devices.pending_input.as_deref(), Some("b"));
+
+        let mut new_reg = Registers::new();
+        let mut new_mem = Memory::new();
+        new_mem.set_io(Box::new(BufferedIO::new("")));
+        snapshot.restore(&mut new_reg, &mut new_mem);
+        assert_eq!(new_mem.read_char(), Some('b'));
+        assert_eq!(new_mem.read_char(), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let (mut reg, mut mem) = get_machine();
+        let before = Snapshot::capture(&reg, &mem);
+
+        reg.r[1] = 5;
+        reg.pc = 0x3002;
+        mem.set(0x4000, 8);
+        mem.set(0x4001, 9);
+        mem.set(0x5000, 1);
+        let after = Snapshot::capture(&reg, &mem);
+
+        let diff = before.diff(&after);
+
+        assert_eq!(diff.registers, vec![
+            RegisterChange { name: "R1".to_string(), before: 0, after: 5 },
+            RegisterChange { name: "PC".to_string(), before: 0x3001, after: 0x3002 },
+        ]);
+        assert_eq!(diff.memory, vec![
+            MemoryChange { start: 0x4000, before: vec![7, 0], after: vec![8, 9] },
+            MemoryChange { start: 0x5000, before: vec![0], after: vec![1] },
+        ]);
+        assert_eq!(
+            diff.generate_msg(),
+            "R1: x0000 -> x0005\nPC: x3001 -> x3002\nx4000-x4001: x0007->x0008 x0000->x0009\nx5000: x0000->x0001\n"
+        );
+        assert!(before.diff(&before).is_empty());
+    }
+}
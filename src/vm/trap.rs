@@ -1,52 +1,114 @@
 use super::{memory::Memory, registers::Registers};
-use std::io::*;
+
+// The same prompt the LC-3 OS (and so lc3tools/PennSim) prints for IN
+pub const IN_PROMPT: &str = "\nInput a character> ";
+
 pub struct Trap;
 
+/* What a trap registered with `VM::register_trap` can see and change. */
+#[allow(dead_code)]
+pub struct TrapContext<'a> {
+    pub vector: u8,
+    pub registers: &'a mut Registers,
+    pub memory: &'a mut Memory,
+}
+
+pub type TrapHandler = Box<dyn FnMut(&mut TrapContext) + Send>;
+
 impl Trap {
-    pub fn get_c(&self, reg: &mut Registers) {
-        self.get_char(reg);
+    pub fn get_c(&self, reg: &mut Registers, mem: &mut Memory) {
+        self.get_char(reg, mem);
     }
 
-    pub fn out(&self, reg: &mut Registers) {
-        print!("{}", reg.get(0) as u8 as char);
+    pub fn out(&self, reg: &mut Registers, mem: &mut Memory) {
+        mem.print(&((reg.get(0) & 0xFF) as u8 as char).to_string());
     }
 
     pub fn put_s(&self, reg: &mut Registers, mem: &mut Memory) {
         self.print_string(reg, mem);
     }
 
-    pub fn r#in(&self, reg: &mut Registers, mem: &mut Memory) {
-        self.print_string(reg, mem);
+    pub fn put_sp(&self, reg: &mut Registers, mem: &mut Memory) {
+        let output = self.get_packed_string(reg, mem);
+        mem.print(&output);
+    }
 
-        self.get_char(reg);
+    pub fn r#in(&self, reg: &mut Registers, mem: &mut Memory) {
+        /*
+        Prompt, read one character, echo it, then end the line.
+        Only R0 is changed, which is where the character ends up.
+        */
+        mem.print(IN_PROMPT);
+        if self.get_char(reg, mem) {
+            mem.print(&format!("{}\n", reg.get(0) as u8 as char));
+        }
     }
 
     pub fn halt(&self, reg:&mut Registers) {
         reg.halt = true;
     }
 
+    /*
+    Any vector without a built-in routine goes through the trap table,
+    the same way the LC-3 does: R7 <- PC, PC <- mem[trapvect8].
+    On the LC-3b the table holds words, so the entry is at trapvect8 << 1.
+    */
+    pub fn call_service_routine(&self, trapvect8: u16, reg: &mut Registers, mem: &mut Memory) {
+        reg.set(7, reg.pc);
+        reg.pc = mem.get(trapvect8 * mem.get_architecture().word_size());
+    }
+
     fn print_string(&self, reg: &mut Registers, mem: &mut Memory) {
         let mut i = reg.get(0);
+        let mut output = String::new();
         let mut c = mem.get(i) as u8 as char;
 
         while c != '\0' {
-            print!("{c}");
-            i += 1;
+            output.push(c);
+            i = mem.next_address(i);
             c = mem.get(i) as u8 as char;
         }
+
+        mem.print(&output);
+    }
+
+    fn get_packed_string(&self, reg: &mut Registers, mem: &mut Memory) -> String {
+        /*
+        Each word holds two characters, the first in bits [7:0] and the second in bits [15:8].
+        Like the LC-3 OS, stop at the first zero byte, so an odd length string
+        doesn't need a whole x0000 word after it.
+        */
+        let mut output = String::new();
+        let mut i = reg.get(0);
+
+        loop {
+            let word = mem.get(i);
+            let low = (word & 0xFF) as u8;
+            let high = (word >> 8) as u8;
+
+            if low == 0 {
+                break;
+            }
+            output.push(low as char);
+            if high == 0 {
+                break;
+            }
+            output.push(high as char);
+            i = mem.next_address(i);
+        }
+
+        return output;
     }
 
-    fn get_char(&self, reg: &mut Registers) {
-        let input: Option<i64> = std::io::stdin()
-            .bytes()
-            .next()
-            .and_then(|result| result.ok())
-            .map(|byte| byte as i64);
-
-        // Since input is an Option<i64>, which is an enum, we have to consider it's cases: Some and None.
-        match input {
-            Some(input) => reg.set(0, input as u16),
-            None => println!("Char: None"),
+    /* Returns false, leaving R0 alone, if there was no input left to read. */
+    fn get_char(&self, reg: &mut Registers, mem: &mut Memory) -> bool {
+        match mem.read_char() {
+            // The upper 8 bits of R0 are always cleared
+            Some(input) => {
+                reg.set(0, input as u16 & 0xFF);
+                return true;
+            },
+            None => return false,
         }
     }
 }
@@ -54,20 +116,69 @@ impl Trap {
 #[cfg(test)]
 mod tests {
     use crate::vm::registers::Registers;
+    use crate::output::BufferedIO;
     use super::*;
 
+    fn get_buffered_memory(input: &str) -> Memory {
+        let mut mem = Memory::new();
+        mem.set_io(Box::new(BufferedIO::new(input)));
+        return mem;
+    }
+
     #[test]
     fn test_out() {
         let mut reg = Registers::new();
+        let mut mem = get_buffered_memory("");
         let trap = Trap {};
 
         reg.set(0, 'a' as u16);
 
-        trap.out(&mut reg);
+        trap.out(&mut reg, &mut mem);
 
         reg.set(0, 'p' as u16);
 
-        trap.out(&mut reg);
-        trap.out(&mut reg);
+        trap.out(&mut reg, &mut mem);
+        trap.out(&mut reg, &mut mem);
+
+        assert_eq!(mem.get_output(), Some("app"));
+    }
+
+    #[test]
+    fn test_in_and_getc() {
+        let mut reg = Registers::new();
+        let mut mem = get_buffered_memory("xy");
+        let trap = Trap {};
+
+        trap.r#in(&mut reg, &mut mem);
+        assert_eq!(reg.get(0), 'x' as u16);
+        assert_eq!(mem.get_output(), Some("\nInput a character> x\n"));
+
+        // GETC doesn't echo
+        trap.get_c(&mut reg, &mut mem);
+        assert_eq!(reg.get(0), 'y' as u16);
+        assert_eq!(mem.get_output(), Some("\nInput a character> x\n"));
+
+        assert!(!mem.is_input_exhausted());
+        trap.get_c(&mut reg, &mut mem);
+        assert!(mem.is_input_exhausted());
+        assert_eq!(reg.get(0), 'y' as u16);
+    }
+
+    #[test]
+    fn test_packed_string() {
+        let mut reg = Registers::new();
+        let mut mem = Memory::new();
+        let trap = Trap {};
+
+        reg.set(0, 0x4000);
+        mem.set(0x4000, ('e' as u16) << 8 | 'h' as u16);
+        mem.set(0x4001, ('l' as u16) << 8 | 'l' as u16);
+        mem.set(0x4002, 'o' as u16);
+        mem.set(0x4003, 'x' as u16);
+
+        assert_eq!(trap.get_packed_string(&mut reg, &mut mem), "hello");
+
+        mem.set(0x4002, 0);
+        assert_eq!(trap.get_packed_string(&mut reg, &mut mem), "hell");
     }
 }
@@ -2,82 +2,533 @@ use super::instructions::{
     Instruction, Add, And, Br, JmpRet, Jsr, Ld,
     Ldi, Lea, Not, Rti, St, Sti, Str, Ldr,
 };
-use super::trap::Trap;
+use super::trap::{Trap, TrapContext, TrapHandler};
+use super::extension::{Extension, EXTENSION_OPCODE};
+use super::arch::Architecture;
+use super::lc3b;
 use super::registers::Registers;
-use super::memory::Memory;
-use std::collections::HashMap;
-
-use std::process::Command;
+use super::memory::{Memory, Protection, WriteViolation};
+use super::coverage::Coverage;
+use super::profiler::Profiler;
+use super::snapshot::{Snapshot, SnapshotDiff};
+use super::budget::{Budget, BudgetExceeded, BudgetReason, LoopDetector, Trace};
+use crate::asm::source_map::SourceMap;
+use crate::asm::debug_info::{AssertOperand, Assertion};
+use crate::output::{BufferedIO, SystemIO};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 const CMD_SIZE: u8 = 16;
 const OPCODE_SIZE: u8 = 4;
 const OPCODE_DELTA: u8 = CMD_SIZE - OPCODE_SIZE;
+const TRAP_OPCODE: u16 = 15;
+// Checking the clock is comparatively slow, so it only happens every so often
+const TIME_CHECK_INTERVAL: u64 = 256;
+// R7 is set to this before `call_subroutine` jumps, so reaching it means the subroutine returned.
+// It sits in the device register space, where no program's code can be.
+const RETURN_SENTINEL: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    Halted,
+    BudgetExceeded(BudgetExceeded),
+    // A store tried to overwrite a read-only word
+    WriteFault(WriteViolation),
+    // The program asked for input after all of it was used up, at this PC
+    InputExhausted(u16),
+    // The subroutine started by `call_subroutine` returned
+    Returned,
+    // Stopped before running the instruction at a breakpoint. Resuming runs it.
+    Breakpoint(u16),
+    // The instruction at this PC has an opcode the VM cannot run, like 1101 without an extension
+    IllegalOpcode(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssertionFailure {
+    pub pc: u16,
+    pub line_num: usize,
+    pub text: String,
+    // `None` when a side refers to a label the VM does not know
+    pub lhs: Option<u16>,
+    pub rhs: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubroutineResult {
+    pub outcome: RunOutcome,
+    pub registers: [u16; 8],
+    // What the subroutine changed, compared to right after the arguments were set
+    pub diff: SnapshotDiff,
+}
 
 pub struct VM {
     instructions: HashMap<u8, Box<dyn Instruction>>,
     registers: Registers,
     memory: Memory,
+    coverage: Option<Coverage>,
+    profiler: Option<Profiler>,
+    budget: Budget,
+    instruction_count: u64,
+    trace: Trace,
+    write_violations: Vec<WriteViolation>,
+    symbols: HashMap<String, u16>,
+    stop_at: Option<u16>,
+    breakpoints: HashSet<u16>,
+    // The breakpoint the last `resume` stopped at, so the next one can get past it
+    paused_at: Option<u16>,
+    assertions: HashMap<u16, Vec<Assertion>>,
+    assertion_failures: Vec<AssertionFailure>,
+    traps: HashMap<u8, TrapHandler>,
+    // The PC of the last instruction `run_single_command` could not run
+    illegal_opcode: Option<u16>,
+}
+
+#[allow(dead_code)]
+impl AssertionFailure {
+    pub fn generate_msg(&self) -> String {
+        let describe = |value: Option<u16>| match value {
+            Some(value) => format!("x{:04X} ({})", value, value as i16),
+            None => "an unknown label".to_string(),
+        };
+
+        return format!(
+            "assertion `{}` on line {} failed at x{:04X}: the left side is {} and the right side is {}",
+            self.text,
+            self.line_num,
+            self.pc,
+            describe(self.lhs),
+            describe(self.rhs),
+        );
+    }
 }
 
 #[allow(dead_code)]
 impl VM {
     pub fn new() -> VM {
+        VM {
+            instructions: VM::get_instruction_set(Architecture::Lc3),
+            registers: Registers::new(),
+            memory: Memory::new(),
+            coverage: None,
+            profiler: None,
+            budget: Budget::new(),
+            instruction_count: 0,
+            trace: Trace::new(),
+            write_violations: vec![],
+            symbols: HashMap::new(),
+            stop_at: None,
+            breakpoints: HashSet::new(),
+            paused_at: None,
+            assertions: HashMap::new(),
+            assertion_failures: vec![],
+            traps: HashMap::new(),
+            illegal_opcode: None,
+        }
+    }
+
+    /* A VM for `architecture`, which must match what the program was assembled for. */
+    pub fn with_architecture(architecture: Architecture) -> VM {
+        let mut vm = VM::new();
+        vm.instructions = VM::get_instruction_set(architecture);
+        vm.memory.set_architecture(architecture);
+        return vm;
+    }
+
+    fn get_instruction_set(architecture: Architecture) -> HashMap<u8, Box<dyn Instruction>> {
         let mut ins: HashMap<u8, Box<dyn Instruction>> = HashMap::new();
 
-        ins.insert(0, Box::new(Br {}));
         ins.insert(1, Box::new(Add {}));
-        ins.insert(2, Box::new(Ld {}));
-        ins.insert(3, Box::new(St {}));
-        ins.insert(4, Box::new(Jsr {}));
         ins.insert(5, Box::new(And {}));
-        ins.insert(6, Box::new(Ldr {}));
-        ins.insert(7, Box::new(Str {}));
         ins.insert(8, Box::new(Rti {}));
-        ins.insert(9, Box::new(Not {}));
-        ins.insert(10, Box::new(Ldi {}));
-        ins.insert(11, Box::new(Sti {}));
         ins.insert(12, Box::new(JmpRet {}));
-        // ins.insert(13, Box::new(Reserved {}));
-        ins.insert(14, Box::new(Lea {}));
         ins.insert(15, Box::new(Trap {}));
 
-        VM {
-            instructions: ins,
-            registers: Registers::new(),
-            memory: Memory::new(),
+        match architecture {
+            Architecture::Lc3 => {
+                ins.insert(0, Box::new(Br {}));
+                ins.insert(2, Box::new(Ld {}));
+                ins.insert(3, Box::new(St {}));
+                ins.insert(4, Box::new(Jsr {}));
+                ins.insert(6, Box::new(Ldr {}));
+                ins.insert(7, Box::new(Str {}));
+                ins.insert(9, Box::new(Not {}));
+                ins.insert(10, Box::new(Ldi {}));
+                ins.insert(11, Box::new(Sti {}));
+                // 13 is reserved, see `set_extension`
+                ins.insert(14, Box::new(Lea {}));
+            },
+            Architecture::Lc3b => {
+                ins.insert(0, Box::new(lc3b::Br {}));
+                ins.insert(2, Box::new(lc3b::Ldb {}));
+                ins.insert(3, Box::new(lc3b::Stb {}));
+                ins.insert(4, Box::new(lc3b::Jsr {}));
+                ins.insert(6, Box::new(lc3b::Ldw {}));
+                ins.insert(7, Box::new(lc3b::Stw {}));
+                ins.insert(9, Box::new(lc3b::Xor {}));
+                // 10 and 11 are unused
+                ins.insert(13, Box::new(lc3b::Shf {}));
+                ins.insert(14, Box::new(lc3b::Lea {}));
+            },
         }
+
+        return ins;
+    }
+
+    pub fn get_architecture(&self) -> Architecture {
+        return self.memory.get_architecture();
+    }
+
+    /* A VM that reads and prints through `io` instead of stdin/stdout. */
+    pub fn with_io(io: Box<dyn SystemIO>) -> VM {
+        let mut vm = VM::new();
+        vm.memory.set_io(io);
+        return vm;
+    }
+
+    /* A VM whose input is `input`, and whose output is kept for `get_output`. */
+    pub fn with_input(input: &str) -> VM {
+        return VM::with_io(Box::new(BufferedIO::new(input)));
+    }
+
+    /* Everything the program printed, unless it was printed straight to stdout. */
+    pub fn get_output(&self) -> Option<&str> {
+        return self.memory.get_output();
+    }
+
+    pub fn run(&mut self, file: Vec<u16>) -> RunOutcome {
+        self.load(file);
+
+        return self.resume();
     }
 
-    pub fn run(&mut self, file: Vec<u16>) {
+    /*
+    Loads the program into a cleared machine and points the PC at its origin,
+    without running anything.
+    */
+    pub fn load(&mut self, file: Vec<u16>) {
+        self.registers = Registers::new();
         self.registers.pc = file[0];
-        
+        self.paused_at = None;
+
+        self.memory.clear();
         self.memory.load_file(file);
+    }
+
+    pub fn get_registers(&self) -> &Registers {
+        return &self.registers;
+    }
+
+    pub fn get_registers_mut(&mut self) -> &mut Registers {
+        return &mut self.registers;
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        return &self.memory;
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut Memory {
+        return &mut self.memory;
+    }
+
+    /* The labels `call_subroutine` can call, usually from `Asm::get_symbols`. */
+    pub fn set_symbols(&mut self, symbols: HashMap<String, u16>) {
+        self.symbols = symbols;
+    }
+
+    /*
+    Runs the subroutine at `label` on its own: the arguments are put into
+    their registers, R7 is pointed at a sentinel return address, and the
+    VM runs until the subroutine returns there (or halts, faults, or runs
+    out of budget). The rest of the machine is left as it was.
+    */
+    pub fn call_subroutine(&mut self, label: &str, args: &[(usize, u16)]) -> Result<SubroutineResult, String> {
+        let address = match self.symbols.get(label) {
+            Some(address) => *address,
+            None => return Err(format!("the label `{}` is not defined", label)),
+        };
+
+        for (register, value) in args.iter() {
+            if *register >= 8 {
+                return Err(format!("R{} is not a register", register));
+            }
+            self.registers.r[*register] = *value;
+        }
+        self.registers.r[7] = RETURN_SENTINEL;
+        self.registers.pc = address;
+        self.registers.halt = false;
+
+        let before = self.snapshot();
+        self.stop_at = Some(RETURN_SENTINEL);
+        let outcome = self.resume();
+        self.stop_at = None;
+
+        return Ok(SubroutineResult {
+            outcome,
+            registers: self.registers.r,
+            diff: before.diff(&self.snapshot()),
+        });
+    }
+
+    /* Keeps executing from the current PC until the machine halts or the budget runs out. */
+    pub fn resume(&mut self) -> RunOutcome {
+        let start_count = self.instruction_count;
+        let start_time = self.budget.max_duration.map(|_| Instant::now());
+        let mut loop_detector = LoopDetector::new();
 
         while self.registers.halt != true {
-            print!("\n{:#06x}\t : ", self.registers.pc);
-            /* let mut child = Command::new("sleep").arg("1").spawn().unwrap();
-            let _ = child.wait().unwrap(); */
+            if self.stop_at == Some(self.registers.pc) {
+                return RunOutcome::Returned;
+            }
+            if self.breakpoints.contains(&self.registers.pc) && self.paused_at != Some(self.registers.pc) {
+                self.paused_at = Some(self.registers.pc);
+                return RunOutcome::Breakpoint(self.registers.pc);
+            }
+            self.paused_at = None;
+
+            let executed = self.instruction_count - start_count;
+            if let Some(reason) = self.check_budget(executed, start_time, &mut loop_detector) {
+                let trace = self.trace.to_vec();
+                return RunOutcome::BudgetExceeded(BudgetExceeded {
+                    reason,
+                    last_pc: *trace.last().unwrap_or(&self.registers.pc),
+                    instructions: executed,
+                    recent_trace: trace,
+                });
+            }
+
+            let pc = self.registers.pc;
+            let violation_count = self.write_violations.len();
             self.run_single_command();
+
+            // Only a write by this instruction faults, so a fault can be resumed from
+            if let Some(violation) = self.write_violations[violation_count..].iter()
+                .find(|violation| violation.protection == Protection::ReadOnly) {
+                return RunOutcome::WriteFault(violation.clone());
+            }
+            if let Some(pc) = self.illegal_opcode.take() {
+                return RunOutcome::IllegalOpcode(pc);
+            }
+            if self.memory.is_input_exhausted() {
+                self.memory.clear_input_exhausted();
+                return RunOutcome::InputExhausted(pc);
+            }
+        }
+
+        return RunOutcome::Halted;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        return Snapshot::capture(&self.registers, &self.memory);
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        snapshot.restore(&mut self.registers, &mut self.memory);
+    }
+
+    /*
+    Protects every word the assembler emitted as an instruction. Data
+    words (`.FILL`, `.BLKW`, `.STRINGZ`) stay writable.
+    */
+    pub fn protect_code(&mut self, source_map: &SourceMap, protection: Protection) {
+        for (address, _) in source_map.instructions() {
+            self.memory.protect(*address, protection);
+        }
+    }
+
+    /* Every write into protected memory so far, oldest first. */
+    pub fn get_write_violations(&self) -> &Vec<WriteViolation> {
+        return &self.write_violations;
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    pub fn get_instruction_count(&self) -> u64 {
+        return self.instruction_count;
+    }
+
+    fn check_budget(&self, executed: u64, start_time: Option<Instant>, loop_detector: &mut LoopDetector) -> Option<BudgetReason> {
+        if self.budget.is_unlimited() {
+            return None;
+        }
+
+        if let Some(max_instructions) = self.budget.max_instructions
+            && executed >= max_instructions {
+            return Some(BudgetReason::Instructions(max_instructions));
+        }
+
+        if let (Some(max_duration), Some(start_time)) = (self.budget.max_duration, start_time)
+            && executed.is_multiple_of(TIME_CHECK_INTERVAL)
+            && start_time.elapsed() >= max_duration {
+            return Some(BudgetReason::Time(max_duration));
+        }
+
+        if self.budget.detect_loops {
+            if self.memory.get(self.registers.pc) >> OPCODE_DELTA == TRAP_OPCODE {
+                loop_detector.forget();
+            } else if loop_detector.check(&self.registers, self.memory.get_write_count()) {
+                return Some(BudgetReason::InfiniteLoop);
+            }
+        }
+
+        return None;
+    }
+
+    /*
+    Makes `resume` stop before running the instruction at any of `addresses`,
+    usually `Asm::debug_info.breakpoints`.
+    */
+    pub fn set_breakpoints(&mut self, addresses: &[u16]) {
+        self.breakpoints = addresses.iter().copied().collect();
+    }
+
+    /*
+    Checks every assertion right before the instruction at its address runs.
+    A failed assertion is recorded, but does not stop the program.
+    */
+    pub fn enable_assertions(&mut self, assertions: &[Assertion]) {
+        self.assertions.clear();
+        for assertion in assertions.iter() {
+            self.assertions.entry(assertion.address).or_default().push(assertion.clone());
         }
     }
 
+    /* Every assertion that failed so far, oldest first. */
+    pub fn get_assertion_failures(&self) -> &Vec<AssertionFailure> {
+        return &self.assertion_failures;
+    }
+
+    fn check_assertions(&mut self, pc: u16) {
+        let Some(assertions) = self.assertions.get(&pc) else {
+            return;
+        };
+
+        for assertion in assertions.iter() {
+            let lhs = self.evaluate(&assertion.lhs);
+            let rhs = self.evaluate(&assertion.rhs);
+
+            let holds = match (lhs, rhs) {
+                (Some(lhs), Some(rhs)) => assertion.comparison.holds(lhs, rhs),
+                _ => false,
+            };
+            if !holds {
+                self.assertion_failures.push(AssertionFailure {
+                    pc,
+                    line_num: assertion.line_num,
+                    text: assertion.text.clone(),
+                    lhs,
+                    rhs,
+                });
+            }
+        }
+    }
+
+    fn evaluate(&self, operand: &AssertOperand) -> Option<u16> {
+        match operand {
+            AssertOperand::Register(index) => return Some(self.registers.r[*index]),
+            AssertOperand::Pc => return Some(self.registers.pc),
+            AssertOperand::Number(number) => return Some(*number),
+            AssertOperand::Label(label) => return self.symbols.get(label).copied(),
+            AssertOperand::Memory(address) => return self.evaluate(address).map(|address| self.memory.get(address)),
+        }
+    }
+
+    /*
+    Runs `handler` for `TRAP vector` instead of the built-in routine or the
+    trap table. The PC already points past the TRAP when it is called.
+    */
+    pub fn register_trap<F>(&mut self, vector: u8, handler: F)
+    where
+        F: FnMut(&mut TrapContext) + Send + 'static,
+    {
+        self.traps.insert(vector, Box::new(handler));
+    }
+
+    pub fn unregister_trap(&mut self, vector: u8) {
+        self.traps.remove(&vector);
+    }
+
+    /*
+    Executes opcode 1101 with `extension`, the same one given to `Asm::set_extension`.
+    Only the LC-3 has the opcode free, since the LC-3b uses it for SHF.
+    */
+    pub fn set_extension(&mut self, extension: Extension) -> Result<(), String> {
+        if self.get_architecture() != Architecture::Lc3 {
+            return Err(format!("opcode 1101 is not free on the {}", self.get_architecture().as_str()));
+        }
+        self.instructions.insert(EXTENSION_OPCODE as u8, Box::new(extension));
+        return Ok(());
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        return self.coverage.as_ref();
+    }
+
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn get_profiler(&self) -> Option<&Profiler> {
+        return self.profiler.as_ref();
+    }
+
     pub fn run_single_command(&mut self) {
         if self.registers.halt == true {
             return;
         }
-        let cmd = self.memory.get(self.registers.pc);
-        self.registers.pc += 1;
+        let pc = self.registers.pc;
+        self.illegal_opcode = None;
+        if !self.assertions.is_empty() {
+            self.check_assertions(pc);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(pc);
+        }
+        self.trace.push(pc);
+        self.instruction_count += 1;
+
+        let cmd = self.memory.get(pc);
+        self.registers.pc = self.memory.next_address(pc);
 
         let opcode: u16 = cmd >> OPCODE_DELTA;
         let value: u16 = cmd - (opcode << OPCODE_DELTA);
-        self.instructions[&(opcode as u8)]
-            .exe(value, &mut self.registers, &mut self.memory);
+
+        let vector = (value & 0xFF) as u8;
+        match self.traps.get_mut(&vector) {
+            Some(handler) if opcode == TRAP_OPCODE => {
+                handler(&mut TrapContext { vector, registers: &mut self.registers, memory: &mut self.memory });
+            },
+            _ => match self.instructions.get(&(opcode as u8)) {
+                Some(instruction) => instruction.exe(value, &mut self.registers, &mut self.memory),
+                // Stays on the instruction, so the state shows where the program went wrong
+                None => {
+                    self.registers.pc = pc;
+                    self.illegal_opcode = Some(pc);
+                    return;
+                },
+            },
+        }
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, cmd, self.registers.pc);
+        }
+
+        for (address, value, protection) in self.memory.take_violations() {
+            self.write_violations.push(WriteViolation { pc, address, value, protection });
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use crate::asm::asm::Asm;
+    use crate::vm::registers::{R0, R1, R2};
     use super::*;
 
     fn run_vm(file: &str) -> VM {
@@ -108,6 +559,20 @@ mod tests {
         return vm;
     }
 
+    /*
+    Assembles `file` with `asm` and runs it on `vm`, after `setup` has turned
+    on whatever the test looks at, like coverage or a budget.
+    */
+    pub fn run_with(mut asm: Asm, mut vm: VM, file: &str, setup: impl FnOnce(&mut VM, &Asm)) -> (Asm, VM, RunOutcome) {
+        let binary_file = asm.run(file.to_string());
+        assert!(asm.errors.is_empty(), "{:?}", asm.errors.iter().map(|e| &e.code).collect::<Vec<_>>());
+
+        setup(&mut vm, &asm);
+        let outcome = vm.run(binary_file);
+
+        return (asm, vm, outcome);
+    }
+
     #[test]
     fn test_add() {
         let vm = run_vm("
@@ -225,4 +690,307 @@ start   ld r1, max
         assert_eq!(vm.registers.r[1], u16::MAX);
 
     }
+
+    #[test]
+    fn test_jsr() {
+        let vm = run_vm("
+        jsr sub
+        lea r2, sub
+        jsrr r2
+        halt
+
+sub     add r1, r1, #1
+        ret
+        ");
+
+        assert_eq!(vm.registers.r[1], 2);
+        assert_eq!(vm.registers.r[7], 3);
+    }
+
+    #[test]
+    fn test_load_twice() {
+        let mut vm = VM::new();
+
+        vm.run(Asm::new().run(".ORIG x3000\nADD R0, R0, #4\nHALT\n.END".to_string()));
+        let outcome = vm.run(Asm::new().run(".ORIG x3000\nADD R1, R1, #5\nHALT\n.END".to_string()));
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(vm.registers.r[0], 0);
+        assert_eq!(vm.registers.r[1], 5);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let vm = run_vm("add r1, r1, #3");
+        let snapshot = vm.snapshot();
+
+        let mut other = VM::new();
+        other.restore(&snapshot);
+
+        assert_eq!(other.registers.r[1], 3);
+        assert!(other.registers.halt);
+        assert!(vm.snapshot().diff(&other.snapshot()).is_empty());
+    }
+
+    #[test]
+    fn test_buffer_overflow_into_code() {
+        let (_, mut vm, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+        lea r1, buffer
+        add r2, r2, #4
+loop    str r2, r1, #0      ; writes one word past the end of the buffer
+        add r1, r1, #1
+        add r2, r2, #-1
+        brzp loop
+        halt
+buffer  .blkw #4
+after   add r3, r3, #1
+        .END", |vm, asm| vm.protect_code(&asm.source_map, Protection::ReadOnly));
+
+        let violation = WriteViolation { pc: 0x3002, address: 0x300B, value: 0, protection: Protection::ReadOnly };
+        assert_eq!(outcome, RunOutcome::WriteFault(violation));
+        assert_eq!(vm.memory.get(0x300B), 0x16E1);
+        assert_eq!(vm.memory.get(0x300A), 1);
+
+        // The loop ends after the bad write, so resuming finishes the program
+        assert_eq!(vm.resume(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_watch_self_modifying_code() {
+        let (_, vm, outcome) = run_with(Asm::new(), VM::new(), ".ORIG x3000
+        ld r1, patch
+        st r1, target
+target  add r2, r2, #1
+        halt
+patch   add r2, r2, #5
+        .END", |vm, asm| vm.protect_code(&asm.source_map, Protection::Watch));
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(vm.registers.r[2], 5);
+        assert_eq!(vm.get_write_violations(), &vec![
+            WriteViolation { pc: 0x3001, address: 0x3002, value: 0x14A5, protection: Protection::Watch },
+        ]);
+    }
+
+    #[test]
+    fn test_custom_trap_routine() {
+        let vm = run_vm("
+        lea r0, service
+        sti r0, vector
+        trap x26
+        add r2, r2, #1
+        halt
+
+vector  .fill x0026
+
+service add r1, r1, #7
+        ret
+        ");
+
+        assert_eq!(vm.registers.r[1], 7);
+        assert_eq!(vm.registers.r[2], 1);
+        assert_eq!(vm.registers.r[7], 3);
+    }
+
+    #[test]
+    fn test_scripted_input() {
+        let (_, vm, outcome) = run_with(Asm::new(), VM::with_input("ab"), r#".ORIG x3000
+        lea r0, prompt
+        puts
+        getc
+        out
+        in
+        add r1, r0, #0
+        halt
+prompt  .stringz "go: "
+        .END"#, |_, _| {});
+
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(vm.registers.r[1], 'b' as u16);
+        assert_eq!(vm.get_output(), Some("go: a\nInput a character> b\n"));
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(String::from(".ORIG x3000
+        add r1, r1, #1
+        .fill xD000
+        halt
+        .END"));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.run(binary_file), RunOutcome::IllegalOpcode(0x3001));
+        assert_eq!(vm.registers.r[1], 1);
+        assert_eq!(vm.registers.pc, 0x3001);
+    }
+
+    #[test]
+    fn test_input_exhausted() {
+        let (_, mut vm, outcome) = run_with(Asm::new(), VM::with_input("a"), ".ORIG x3000
+        getc
+        getc
+        halt
+        .END", |_, _| {});
+
+        assert_eq!(outcome, RunOutcome::InputExhausted(0x3001));
+        assert_eq!(vm.registers.r[0], 'a' as u16);
+        // It is only reported once
+        assert_eq!(vm.resume(), RunOutcome::Halted);
+
+        // Checking KBSR once, without waiting for a character, is fine
+        let (_, _, outcome) = run_with(Asm::new(), VM::with_input(""), ".ORIG x3000
+        ldi r1, kbsr
+        halt
+kbsr    .fill xFE00
+        .END", |_, _| {});
+        assert_eq!(outcome, RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_memory_mapped_console() {
+        let (_, vm, outcome) = run_with(Asm::new(), VM::with_input("hey"), ".ORIG x3000
+poll    ldi r1, kbsr
+        brzp poll
+        ldi r0, kbdr
+wait    ldi r1, dsr
+        brzp wait
+        sti r0, ddr
+        br poll
+kbsr    .fill xFE00
+kbdr    .fill xFE02
+dsr     .fill xFE04
+ddr     .fill xFE06
+        .END", |_, _| {});
+
+        assert_eq!(outcome, RunOutcome::InputExhausted(0x3000));
+        assert_eq!(vm.get_output(), Some("hey"));
+    }
+
+    const MULTIPLY: &str = ".ORIG x3000
+        halt
+multiply and r2, r2, #0
+        add r1, r1, #0
+        brz done
+loop    add r2, r2, r0
+        add r1, r1, #-1
+        brp loop
+done    st r2, result
+        ret
+result  .fill #0
+        .END";
+
+    #[test]
+    fn test_call_subroutine() {
+        let (_, mut vm, _) = run_with(Asm::new(), VM::new(), MULTIPLY, |vm, asm| vm.set_symbols(asm.get_symbols()));
+
+        let result = vm.call_subroutine("multiply", &[(R0, 5), (R1, 7)]).unwrap();
+
+        assert_eq!(result.outcome, RunOutcome::Returned);
+        assert_eq!(result.registers[R2], 35);
+        assert_eq!(result.diff.memory.len(), 1);
+        assert_eq!(result.diff.memory[0].after, vec![35]);
+
+        let result = vm.call_subroutine("multiply", &[(R0, 3), (R1, 0)]).unwrap();
+        assert_eq!(result.registers[R2], 0);
+    }
+
+    #[test]
+    fn test_call_subroutine_errors() {
+        let (_, mut vm, _) = run_with(Asm::new(), VM::new(), MULTIPLY, |vm, asm| vm.set_symbols(asm.get_symbols()));
+
+        assert!(vm.call_subroutine("divide", &[]).is_err());
+        assert!(vm.call_subroutine("multiply", &[(8, 1)]).is_err());
+
+        vm.set_budget(Budget { max_instructions: Some(10), ..Budget::new() });
+        let result = vm.call_subroutine("multiply", &[(R0, 1), (R1, 100)]).unwrap();
+        assert!(matches!(result.outcome, RunOutcome::BudgetExceeded(_)));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(".ORIG x3000
+        add r1, r1, #1
+        .BREAK
+        add r1, r1, #1
+        halt
+        .END".to_string());
+
+        let mut vm = VM::new();
+        vm.set_breakpoints(&asm.debug_info.breakpoints);
+
+        assert_eq!(vm.run(binary_file), RunOutcome::Breakpoint(0x3001));
+        assert_eq!(vm.get_registers().r[R1], 1);
+        assert_eq!(vm.resume(), RunOutcome::Halted);
+        assert_eq!(vm.get_registers().r[R1], 2);
+    }
+
+    #[test]
+    fn test_assertions() {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(".ORIG x3000
+        add r0, r0, #2
+        .ASSERT R0 == #2
+        st r0, count
+        .ASSERT [count] > #2    ; fails
+        halt
+count   .FILL #0
+        .END".to_string());
+
+        let mut vm = VM::new();
+        vm.enable_assertions(&asm.debug_info.assertions);
+        assert_eq!(vm.run(binary_file), RunOutcome::Halted);
+
+        let failures = vm.get_assertion_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].pc, 0x3002);
+        assert_eq!(failures[0].lhs, Some(2));
+        assert_eq!(
+            failures[0].generate_msg(),
+            "assertion `[count] > #2` on line 5 failed at x3002: the left side is x0002 (2) and the right side is x0002 (2)"
+        );
+    }
+
+    #[test]
+    fn test_register_trap() {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(".ORIG x3000
+        add r0, r0, #-12
+        trap x30        ; print R0 as decimal
+        trap x31        ; random number
+        add r1, r0, #0
+        trap x31
+        out             ; overridden below
+        halt
+        .END".to_string());
+
+        let mut vm = VM::with_input("");
+        vm.register_trap(0x30, |ctx| {
+            let number = ctx.registers.get(0) as i16;
+            ctx.memory.print(&number.to_string());
+        });
+        let mut seed: u16 = 1;
+        vm.register_trap(0x31, move |ctx| {
+            seed = seed.wrapping_mul(75).wrapping_add(74);
+            ctx.registers.set(0, seed);
+        });
+        vm.register_trap(0x21, |ctx| ctx.memory.print(&format!("<{}>", ctx.vector)));
+
+        assert_eq!(vm.run(binary_file), RunOutcome::Halted);
+        assert_eq!(vm.get_output(), Some("-12<33>"));
+        assert_eq!(vm.get_registers().r[R1], 149);
+        assert_eq!(vm.get_registers().r[R0], 11249);
+    }
+
+    #[test]
+    fn test_unregister_trap() {
+        let mut vm = VM::with_input("");
+        vm.register_trap(0x25, |ctx| ctx.registers.set(1, 1));
+        vm.unregister_trap(0x25);
+
+        // HALT
+        assert_eq!(vm.run(vec![0x3000, 0xF025]), RunOutcome::Halted);
+        assert_eq!(vm.get_registers().r[R1], 0);
+    }
 }
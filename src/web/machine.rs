@@ -0,0 +1,126 @@
+use crate::asm::asm::Asm;
+use crate::grader::runner::describe_outcome;
+use crate::vm::vm::VM;
+use wasm_bindgen::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use crate::vm::trap::TrapContext;
+#[cfg(target_arch = "wasm32")]
+use js_sys::{Function, JsString, Uint16Array};
+
+/*
+The VM for the web page. A JS function can be registered for a trap vector:
+
+    vm.register_trap(0x30, (vector, registers, memory) => {
+        registers[1] = registers[0] * 2;
+        return `R0 is ${registers[0]}`;
+    });
+
+`registers` holds R0 to R7 and `memory` all 64K words. Changes to either
+are copied back into the VM, and a string it returns is printed. JS traps
+only exist in wasm builds, since a JS function cannot leave its thread.
+*/
+#[wasm_bindgen]
+pub struct WebVM {
+    vm: VM,
+}
+
+#[cfg(target_arch = "wasm32")]
+struct JsTrap(Function);
+
+// SAFETY: a JS function can only be called on the thread that made it. A `VM` has to be
+// `Send` for the batch grader, but this is only compiled for wasm32, which runs on a
+// single thread, so a `JsTrap` never actually moves to another one.
+#[cfg(target_arch = "wasm32")]
+unsafe impl Send for JsTrap {}
+
+#[wasm_bindgen]
+impl WebVM {
+    #[wasm_bindgen(constructor)]
+    pub fn new(input: &str) -> WebVM {
+        WebVM { vm: VM::with_input(input) }
+    }
+
+    /*
+    Assembles `source` and loads it without running it. The error has every
+    assembler error, one per line.
+    */
+    pub fn load(&mut self, source: String) -> Result<(), String> {
+        let mut asm = Asm::new();
+        let binary_file = asm.run(source);
+
+        if !asm.errors.is_empty() {
+            return Err(asm.errors.iter().map(|error| error.generate_msg()).collect::<Vec<String>>().join("\n"));
+        }
+        self.vm.load(binary_file);
+        return Ok(());
+    }
+
+    /* Runs from the current PC, and says why it stopped, like "halted". */
+    pub fn run(&mut self) -> String {
+        return describe_outcome(&self.vm.resume());
+    }
+
+    pub fn get_output(&self) -> String {
+        return self.vm.get_output().unwrap_or("").to_string();
+    }
+
+    pub fn get_registers(&self) -> Vec<u16> {
+        return self.vm.get_registers().r.to_vec();
+    }
+
+    /* Calls `handler(vector, registers, memory)` for `TRAP vector`, see `WebVM`. */
+    #[cfg(target_arch = "wasm32")]
+    pub fn register_trap(&mut self, vector: u8, handler: Function) {
+        let handler = JsTrap(handler);
+        self.vm.register_trap(vector, move |ctx| handler.call(ctx));
+    }
+
+    pub fn unregister_trap(&mut self, vector: u8) {
+        self.vm.unregister_trap(vector);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl JsTrap {
+    fn call(&self, ctx: &mut TrapContext) {
+        let registers = Uint16Array::from(&ctx.registers.r[..]);
+        let memory = Uint16Array::from(ctx.memory.as_slice());
+
+        let result = self.0.call3(&JsValue::NULL, &JsValue::from(ctx.vector), &registers, &memory);
+
+        registers.copy_to(&mut ctx.registers.r);
+        let before = ctx.memory.as_slice().to_vec();
+        for (address, word) in memory.to_vec().into_iter().enumerate() {
+            // `set`, so that protected words and the display are handled like a store
+            if before[address] != word {
+                ctx.memory.set(address as u16, word);
+            }
+        }
+
+        if let Ok(output) = result
+            && let Some(output) = output.dyn_ref::<JsString>() {
+            ctx.memory.print(&String::from(output));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_and_run() {
+        let mut vm = WebVM::new("");
+        assert!(vm.load(".ORIG x3000\nADD R0, R0, #4\nHALT\n.END".to_string()).is_ok());
+        assert_eq!(vm.run(), "halted");
+        assert_eq!(vm.get_registers()[0], 4);
+
+        // A second program starts from a cleared machine
+        assert!(vm.load(".ORIG x3000\nADD R1, R1, #5\nHALT\n.END".to_string()).is_ok());
+        assert_eq!(vm.run(), "halted");
+        assert_eq!(vm.get_registers()[..2], [0, 5]);
+
+        let error = vm.load(".ORIG x3000\nADD R0, R0\n.END".to_string()).unwrap_err();
+        assert!(error.contains("SM"));
+    }
+}
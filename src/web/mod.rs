@@ -1 +1,2 @@
-pub mod highlight;
\ No newline at end of file
+pub mod highlight;
+pub mod machine;
\ No newline at end of file